@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+
+use crate::config::{app_config, config_file_path, parse_config};
+use crate::types::{ConflictPolicy, Palette, PaletteChange};
+use crate::palette_name::PaletteName;
+use crate::palette_manager::{palette_etag, palette_root_for};
+use crate::palette_repository::PaletteRepository;
+use crate::palette_import::UnpackBudget;
+use crate::error::AppError;
+
+// Bumped whenever the archive layout changes in a way older versions cannot restore
+const BACKUP_FORMAT_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+const SETTINGS_FILE: &str = "settings/config.json";
+
+// A full Commander backup is a zip holding every palette of every root plus the settings:
+//
+//   manifest.json
+//   palettes/<root>/<palette file>.json
+//   settings/config.json
+//
+// The manifest lists what the archive contains, so a restore can preview its changes
+// before touching anything. Palette backups and history are not included.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupManifest {
+    pub format_version: u32,
+    pub app_version: String,
+    pub created_at: String,
+    pub palettes: Vec<BackupPaletteEntry>,
+    #[serde(default)]
+    pub settings: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupPaletteEntry {
+    // As shown on the machine that made the backup. Restores go by `file` instead,
+    // since a qualified name only means something with that machine's palette roots.
+    pub name: String,
+    pub file: String,
+    pub etag: String,
+    pub categories: usize,
+    pub commands: usize,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreAction {
+    Create,
+    Update,
+    Unchanged,
+    Skip,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RestoreItem {
+    pub name: String,
+    pub action: RestoreAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub created_at: String,
+    pub app_version: String,
+    pub palettes: Vec<RestoreItem>,
+    pub settings: RestoreItem,
+    // Settings only take effect after Commander is restarted
+    pub restart_required: bool,
+}
+
+// Builds the backup archive from everything currently on disk
+pub fn create_full_backup(palettes: &PaletteRepository) -> Result<Vec<u8>, AppError> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut manifest = BackupManifest {
+        format_version: BACKUP_FORMAT_VERSION,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        palettes: Vec::new(),
+        settings: None,
+    };
+
+    for name in palettes.list()? {
+        let palette = palettes.get(&name)?;
+        let root = palette_root_for(&name)?;
+        let file = format!("palettes/{}/{}", root.name, name.file_name());
+        let contents = serde_json::to_vec_pretty(&palette).map_err(|e| AppError::internal(format!("Failed to serialize palette '{}': {}", name, e)))?;
+        zip.start_file(file.as_str(), options).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+        zip.write_all(&contents).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+        manifest.palettes.push(BackupPaletteEntry {
+            etag: palette_etag(&palette),
+            categories: palette.commands.len(),
+            commands: palette.commands.values().map(|commands| commands.len()).sum(),
+            name: name.to_string(),
+            file,
+        });
+    }
+
+    if let Some(config_path) = config_file_path().filter(|path| path.exists()) {
+        let contents = fs::read(&config_path).map_err(|e| AppError::internal(format!("Failed to read settings: {}", e)))?;
+        zip.start_file(SETTINGS_FILE, options).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+        zip.write_all(&contents).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+        manifest.settings = Some(SETTINGS_FILE.to_string());
+    }
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest).map_err(|e| AppError::internal(format!("Failed to serialize backup manifest: {}", e)))?;
+    zip.start_file(MANIFEST_FILE, options).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+    zip.write_all(&manifest_json).map_err(|e| AppError::internal(format!("Failed to write backup archive: {}", e)))?;
+
+    let cursor = zip.finish().map_err(|e| AppError::internal(format!("Failed to finish backup archive: {}", e)))?;
+    Ok(cursor.into_inner())
+}
+
+// The root and the name within that root of a palette in the archive, from its path
+// "palettes/<root>/<file name>"
+fn archive_palette_location(file: &str) -> Result<(&str, PaletteName), AppError> {
+    let invalid = || AppError::invalid(format!("Invalid backup: '{}' is not a palette path.", file));
+    let (root, file_name) = file.strip_prefix("palettes/").and_then(|rest| rest.split_once('/')).ok_or_else(invalid)?;
+    let local = file_name.strip_suffix(".json").and_then(PaletteName::from_file_stem).ok_or_else(invalid)?;
+    Ok((root, local))
+}
+
+fn read_archive_file(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, budget: &mut UnpackBudget, path: &str) -> Result<Vec<u8>, AppError> {
+    let file = archive.by_name(path).map_err(|e| AppError::invalid(format!("Invalid backup: '{}' is missing: {}", path, e)))?;
+    budget.read_entry(file, path).map_err(|e| AppError::invalid(format!("Invalid backup: {}", e)))
+}
+
+// Restores palettes (and, if asked, settings) from a backup archive.
+// With `dry_run` nothing is written; the report shows what would happen.
+// Palettes that exist locally but not in the backup are left alone.
+pub fn restore_full_backup(
+    palettes: &PaletteRepository,
+    contents: &[u8],
+    dry_run: bool,
+    restore_settings: bool,
+    change: &PaletteChange,
+) -> Result<RestoreReport, AppError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(contents)).map_err(|e| AppError::invalid(format!("Invalid backup: not a zip archive: {}", e)))?;
+    let mut budget = UnpackBudget::default();
+    let manifest: BackupManifest = serde_json::from_slice(&read_archive_file(&mut archive, &mut budget, MANIFEST_FILE)?)
+        .map_err(|e| AppError::invalid(format!("Invalid backup: unreadable manifest: {}", e)))?;
+    if manifest.format_version > BACKUP_FORMAT_VERSION {
+        return Err(AppError::invalid(format!(
+            "Invalid backup: format version {} was written by a newer Commander ({}).",
+            manifest.format_version, manifest.app_version
+        )));
+    }
+
+    // Read everything before writing anything, so a broken archive changes nothing.
+    // Settings decide where palettes are read from after a restart, so they are checked
+    // like config.json is at startup and a bad settings file rejects the whole restore.
+    let config_path = config_file_path();
+    let settings_contents = match &manifest.settings {
+        Some(path) => Some(read_archive_file(&mut archive, &mut budget, path)?),
+        None => None,
+    };
+    if let (Some(settings), true) = (&settings_contents, restore_settings) {
+        let config_dir = config_path.as_deref().and_then(Path::parent).unwrap_or(Path::new("."));
+        parse_config(settings, config_dir).map_err(|e| AppError::invalid(format!("Invalid backup: settings: {}", e)))?;
+    }
+
+    let mut incoming: Vec<(Palette, RestoreItem)> = Vec::new();
+    let existing: HashMap<PaletteName, String> = palettes.list()?
+        .into_iter()
+        .filter_map(|name| palettes.get(&name).ok().map(|palette| (name, palette_etag(&palette))))
+        .collect();
+    let config = app_config();
+    for entry in &manifest.palettes {
+        let (root_name, local_name) = archive_palette_location(&entry.file)?;
+        let mut palette: Palette = serde_json::from_slice(&read_archive_file(&mut archive, &mut budget, &entry.file)?)
+            .map_err(|e| AppError::invalid(format!("Invalid backup: '{}' is not a palette: {}", entry.file, e)))?;
+        // The backup may come from a machine with other palette roots
+        let Some(root) = config.palette_root(root_name) else {
+            let message = format!("Palette root '{}' is not configured here; add it to the settings to restore this palette.", root_name);
+            incoming.push((palette, RestoreItem { name: entry.name.clone(), action: RestoreAction::Skip, message: Some(message) }));
+            continue;
+        };
+        palette.name = local_name.in_root(config.root_qualifier(root));
+        let mut item = RestoreItem {
+            name: palette.name.to_string(),
+            action: match existing.get(&palette.name) {
+                None => RestoreAction::Create,
+                Some(etag) if *etag == palette_etag(&palette) => RestoreAction::Unchanged,
+                Some(_) => RestoreAction::Update,
+            },
+            message: None,
+        };
+        if item.action != RestoreAction::Unchanged && root.read_only {
+            item.action = RestoreAction::Skip;
+            item.message = Some(format!("Palette root '{}' is read-only.", root.name));
+        }
+        incoming.push((palette, item));
+    }
+
+    let current_settings = config_path.as_ref().and_then(|path| fs::read(path).ok());
+    let settings_item = |action: RestoreAction, message: Option<&str>| RestoreItem {
+        name: "settings".to_string(),
+        action,
+        message: message.map(str::to_string),
+    };
+    let settings_action = match (&settings_contents, restore_settings) {
+        (None, _) => settings_item(RestoreAction::Skip, Some("The backup has no settings.")),
+        (Some(_), false) => settings_item(RestoreAction::Skip, Some("Pass ?settings=true to restore settings.")),
+        (Some(settings), true) if current_settings.as_ref() == Some(settings) => settings_item(RestoreAction::Unchanged, None),
+        (Some(_), true) if current_settings.is_some() => settings_item(RestoreAction::Update, None),
+        (Some(_), true) => settings_item(RestoreAction::Create, None),
+    };
+
+    if !dry_run {
+        for (palette, item) in &mut incoming {
+            if !matches!(item.action, RestoreAction::Create | RestoreAction::Update) {
+                continue;
+            }
+            if let Err(e) = palettes.import(palette.clone(), ConflictPolicy::Overwrite, change) {
+                item.action = RestoreAction::Skip;
+                item.message = Some(e.message);
+            }
+        }
+        if matches!(settings_action.action, RestoreAction::Create | RestoreAction::Update) {
+            if let (Some(path), Some(settings)) = (&config_path, &settings_contents) {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|e| AppError::internal(format!("Failed to create config directory: {}", e)))?;
+                }
+                fs::write(path, settings).map_err(|e| AppError::internal(format!("Failed to write settings: {}", e)))?;
+            }
+        }
+    }
+
+    let restart_required = matches!(settings_action.action, RestoreAction::Create | RestoreAction::Update);
+    Ok(RestoreReport {
+        dry_run,
+        created_at: manifest.created_at,
+        app_version: manifest.app_version,
+        palettes: incoming.into_iter().map(|(_, item)| item).collect(),
+        settings: settings_action,
+        restart_required,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    // An archive holding the given palettes, as (manifest name, archive path), and settings
+    fn backup(palettes: &[(&str, &str)], settings: Option<&str>) -> Vec<u8> {
+        let manifest = BackupManifest {
+            format_version: BACKUP_FORMAT_VERSION,
+            app_version: "test".to_string(),
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            palettes: palettes.iter().map(|(name, file)| BackupPaletteEntry {
+                name: name.to_string(),
+                file: file.to_string(),
+                etag: String::new(),
+                categories: 1,
+                commands: 1,
+            }).collect(),
+            settings: settings.map(|_| SETTINGS_FILE.to_string()),
+        };
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(MANIFEST_FILE, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&serde_json::to_vec(&manifest).unwrap()).unwrap();
+        for (name, file) in palettes {
+            zip.start_file(*file, SimpleFileOptions::default()).unwrap();
+            zip.write_all(serde_json::json!({ "name": name, "commands": { "A": { "x": {} } } }).to_string().as_bytes()).unwrap();
+        }
+        if let Some(settings) = settings {
+            zip.start_file(SETTINGS_FILE, SimpleFileOptions::default()).unwrap();
+            zip.write_all(settings.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn backup_with_settings(settings: &str) -> Vec<u8> {
+        backup(&[], Some(settings))
+    }
+
+    #[test]
+    fn archive_paths_give_root_and_name() {
+        let (root, name) = archive_palette_location("palettes/team/Deploy%3Aprod.json").unwrap();
+        assert_eq!(root, "team");
+        assert_eq!(name.local_name(), "Deploy:prod");
+        assert_eq!(name.root(), None);
+        for file in ["Deploy.json", "palettes/Deploy.json", "palettes/team/Deploy.txt", "palettes/team/..%2Fx.json"] {
+            assert!(archive_palette_location(file).unwrap_err().message.contains("is not a palette path"), "{}", file);
+        }
+    }
+
+    #[test]
+    fn palettes_of_unconfigured_roots_are_skipped() {
+        let root = format!("rust-commander-test-{}", uuid::Uuid::new_v4().simple());
+        let file = format!("palettes/{}/Deploy.json", root);
+        let name = format!("{}:Deploy", root);
+        let archive = backup(&[(name.as_str(), file.as_str())], None);
+        for dry_run in [true, false] {
+            let report = restore_full_backup(&PaletteRepository::new(), &archive, dry_run, false, &PaletteChange::default()).unwrap();
+            assert_eq!(report.palettes.len(), 1);
+            let item = &report.palettes[0];
+            assert_eq!(item.name, name);
+            assert_eq!(item.action, RestoreAction::Skip);
+            assert_eq!(
+                item.message.as_deref(),
+                Some(format!("Palette root '{}' is not configured here; add it to the settings to restore this palette.", root).as_str())
+            );
+        }
+        // Nothing ended up in the default root under the qualified name
+        assert!(PaletteRepository::new().get(&PaletteName::parse(&name).unwrap()).is_err());
+    }
+
+    #[test]
+    fn malicious_settings_reject_the_whole_restore() {
+        let malicious = [
+            r#"{"palette_roots": [{"name": "../../etc", "path": "/etc"}]}"#,
+            r#"{"palette_roots": [{"name": "a", "path": "/tmp/a"}, {"name": "a", "path": "/etc"}]}"#,
+            r#"{"palette_roots": "/etc"}"#,
+            "not json",
+        ];
+        for settings in malicious {
+            for dry_run in [true, false] {
+                // Rejected before any palette or setting is looked at, let alone written
+                let error = restore_full_backup(&PaletteRepository::new(), &backup_with_settings(settings), dry_run, true, &PaletteChange::default())
+                    .unwrap_err();
+                assert!(error.is(ErrorCode::InvalidRequest), "{}", settings);
+                assert!(error.message.starts_with("Invalid backup: settings:"), "{}", error.message);
+            }
+        }
+    }
+}
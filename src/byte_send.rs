@@ -0,0 +1,124 @@
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::state::AppState;
+use crate::net_error::NetError;
+use crate::types::{ByteFormat, BytesPayload};
+
+// Sent on /ws after bytes were written, so every client sees exactly what went out
+pub const TCP_SENT_PREFIX: &str = "TCP_SENT:";
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text.split(|c: char| c.is_whitespace() || c == ':' || c == '-')
+        .map(|group| group.strip_prefix("0x").or_else(|| group.strip_prefix("0X")).unwrap_or(group))
+        .collect();
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex data '{}': '{}' is not a hex digit.", text, c));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex data '{}': odd number of digits.", text));
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default())
+        .collect())
+}
+
+fn decode(text: &str, format: ByteFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ByteFormat::Hex => decode_hex(text),
+        ByteFormat::Base64 => BASE64.decode(text.trim()).map_err(|e| format!("Invalid base64 data: {}.", e)),
+    }
+}
+
+// Renders bytes as "02 41 03"
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+// The bytes a payload asks for: prefix, data and suffix
+pub fn decode_payload(payload: &BytesPayload) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let parts = payload.prefix.iter().chain(std::iter::once(&payload.data)).chain(payload.suffix.iter());
+    for part in parts {
+        bytes.extend(decode(part, payload.format)?);
+    }
+    if bytes.is_empty() {
+        return Err("Invalid payload: there are no bytes to send.".to_string());
+    }
+    Ok(bytes)
+}
+
+// Writes the bytes unchanged and announces them on /ws as TCP_SENT:{"bytes": n, "hex": "..."}
+pub async fn send_bytes(app_state: &AppState, bytes: &[u8]) -> Result<(), NetError> {
+    let connection = app_state.current_connection().ok_or_else(NetError::not_connected)?;
+    connection.write(bytes.to_vec()).await?;
+    println!("Sent {} raw bytes to TCP: {}", bytes.len(), to_hex(bytes));
+    let event = serde_json::json!({ "bytes": bytes.len(), "hex": to_hex(bytes) });
+    let _ = app_state.tcp_message_tx.send(format!("{}{}", TCP_SENT_PREFIX, event));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(data: &str, format: ByteFormat) -> BytesPayload {
+        BytesPayload { data: data.to_string(), format, prefix: None, suffix: None }
+    }
+
+    #[test]
+    fn hex_ignores_separators_and_prefixes() {
+        for text in ["02 41 03", "02:41:03", "02-41-03", "0x02 0X41 0x03", "024103", " 02\t41\n03 "] {
+            assert_eq!(decode_payload(&payload(text, ByteFormat::Hex)).unwrap(), [0x02, 0x41, 0x03], "{}", text);
+        }
+        assert_eq!(decode_payload(&payload("fF", ByteFormat::Hex)).unwrap(), [0xFF]);
+    }
+
+    #[test]
+    fn bad_hex_is_rejected() {
+        assert_eq!(
+            decode_payload(&payload("02 4", ByteFormat::Hex)).unwrap_err(),
+            "Invalid hex data '02 4': odd number of digits."
+        );
+        assert_eq!(
+            decode_payload(&payload("0g", ByteFormat::Hex)).unwrap_err(),
+            "Invalid hex data '0g': 'g' is not a hex digit."
+        );
+        // Separators are dropped before the digits are paired up
+        assert_eq!(decode_payload(&payload("0 2", ByteFormat::Hex)).unwrap(), [0x02]);
+        assert!(decode_payload(&payload("é", ByteFormat::Hex)).is_err());
+    }
+
+    #[test]
+    fn base64_is_decoded() {
+        assert_eq!(decode_payload(&payload(" AkED\n", ByteFormat::Base64)).unwrap(), [0x02, 0x41, 0x03]);
+        assert!(decode_payload(&payload("AkE", ByteFormat::Base64)).unwrap_err().starts_with("Invalid base64 data:"));
+    }
+
+    #[test]
+    fn prefix_and_suffix_wrap_the_data() {
+        let payload = BytesPayload {
+            data: "41".to_string(),
+            format: ByteFormat::Hex,
+            prefix: Some("02".to_string()),
+            suffix: Some("03 0d".to_string()),
+        };
+        assert_eq!(decode_payload(&payload).unwrap(), [0x02, 0x41, 0x03, 0x0d]);
+    }
+
+    #[test]
+    fn empty_payloads_are_rejected() {
+        assert_eq!(
+            decode_payload(&payload(" ", ByteFormat::Hex)).unwrap_err(),
+            "Invalid payload: there are no bytes to send."
+        );
+        assert!(decode_payload(&payload("", ByteFormat::Base64)).is_err());
+    }
+
+    #[test]
+    fn hex_rendering() {
+        assert_eq!(to_hex(&[0x02, 0x41, 0xff]), "02 41 ff");
+        assert_eq!(to_hex(&[]), "");
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_FILE_NAME: &str = "config.json";
+const PALETTE_DIR_NAME: &str = "palettes";
+// Name of the root used when the config file does not list any
+const DEFAULT_ROOT_NAME: &str = "local";
+
+// A directory palettes are stored in, e.g. a writable personal folder or
+// a read-only checkout of a team repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaletteRoot {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+// Contents of config.json in the platform config directory, e.g.
+// ~/.config/rustcommander/config.json on Linux:
+//
+// {
+//   "palette_roots": [
+//     { "name": "personal", "path": "/home/me/palettes" },
+//     { "name": "shared", "path": "/home/me/src/team-palettes", "read_only": true }
+//   ]
+// }
+//
+// The first root is the default one: its palettes are addressed by their bare name,
+// palettes in other roots as "<root>:<name>". Relative paths are resolved against
+// the config directory.
+#[derive(Debug, Deserialize)]
+pub struct AppConfig {
+    #[serde(default)]
+    pub palette_roots: Vec<PaletteRoot>,
+}
+
+static CONFIG: OnceLock<AppConfig> = OnceLock::new();
+
+#[cfg(not(test))]
+fn project_dirs() -> Option<directories::ProjectDirs> {
+    directories::ProjectDirs::from("com", "RustCommander", "RustCommander")
+}
+
+// The loaded configuration. Read once on first use; a missing file means defaults.
+pub fn app_config() -> &'static AppConfig {
+    CONFIG.get_or_init(|| match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Ignoring invalid configuration: {}", e);
+            default_config()
+        }
+    })
+}
+
+// Where config.json lives, whether or not it exists
+#[cfg(not(test))]
+pub fn config_file_path() -> Option<PathBuf> {
+    project_dirs().map(|proj_dirs| proj_dirs.config_dir().join(CONFIG_FILE_NAME))
+}
+
+// Tests never read or replace the user's settings
+#[cfg(test)]
+pub fn config_file_path() -> Option<PathBuf> {
+    Some(crate::test_support::test_home().join(CONFIG_FILE_NAME))
+}
+
+fn load_config() -> Result<AppConfig, String> {
+    let Some(config_path) = config_file_path() else {
+        return Ok(default_config());
+    };
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    if !config_path.exists() {
+        return Ok(default_config());
+    }
+    let contents = fs::read(&config_path)
+        .map_err(|e| format!("Failed to read '{}': {}", config_path.display(), e))?;
+    parse_config(&contents, config_dir).map_err(|e| format!("Invalid '{}': {}", config_path.display(), e))
+}
+
+// Parses and checks the contents of a config.json living in `config_dir`. Used for the
+// file on disk and for settings restored from a backup before they replace it.
+pub fn parse_config(contents: &[u8], config_dir: &Path) -> Result<AppConfig, String> {
+    let mut config: AppConfig = serde_json::from_slice(contents).map_err(|e| format!("invalid JSON: {}", e))?;
+
+    for root in &mut config.palette_roots {
+        if !is_valid_root_name(&root.name) {
+            return Err(format!(
+                "Invalid palette root name '{}': use only letters, digits, '-' and '_'.",
+                root.name
+            ));
+        }
+        if root.path.is_relative() {
+            root.path = config_dir.join(&root.path);
+        }
+    }
+    for (i, root) in config.palette_roots.iter().enumerate() {
+        if config.palette_roots[..i].iter().any(|other| other.name == root.name) {
+            return Err(format!("Palette root '{}' is configured more than once.", root.name));
+        }
+    }
+    if config.palette_roots.is_empty() {
+        config.palette_roots = default_config().palette_roots;
+    }
+    Ok(config)
+}
+
+fn default_config() -> AppConfig {
+    AppConfig {
+        palette_roots: vec![PaletteRoot { name: DEFAULT_ROOT_NAME.to_string(), path: default_palette_dir(), read_only: false }],
+    }
+}
+
+#[cfg(not(test))]
+fn default_palette_dir() -> PathBuf {
+    match project_dirs() {
+        Some(proj_dirs) => proj_dirs.data_dir().join(PALETTE_DIR_NAME),
+        None => Path::new(PALETTE_DIR_NAME).to_path_buf(),
+    }
+}
+
+// Nor the user's palettes
+#[cfg(test)]
+fn default_palette_dir() -> PathBuf {
+    crate::test_support::test_home().join(PALETTE_DIR_NAME)
+}
+
+pub fn is_valid_root_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl AppConfig {
+    pub fn default_root(&self) -> &PaletteRoot {
+        // load_config never leaves the list empty
+        &self.palette_roots[0]
+    }
+
+    pub fn palette_root(&self, name: &str) -> Option<&PaletteRoot> {
+        self.palette_roots.iter().find(|root| root.name == name)
+    }
+
+    // Prefix used for the palettes of a root; the default root's palettes have none
+    pub fn root_qualifier<'a>(&self, root: &'a PaletteRoot) -> Option<&'a str> {
+        if root.name == self.default_root().name {
+            None
+        } else {
+            Some(&root.name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots_are_checked_and_resolved_against_the_config_dir() {
+        let config = parse_config(
+            br#"{"palette_roots": [{"name": "mine", "path": "palettes"}, {"name": "team_1", "path": "/srv/p", "read_only": true}]}"#,
+            Path::new("/home/me/.config/rc"),
+        ).unwrap();
+        assert_eq!(config.default_root().name, "mine");
+        assert_eq!(config.default_root().path, Path::new("/home/me/.config/rc/palettes"));
+        let team = config.palette_root("team_1").unwrap();
+        assert!(team.read_only);
+        assert_eq!(config.root_qualifier(team), Some("team_1"));
+        assert_eq!(config.root_qualifier(config.default_root()), None);
+    }
+
+    #[test]
+    fn no_roots_means_the_default_root() {
+        let config = parse_config(b"{}", Path::new("/x")).unwrap();
+        assert_eq!(config.palette_roots.len(), 1);
+        assert_eq!(config.default_root().name, DEFAULT_ROOT_NAME);
+    }
+
+    #[test]
+    fn bad_root_names_and_duplicates_are_rejected() {
+        for contents in [
+            r#"{"palette_roots": [{"name": "../x", "path": "/x"}]}"#,
+            r#"{"palette_roots": [{"name": "", "path": "/x"}]}"#,
+            r#"{"palette_roots": [{"name": "a:b", "path": "/x"}]}"#,
+            r#"{"palette_roots": [{"name": "a", "path": "/x"}, {"name": "a", "path": "/y"}]}"#,
+            r#"{"palette_roots": [{"name": "a"}]}"#,
+            r#""roots""#,
+        ] {
+            assert!(parse_config(contents.as_bytes(), Path::new("/x")).is_err(), "{}", contents);
+        }
+    }
+}
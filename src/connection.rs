@@ -0,0 +1,625 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value as JsonValue, Deserializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::Instant;
+
+use crate::byte_send::to_hex;
+use crate::framing::{Framing, FrameDecoder, FrameEvent};
+use crate::jsonrpc::{spawn_timeout_sweeper, JsonRpcSession};
+use crate::payload_encoding::{Decoded, PayloadEncoding};
+use crate::reader_limits::{FlushReason, ReaderLimits, ReaderStats};
+use crate::net_error::{NetError, NetErrorKind};
+
+// Writes waiting for the writer task; further sends are refused until it catches up
+pub const WRITE_QUEUE_LEN: usize = 32;
+
+// Sent on /ws when the connection goes away
+const CLOSED_MESSAGE: &str = "TCP_CONNECTION_CLOSED_OR_STREAM_ENDED";
+
+fn default_connect_ms() -> u64 {
+    10_000
+}
+
+fn default_write_ms() -> u64 {
+    5_000
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct ConnectionTimeouts {
+    // How long to wait for the host name to resolve and the device to accept the connection
+    #[serde(default = "default_connect_ms")]
+    pub connect_ms: u64,
+    // How long one write may take before the device is considered stuck
+    #[serde(default = "default_write_ms")]
+    pub write_ms: u64,
+    // Closes the connection when the device sends nothing for this long; 0 keeps it open
+    #[serde(default)]
+    pub idle_ms: u64,
+}
+
+impl Default for ConnectionTimeouts {
+    fn default() -> Self {
+        Self { connect_ms: default_connect_ms(), write_ms: default_write_ms(), idle_ms: 0 }
+    }
+}
+
+impl ConnectionTimeouts {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.connect_ms == 0 || self.write_ms == 0 {
+            return Err("Invalid timeouts: connect_ms and write_ms must be at least 1.".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Everything about a connection that is chosen in /connect
+pub struct ConnectionSettings {
+    pub framing: Option<Framing>,
+    pub encoding: PayloadEncoding,
+    pub jsonrpc: Option<Arc<JsonRpcSession>>,
+    pub limits: ReaderLimits,
+    pub timeouts: ConnectionTimeouts,
+    pub stats: Arc<ReaderStats>,
+}
+
+struct WriteRequest {
+    bytes: Vec<u8>,
+    done: oneshot::Sender<Result<(), NetError>>,
+}
+
+// Resolves the address and connects to the first of its addresses that answers, all
+// within the connect timeout
+async fn connect(address: &str, timeout: Duration) -> Result<TcpStream, NetError> {
+    let deadline = Instant::now() + timeout;
+    let timed_out = || NetError::new(
+        NetErrorKind::Timeout,
+        format!("No answer from {} within {} ms.", address, timeout.as_millis()),
+    ).at(address);
+    let addrs: Vec<_> = match tokio::time::timeout_at(deadline, lookup_host(address)).await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            let message = format!("'{}' is not a valid address; expected host:port.", address);
+            return Err(NetError::new(NetErrorKind::InvalidAddress, message).at(address));
+        }
+        Ok(Err(e)) => return Err(NetError::new(NetErrorKind::Dns, format!("Could not resolve {}: {}", address, e)).at(address)),
+        Err(_) => return Err(timed_out()),
+    };
+    let mut last_error = NetError::new(NetErrorKind::Dns, format!("{} did not resolve to any address.", address)).at(address);
+    for addr in addrs {
+        match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = NetError::from_io(&format!("TCP connection error to {}", addr), &e).at(address),
+            Err(_) => return Err(timed_out()),
+        }
+    }
+    Err(last_error)
+}
+
+// An open TCP connection. The socket is owned by two tasks: a reader that broadcasts
+// device messages on /ws and a writer that drains a queue of outbound writes, so no
+// request ever waits on the socket while holding a lock.
+pub struct Connection {
+    // Address given to /connect
+    pub address: String,
+    // Socket address the connection actually reached
+    pub peer: SocketAddr,
+    pub framing: Option<Framing>,
+    pub encoding: PayloadEncoding,
+    pub jsonrpc: Option<Arc<JsonRpcSession>>,
+    writes: mpsc::Sender<WriteRequest>,
+    reader_task: JoinHandle<()>,
+    writer_task: JoinHandle<()>,
+}
+
+impl Connection {
+    pub async fn open(address: &str, settings: ConnectionSettings, tx: Sender<String>) -> Result<Connection, NetError> {
+        let timeouts = settings.timeouts;
+        let stream = connect(address, Duration::from_millis(timeouts.connect_ms)).await?;
+        let peer = stream.peer_addr().map_err(|e| NetError::from_io("Failed to read the peer address", &e).at(address))?;
+        let (read_half, write_half) = stream.into_split();
+
+        let (writes, queue) = mpsc::channel(WRITE_QUEUE_LEN);
+        // Set once the reader is spawned, so a failed write can stop it
+        let reader = Arc::new(OnceLock::new());
+        let writer_task = tokio::spawn(write_loop(write_half, queue, Duration::from_millis(timeouts.write_ms), reader.clone(), tx.clone()));
+
+        let inbound_framing = settings.framing.clone().filter(Framing::frames_inbound);
+        let device = DeviceReader {
+            encoding: settings.encoding,
+            rpc: settings.jsonrpc.clone(),
+            limits: settings.limits,
+            idle_timeout: (timeouts.idle_ms > 0).then(|| Duration::from_millis(timeouts.idle_ms)),
+            writer: writer_task.abort_handle(),
+            stats: settings.stats,
+            tx: tx.clone(),
+        };
+        let reader_task = tokio::spawn(async move {
+            match inbound_framing {
+                Some(framing) => read_frames(read_half, framing.decoder(), &device).await,
+                None if device.encoding != PayloadEncoding::Json => read_encoded(read_half, &device).await,
+                None => read_json(read_half, &device).await,
+            }
+            println!("TCP reader task finished.");
+            if let Err(e) = device.tx.send(CLOSED_MESSAGE.to_string()) {
+                println!("Failed to broadcast TCP close/end message: {}. Msg: {}", e, CLOSED_MESSAGE);
+            }
+        });
+        let _ = reader.set(reader_task.abort_handle());
+
+        if let Some(rpc) = &settings.jsonrpc {
+            spawn_timeout_sweeper(rpc, tx);
+        }
+
+        Ok(Connection {
+            address: address.to_string(),
+            peer,
+            framing: settings.framing,
+            encoding: settings.encoding,
+            jsonrpc: settings.jsonrpc,
+            writes,
+            reader_task,
+            writer_task,
+        })
+    }
+
+    // True if `address` names the device this connection reached, so "localhost:9000"
+    // matches a connection opened as "127.0.0.1:9000". Addresses that do not resolve
+    // within a second never match.
+    pub async fn is_connected_to(&self, address: &str) -> bool {
+        if address == self.address {
+            return true;
+        }
+        match tokio::time::timeout(Duration::from_secs(1), lookup_host(address)).await {
+            Ok(Ok(mut addrs)) => addrs.any(|addr| addr == self.peer),
+            _ => false,
+        }
+    }
+
+    // Wraps outbound bytes in the connection's frame, if it has one
+    pub fn frame(&self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        match &self.framing {
+            Some(framing) => framing.encode(&bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    // Queues the bytes and waits until the writer task has written them. Fails at once
+    // when the queue is full, so a device that stopped reading cannot pile up requests.
+    pub async fn write(&self, bytes: Vec<u8>) -> Result<(), NetError> {
+        let (done, result) = oneshot::channel();
+        self.writes.try_send(WriteRequest { bytes, done }).map_err(|e| match e {
+            TrySendError::Full(_) => NetError::new(
+                NetErrorKind::QueueFull,
+                format!("Write queue is full: {} writes are waiting for the device. Try again once it catches up.", WRITE_QUEUE_LEN),
+            ),
+            TrySendError::Closed(_) => NetError::not_connected(),
+        }.at(&self.address))?;
+        result.await.unwrap_or_else(|_| Err(NetError::new(NetErrorKind::Reset, "TCP connection closed before the write completed.")))
+            .map_err(|e| e.at(&self.address))
+    }
+
+    // Writes accepted but not yet written
+    pub fn queued_writes(&self) -> usize {
+        WRITE_QUEUE_LEN - self.writes.capacity()
+    }
+
+    // True once the writer has stopped, e.g. after a failed write or an idle timeout
+    pub fn is_closed(&self) -> bool {
+        self.writes.is_closed()
+    }
+
+    pub fn close(&self) {
+        self.reader_task.abort();
+        self.writer_task.abort();
+    }
+}
+
+async fn write_loop(
+    mut stream: OwnedWriteHalf,
+    mut queue: mpsc::Receiver<WriteRequest>,
+    timeout: Duration,
+    reader: Arc<OnceLock<AbortHandle>>,
+    tx: Sender<String>,
+) {
+    while let Some(request) = queue.recv().await {
+        let result = match tokio::time::timeout(timeout, stream.write_all(&request.bytes)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(NetError::from_io("TCP write error", &e)),
+            Err(_) => Err(NetError::new(
+                NetErrorKind::Timeout,
+                format!("TCP write timed out after {} ms; the device is not reading.", timeout.as_millis()),
+            )),
+        };
+        let failed = result.is_err();
+        if let Err(e) = &result {
+            println!("{}", e);
+        }
+        // The caller may have given up waiting; the write happened either way
+        let _ = request.done.send(result);
+        if failed {
+            // A partial write leaves the stream in an unknown state, so the connection is
+            // closed: queued writes fail, and later ones find it not connected
+            queue.close();
+            let _ = stream.shutdown().await;
+            if let Some(reader) = reader.get().filter(|reader| !reader.is_finished()) {
+                reader.abort();
+                let _ = tx.send(CLOSED_MESSAGE.to_string());
+            }
+            break;
+        }
+    }
+}
+
+// What every reader needs besides the stream itself
+struct DeviceReader {
+    encoding: PayloadEncoding,
+    rpc: Option<Arc<JsonRpcSession>>,
+    limits: ReaderLimits,
+    // Closes the connection when the device stays silent this long
+    idle_timeout: Option<Duration>,
+    // Stopped along with the reader on an idle timeout, which closes the socket
+    writer: AbortHandle,
+    stats: Arc<ReaderStats>,
+    tx: Sender<String>,
+}
+
+enum ReadOutcome {
+    Data(usize),
+    // A partial message sat in the buffer for longer than partial_timeout_ms
+    Idle,
+    Closed,
+}
+
+impl DeviceReader {
+    // Reads the next chunk. While a partial message is buffered, gives up after the
+    // partial timeout so the caller can flush it. When the device stays silent for the
+    // idle timeout, reports it on /ws and closes the connection.
+    async fn read_chunk<R: AsyncRead + Unpin>(&self, reader: &mut R, buf: &mut [u8], partial_buffered: bool) -> ReadOutcome {
+        let partial_timeout = (partial_buffered && self.limits.partial_timeout_ms > 0)
+            .then(|| Duration::from_millis(self.limits.partial_timeout_ms));
+        let wait = match (partial_timeout, self.idle_timeout) {
+            (Some(partial), Some(idle)) => Some(partial.min(idle)),
+            (partial, idle) => partial.or(idle),
+        };
+        let result = match wait {
+            Some(wait) => match tokio::time::timeout(wait, reader.read(buf)).await {
+                Ok(result) => result,
+                Err(_) if Some(wait) == partial_timeout => return ReadOutcome::Idle,
+                Err(_) => {
+                    let error = NetError::new(
+                        NetErrorKind::Timeout,
+                        format!("No data from the device for {} ms; closing the connection.", wait.as_millis()),
+                    );
+                    println!("{}", error);
+                    let _ = self.tx.send(format!("TCP_READ_ERROR:{}", error.to_json()));
+                    self.writer.abort();
+                    return ReadOutcome::Closed;
+                }
+            },
+            None => reader.read(buf).await,
+        };
+        match result {
+            Ok(0) => {
+                println!("TCP connection closed by peer (EOF).");
+                ReadOutcome::Closed
+            }
+            Ok(n) => {
+                self.stats.received(n);
+                ReadOutcome::Data(n)
+            }
+            Err(e) => {
+                println!("TCP read error: {}", e);
+                ReadOutcome::Closed
+            }
+        }
+    }
+
+    fn send(&self, message: String, label: &str) {
+        println!("TCP In ({}): {}", label, message);
+        if let Err(e) = self.tx.send(message.clone()) {
+            println!("Failed to broadcast TCP message: {}. Msg: {}", e, message);
+        }
+    }
+
+    // Broadcasts a device message; JSON-RPC connections tag responses and notifications
+    fn broadcast(&self, value: JsonValue, label: &str) {
+        let messages = match &self.rpc {
+            Some(rpc) => rpc.inbound(value),
+            None => vec![value.to_string()],
+        };
+        for message in messages {
+            self.send(message, label);
+        }
+    }
+
+    fn frame_error(&self, error: &str, bytes: &[u8]) {
+        self.stats.dropped(bytes.len());
+        self.send(format!("TCP_FRAME_ERROR:{}", serde_json::json!({ "error": error, "hex": to_hex(bytes) })), "Frame");
+    }
+}
+
+// Reader for connections with inbound framing. Each frame that passes its checks is
+// broadcast like any device message; frames that fail are reported as TCP_FRAME_ERROR:{...}.
+// With a binary encoding, frames that do not decode are reported the same way.
+async fn read_frames(reader: OwnedReadHalf, mut decoder: FrameDecoder, device: &DeviceReader) {
+    let mut buf_reader = BufReader::new(reader);
+    let mut temp_read_buf = [0u8; 4096];
+    loop {
+        let n = match device.read_chunk(&mut buf_reader, &mut temp_read_buf, decoder.buffered() > 0).await {
+            ReadOutcome::Data(n) => n,
+            ReadOutcome::Idle => {
+                device.stats.flush(&device.tx, FlushReason::Idle, &decoder.take_buffer());
+                continue;
+            }
+            ReadOutcome::Closed => {
+                device.stats.flush(&device.tx, FlushReason::Closed, &decoder.take_buffer());
+                break;
+            }
+        };
+        for event in decoder.push(&temp_read_buf[..n]) {
+            match event {
+                FrameEvent::Frame(payload) => match device.encoding.decode(&payload) {
+                    Ok(json_value) => device.broadcast(json_value, "Frame"),
+                    Err(_) if device.encoding == PayloadEncoding::Json => device.send(String::from_utf8_lossy(&payload).into_owned(), "Frame"),
+                    Err(e) => device.frame_error(&e, &payload),
+                },
+                FrameEvent::Invalid { message, bytes } => device.frame_error(&message, &bytes),
+            }
+        }
+        device.stats.dropped(decoder.take_skipped());
+        if decoder.buffered() > device.limits.max_frame_bytes {
+            device.stats.flush(&device.tx, FlushReason::Oversized, &decoder.take_buffer());
+        }
+    }
+}
+
+// Reader for unframed MessagePack or CBOR streams. Values are self-delimiting, so they
+// are decoded back to back and broadcast as JSON text.
+async fn read_encoded(reader: OwnedReadHalf, device: &DeviceReader) {
+    let encoding = device.encoding;
+    let mut buf_reader = BufReader::new(reader);
+    let mut data_buffer = Vec::new();
+    let mut temp_read_buf = [0u8; 4096];
+    loop {
+        let n = match device.read_chunk(&mut buf_reader, &mut temp_read_buf, !data_buffer.is_empty()).await {
+            ReadOutcome::Data(n) => n,
+            ReadOutcome::Idle => {
+                device.stats.flush(&device.tx, FlushReason::Idle, &std::mem::take(&mut data_buffer));
+                continue;
+            }
+            ReadOutcome::Closed => {
+                device.stats.flush(&device.tx, FlushReason::Closed, &data_buffer);
+                break;
+            }
+        };
+        data_buffer.extend_from_slice(&temp_read_buf[..n]);
+        // Decoded bytes are dropped in one go once the chunk is done, not value by value
+        let mut consumed = 0;
+        while consumed < data_buffer.len() {
+            match encoding.decode_next(&data_buffer[consumed..]) {
+                Decoded::Value(json_value, len) => {
+                    consumed += len;
+                    device.broadcast(json_value, encoding.label());
+                }
+                Decoded::Incomplete => break,
+                Decoded::Invalid(e) => {
+                    // Skip one byte and try again from there
+                    println!("TCP stream: {}. Skipping byte {:02x}.", e, data_buffer[consumed]);
+                    consumed += 1;
+                    device.stats.dropped(1);
+                }
+            }
+        }
+        data_buffer.drain(..consumed);
+        if data_buffer.len() > device.limits.max_frame_bytes {
+            device.stats.flush(&device.tx, FlushReason::Oversized, &std::mem::take(&mut data_buffer));
+        }
+    }
+}
+
+
+// Reader for plain JSON streams: values are parsed back to back, skipping anything
+// that is not JSON.
+async fn read_json(reader: OwnedReadHalf, device: &DeviceReader) {
+    let mut buf_reader = BufReader::new(reader);
+    let mut data_buffer = Vec::new();
+    let mut current_read_offset = 0; // Offset for the start of data to process in data_buffer
+    let mut resyncing = false; // Set after a flush, until the next '{' or '[' is found
+
+    loop { // Outer loop: Read more data from socket, then process data_buffer
+
+        loop { // Inner loop: Process available data
+            // Pre-skip any leading null bytes from the current_read_offset
+            while current_read_offset < data_buffer.len() && data_buffer[current_read_offset] == 0u8 {
+                current_read_offset += 1;
+                device.stats.dropped(1);
+            }
+
+            if current_read_offset >= data_buffer.len() {
+                break; 
+            }
+
+            // After a flush the stream may resume in the middle of a value, so
+            // skip ahead to something that can start an object or array.
+            if resyncing {
+                match data_buffer[current_read_offset..].iter().position(|b| *b == b'{' || *b == b'[') {
+                    Some(skip) => {
+                        device.stats.dropped(skip);
+                        current_read_offset += skip;
+                        resyncing = false;
+                    }
+                    None => {
+                        device.stats.dropped(data_buffer.len() - current_read_offset);
+                        current_read_offset = data_buffer.len();
+                        break;
+                    }
+                }
+            }
+
+            // Create a deserializer for the current segment of the buffer.
+            let mut stream_deserializer = Deserializer::from_slice(&data_buffer[current_read_offset..]).into_iter::<JsonValue>();
+
+            match stream_deserializer.next() {
+                Some(Ok(json_value)) => {
+                    device.broadcast(json_value, "Streamed JSON");
+                    // Advance current_read_offset by the number of bytes consumed for this JSON object.
+                    current_read_offset += stream_deserializer.byte_offset();
+                }
+                Some(Err(ref e)) if e.is_eof() => {
+                    // EOF in the current slice means an incomplete JSON object.
+                    // We need to read more data from the socket.
+                    // current_read_offset is not advanced here, as the data from this point is partial.
+                    // The buffer compaction logic later will preserve this partial data.
+                    break; // Break inner loop to read more data.
+                }
+                Some(Err(e)) => {
+                    // A syntax error or other non-EOF error occurred.
+                    let error_offset_in_slice = stream_deserializer.byte_offset();
+                    println!(
+                        "TCP stream: Encountered non-JSON data or syntax error: '{}'. Occurred at offset {} within the current data segment being parsed. Attempting to skip.",
+                        e,
+                        error_offset_in_slice
+                    );
+
+                    // We must advance past the problematic data to avoid an infinite loop.
+                    // Advance by the offset where the error occurred in the slice + 1 to skip the char causing it.
+                    let skipped = (error_offset_in_slice + 1).min(data_buffer.len() - current_read_offset);
+                    device.stats.dropped(skipped);
+                    current_read_offset += skipped;
+                }
+                None => {
+                    // The deserializer's iterator is exhausted for the current slice.
+                    // This means the slice was empty or contained only data that serde_json
+                    // considers "trailing" after any valid JSON (e.g., whitespace it skipped).
+                    // Advance current_read_offset by the number of bytes consumed from the slice.
+                    current_read_offset += stream_deserializer.byte_offset();
+                    break; // Break inner loop, as this slice is fully processed or no more JSON can be formed from it.
+                }
+            }
+        } // End inner processing loop
+
+        // Buffer compaction: Remove processed data from the beginning of data_buffer.
+        if current_read_offset > 0 {
+            if current_read_offset >= data_buffer.len() {
+                data_buffer.clear(); // All data processed
+            } else {
+                data_buffer.drain(..current_read_offset); // Remove processed prefix
+            }
+            current_read_offset = 0; // Reset offset as we've modified the buffer's beginning
+        }
+
+        // Whatever is left is a partial JSON value; give up on it once it gets too big.
+        // The parser then starts over with the next bytes that arrive.
+        if data_buffer.len() > device.limits.max_frame_bytes {
+            device.stats.flush(&device.tx, FlushReason::Oversized, &data_buffer);
+            data_buffer.clear();
+            resyncing = true;
+        }
+
+        // Read more data from the socket.
+        let mut temp_read_buf = [0u8; 4096];
+        match device.read_chunk(&mut buf_reader, &mut temp_read_buf, !data_buffer.is_empty()).await {
+            ReadOutcome::Data(n) => {
+                data_buffer.extend_from_slice(&temp_read_buf[..n]);
+                // Loop back to inner processing loop with new data.
+            }
+            ReadOutcome::Idle => {
+                // The partial value never completed; flush it and resync on new data
+                device.stats.flush(&device.tx, FlushReason::Idle, &data_buffer);
+                data_buffer.clear();
+                resyncing = true;
+            }
+            ReadOutcome::Closed => {
+                // Don't broadcast read errors either, just terminate the reader for socket errors.
+                device.stats.flush(&device.tx, FlushReason::Closed, &data_buffer);
+                break; // Break outer loop, connection closed.
+            }
+        }
+    } // End outer loop (socket read loop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            framing: None,
+            encoding: PayloadEncoding::default(),
+            jsonrpc: None,
+            limits: ReaderLimits::default(),
+            timeouts: ConnectionTimeouts::default(),
+            stats: Arc::new(ReaderStats::default()),
+        }
+    }
+
+    #[test]
+    fn timeouts_default_and_validate() {
+        let timeouts: ConnectionTimeouts = serde_json::from_str(r#"{"idle_ms": 500}"#).unwrap();
+        assert_eq!((timeouts.connect_ms, timeouts.write_ms, timeouts.idle_ms), (10_000, 5_000, 500));
+        assert!(timeouts.validate().is_ok());
+        for timeouts in [
+            ConnectionTimeouts { connect_ms: 0, ..Default::default() },
+            ConnectionTimeouts { write_ms: 0, ..Default::default() },
+        ] {
+            assert_eq!(timeouts.validate().unwrap_err(), "Invalid timeouts: connect_ms and write_ms must be at least 1.");
+        }
+        assert!(ConnectionTimeouts { connect_ms: 1, write_ms: 1, idle_ms: 0 }.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn connect_failures_are_classified() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let (tx, _rx) = tokio::sync::broadcast::channel(16);
+        let err = Connection::open(&address, settings(), tx.clone()).await.err().unwrap();
+        assert_eq!(err.kind, NetErrorKind::Refused);
+        assert_eq!(err.address.as_deref(), Some(address.as_str()));
+        let err = Connection::open("no-port", settings(), tx).await.err().unwrap();
+        assert_eq!(err.kind, NetErrorKind::InvalidAddress);
+    }
+
+    #[tokio::test]
+    async fn targets_match_by_resolved_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, _rx) = tokio::sync::broadcast::channel(16);
+        let connection = Connection::open(&format!("127.0.0.1:{}", port), settings(), tx).await.unwrap();
+
+        assert!(connection.is_connected_to(&format!("127.0.0.1:{}", port)).await);
+        assert!(connection.is_connected_to(&format!("localhost:{}", port)).await);
+        assert!(!connection.is_connected_to(&format!("127.0.0.1:{}", port + 1)).await);
+        assert!(!connection.is_connected_to("not an address").await);
+        connection.close();
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, mut rx) = tokio::sync::broadcast::channel(16);
+        let settings = ConnectionSettings { timeouts: ConnectionTimeouts { write_ms: 100, ..Default::default() }, ..settings() };
+        let connection = Connection::open(&address, settings, tx).await.unwrap();
+        // Accepted but never read, so the socket buffers fill up and the write stalls
+        let (_device, _) = listener.accept().await.unwrap();
+
+        let err = connection.write(vec![0; 64 * 1024 * 1024]).await.unwrap_err();
+        assert_eq!(err.kind, NetErrorKind::Timeout);
+        assert!(connection.is_closed());
+        assert_eq!(connection.write(b"more".to_vec()).await.unwrap_err().kind, NetErrorKind::NotConnected);
+        assert_eq!(rx.recv().await.unwrap(), CLOSED_MESSAGE);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(connection.reader_task.is_finished());
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::header::ETAG;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::net_error::{NetError, NetErrorKind};
+
+// What went wrong, as a stable machine-readable code. Clients should branch on this,
+// never on the message, which is meant for people and may change.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The request itself is malformed: a bad name, escape, query parameter or body
+    InvalidRequest,
+    NotFound,
+    AlreadyExists,
+    // The palette changed since the client read it (If-Match)
+    PreconditionFailed,
+    PayloadTooLarge,
+    // The palette document has validation errors; details.issues lists them
+    InvalidPalette,
+    // The export format cannot hold the palette, e.g. a null in TOML
+    Unrepresentable,
+    // The palette root is configured as read-only
+    ReadOnly,
+    // A dangerous command was sent without a confirmation token
+    ConfirmationRequired,
+    // The confirmation token has expired, is for another command or the palette changed
+    ConfirmationRejected,
+    // The command is meant for another device than the one connected
+    WrongTarget,
+    // Connect and write failures, one per NetErrorKind
+    InvalidAddress,
+    Dns,
+    Refused,
+    Timeout,
+    Unreachable,
+    Reset,
+    QueueFull,
+    NotConnected,
+    Network,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidAddress => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::AlreadyExists | ErrorCode::WrongTarget => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::InvalidPalette | ErrorCode::Unrepresentable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ReadOnly | ErrorCode::ConfirmationRejected => StatusCode::FORBIDDEN,
+            ErrorCode::ConfirmationRequired => StatusCode::PRECONDITION_REQUIRED,
+            // Problems reaching the device are the device's, not ours
+            ErrorCode::Dns | ErrorCode::Refused | ErrorCode::Unreachable | ErrorCode::Reset => StatusCode::BAD_GATEWAY,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            // The device is not keeping up with the writes
+            ErrorCode::QueueFull | ErrorCode::NotConnected => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Network | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<NetErrorKind> for ErrorCode {
+    fn from(kind: NetErrorKind) -> Self {
+        match kind {
+            NetErrorKind::InvalidAddress => ErrorCode::InvalidAddress,
+            NetErrorKind::Dns => ErrorCode::Dns,
+            NetErrorKind::Refused => ErrorCode::Refused,
+            NetErrorKind::Timeout => ErrorCode::Timeout,
+            NetErrorKind::Unreachable => ErrorCode::Unreachable,
+            NetErrorKind::Reset => ErrorCode::Reset,
+            NetErrorKind::QueueFull => ErrorCode::QueueFull,
+            NetErrorKind::NotConnected => ErrorCode::NotConnected,
+            NetErrorKind::Other => ErrorCode::Network,
+        }
+    }
+}
+
+// Every API error, sent as {"error": {"code": "...", "message": "...", "details": ...}}.
+// `details` is null unless the error carries more, e.g. validation issues or the
+// current version of a palette that was modified by someone else.
+#[derive(Serialize, Debug, Clone)]
+pub struct AppError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<JsonValue>,
+}
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), details: None }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn already_exists(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::AlreadyExists, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn with_details(mut self, details: JsonValue) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn is(&self, code: ErrorCode) -> bool {
+        self.code == code
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        // A 412 carries the palette's current version, so the client can merge and retry
+        if let Some(etag) = self.details.as_ref().and_then(|d| d.get("etag")).and_then(JsonValue::as_str) {
+            response.insert_header((ETAG, etag));
+        }
+        response.json(serde_json::json!({ "error": self }))
+    }
+}
+
+impl From<NetError> for AppError {
+    fn from(e: NetError) -> Self {
+        let details = e.address.as_ref().map(|address| serde_json::json!({ "address": address }));
+        Self { code: e.kind.into(), message: e.message, details }
+    }
+}
+
+// Bodies, query strings and path segments actix cannot parse get the same error body
+// as everything else instead of its plain-text defaults
+pub fn json_error_handler(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let code = match e {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => ErrorCode::PayloadTooLarge,
+        _ => ErrorCode::InvalidRequest,
+    };
+    AppError::new(code, format!("Invalid JSON body: {}", e)).into()
+}
+
+pub fn query_error_handler(e: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::invalid(format!("Invalid query string: {}", e)).into()
+}
+
+pub fn path_error_handler(e: PathError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::not_found(format!("Invalid path: {}", e)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    async fn body(e: &AppError) -> JsonValue {
+        let bytes = to_bytes(e.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn codes_map_to_statuses() {
+        let cases = [
+            (ErrorCode::InvalidRequest, 400),
+            (ErrorCode::InvalidAddress, 400),
+            (ErrorCode::NotFound, 404),
+            (ErrorCode::AlreadyExists, 409),
+            (ErrorCode::WrongTarget, 409),
+            (ErrorCode::PreconditionFailed, 412),
+            (ErrorCode::PayloadTooLarge, 413),
+            (ErrorCode::InvalidPalette, 422),
+            (ErrorCode::Unrepresentable, 422),
+            (ErrorCode::ReadOnly, 403),
+            (ErrorCode::ConfirmationRejected, 403),
+            (ErrorCode::ConfirmationRequired, 428),
+            (ErrorCode::Dns, 502),
+            (ErrorCode::Refused, 502),
+            (ErrorCode::Unreachable, 502),
+            (ErrorCode::Reset, 502),
+            (ErrorCode::Timeout, 504),
+            (ErrorCode::QueueFull, 503),
+            (ErrorCode::NotConnected, 503),
+            (ErrorCode::Network, 500),
+            (ErrorCode::Internal, 500),
+        ];
+        for (code, status) in cases {
+            assert_eq!(code.status().as_u16(), status, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn net_error_kinds_map_to_codes() {
+        let cases = [
+            (NetErrorKind::InvalidAddress, ErrorCode::InvalidAddress),
+            (NetErrorKind::Dns, ErrorCode::Dns),
+            (NetErrorKind::Refused, ErrorCode::Refused),
+            (NetErrorKind::Timeout, ErrorCode::Timeout),
+            (NetErrorKind::Unreachable, ErrorCode::Unreachable),
+            (NetErrorKind::Reset, ErrorCode::Reset),
+            (NetErrorKind::QueueFull, ErrorCode::QueueFull),
+            (NetErrorKind::NotConnected, ErrorCode::NotConnected),
+            (NetErrorKind::Other, ErrorCode::Network),
+        ];
+        for (kind, code) in cases {
+            assert_eq!(ErrorCode::from(kind), code);
+            // Codes are spelled the same on the wire as the kinds they come from, except "other"
+            if kind != NetErrorKind::Other {
+                assert_eq!(serde_json::json!(code), serde_json::json!(kind));
+            }
+        }
+    }
+
+    #[test]
+    fn net_errors_keep_their_address() {
+        let e = AppError::from(NetError::new(NetErrorKind::Timeout, "Too slow.").at("10.0.0.1:80"));
+        assert!(e.is(ErrorCode::Timeout));
+        assert_eq!(e.message, "Too slow.");
+        assert_eq!(e.details, Some(serde_json::json!({ "address": "10.0.0.1:80" })));
+        assert_eq!(AppError::from(NetError::not_connected()).details, None);
+    }
+
+    #[test]
+    fn oversized_bodies_are_413() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let e = json_error_handler(JsonPayloadError::Overflow { limit: 10 }, &req);
+        assert_eq!(e.as_response_error().status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        let e = json_error_handler(JsonPayloadError::ContentType, &req);
+        assert_eq!(e.as_response_error().status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn response_body_wraps_the_error() {
+        let e = AppError::invalid("Bad name.");
+        let response = e.error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(ETAG).is_none());
+        assert_eq!(body(&e).await, serde_json::json!({
+            "error": { "code": "invalid_request", "message": "Bad name.", "details": null }
+        }));
+    }
+
+    #[actix_web::test]
+    async fn precondition_failures_carry_the_current_etag() {
+        let details = serde_json::json!({ "etag": "\"abc\"", "palette": {} });
+        let e = AppError::new(ErrorCode::PreconditionFailed, "Changed.").with_details(details.clone());
+        let response = e.error_response();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), "\"abc\"");
+        assert_eq!(body(&e).await["error"]["details"], details);
+    }
+}
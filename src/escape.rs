@@ -0,0 +1,160 @@
+// Escape sequences accepted in delimiters, and in text commands sent with "unescape",
+// so framing bytes like STX/ETX can be typed into a text box:
+//
+//   \r \n \t \0 \\ \" \'    the usual control characters and quotes
+//   \a \b \e \f \v          bell, backspace, escape, form feed, vertical tab
+//   \xNN                    one byte, two hex digits (\x02 is STX)
+//   \NNN                    one byte, up to three octal digits starting with 1-7 (\3 is ETX);
+//                           \0 is always NUL, so "\012" is NUL followed by "12"
+//   \u{N..}  \uNNNN         a Unicode character, sent as UTF-8
+//
+// Any other character after a backslash is kept as it is, backslash included.
+
+fn invalid(sequence: &str, reason: &str) -> String {
+    format!("Invalid escape sequence '{}': {}.", sequence, reason)
+}
+
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize) -> String {
+    let mut digits = String::new();
+    while digits.len() < max {
+        match chars.peek() {
+            Some(c) if c.is_digit(radix) => {
+                digits.push(*c);
+                chars.next();
+            }
+            _ => break,
+        }
+    }
+    digits
+}
+
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+pub fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push_char(&mut bytes, c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(b'\0'),
+            Some('a') => bytes.push(0x07),
+            Some('b') => bytes.push(0x08),
+            Some('e') => bytes.push(0x1b),
+            Some('f') => bytes.push(0x0c),
+            Some('v') => bytes.push(0x0b),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('\'') => bytes.push(b'\''),
+            Some('x') => {
+                let digits = take_digits(&mut chars, 16, 2);
+                if digits.len() != 2 {
+                    return Err(invalid(&format!("\\x{}", digits), "expected two hex digits"));
+                }
+                bytes.push(u8::from_str_radix(&digits, 16).unwrap_or_default());
+            }
+            Some(first @ '1'..='7') => {
+                let digits = format!("{}{}", first, take_digits(&mut chars, 8, 2));
+                let value = u32::from_str_radix(&digits, 8).unwrap_or_default();
+                let byte = u8::try_from(value).map_err(|_| invalid(&format!("\\{}", digits), "octal escapes go up to \\377"))?;
+                bytes.push(byte);
+            }
+            Some('u') => {
+                let (sequence, digits) = if chars.peek() == Some(&'{') {
+                    chars.next();
+                    let digits = take_digits(&mut chars, 16, 6);
+                    if chars.next() != Some('}') || digits.is_empty() {
+                        return Err(invalid(&format!("\\u{{{}", digits), "expected 1 to 6 hex digits in braces"));
+                    }
+                    (format!("\\u{{{}}}", digits), digits)
+                } else {
+                    let digits = take_digits(&mut chars, 16, 4);
+                    if digits.len() != 4 {
+                        return Err(invalid(&format!("\\u{}", digits), "expected four hex digits or \\u{...}"));
+                    }
+                    (format!("\\u{}", digits), digits)
+                };
+                let c = u32::from_str_radix(&digits, 16).ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| invalid(&sequence, "not a Unicode character"))?;
+                push_char(&mut bytes, c);
+            }
+            Some(other) => {
+                bytes.push(b'\\');
+                push_char(&mut bytes, other);
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_escapes() {
+        assert_eq!(unescape(r#"\r\n\t\0\\\"\'"#).unwrap(), b"\r\n\t\0\\\"'");
+        assert_eq!(unescape(r"\a\b\e\f\v").unwrap(), [0x07, 0x08, 0x1b, 0x0c, 0x0b]);
+        assert_eq!(unescape("plain é").unwrap(), "plain é".as_bytes());
+    }
+
+    #[test]
+    fn unknown_escapes_keep_the_backslash() {
+        assert_eq!(unescape(r"\q|*|").unwrap(), b"\\q|*|");
+        assert_eq!(unescape(r"\é").unwrap(), "\\é".as_bytes());
+        assert_eq!(unescape("end\\").unwrap(), b"end\\");
+    }
+
+    #[test]
+    fn hex_escapes() {
+        assert_eq!(unescape(r"\x02data\x03").unwrap(), b"\x02data\x03");
+        assert_eq!(unescape(r"\xFFf").unwrap(), [0xFF, b'f']);
+        assert_eq!(unescape(r"\x4").unwrap_err(), "Invalid escape sequence '\\x4': expected two hex digits.");
+        assert!(unescape(r"\xg0").is_err());
+    }
+
+    #[test]
+    fn nul_is_never_octal() {
+        assert_eq!(unescape(r"\012").unwrap(), b"\x0012");
+        assert_eq!(unescape(r"\00").unwrap(), b"\x000");
+        assert_eq!(unescape(r"\0\0").unwrap(), b"\0\0");
+    }
+
+    #[test]
+    fn octal_escapes() {
+        assert_eq!(unescape(r"\3").unwrap(), [3]);
+        assert_eq!(unescape(r"\12").unwrap(), [0o12]);
+        assert_eq!(unescape(r"\377").unwrap(), [0xFF]);
+        // At most three digits are read
+        assert_eq!(unescape(r"\1234").unwrap(), [0o123, b'4']);
+        assert_eq!(unescape(r"\18").unwrap(), [1, b'8']);
+        assert_eq!(unescape(r"\400").unwrap_err(), "Invalid escape sequence '\\400': octal escapes go up to \\377.");
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        assert_eq!(unescape(r"\u00e9").unwrap(), "é".as_bytes());
+        assert_eq!(unescape(r"\u{e9}").unwrap(), "é".as_bytes());
+        assert_eq!(unescape(r"\u{1F600}").unwrap(), "😀".as_bytes());
+        assert_eq!(unescape(r"\u00E9x").unwrap(), "éx".as_bytes());
+    }
+
+    #[test]
+    fn invalid_unicode_escapes() {
+        assert!(unescape(r"\u00e").is_err());
+        assert!(unescape(r"\u{}").is_err());
+        assert!(unescape(r"\u{e9").is_err());
+        assert!(unescape(r"\u{1234567}").is_err());
+        assert_eq!(unescape(r"\ud800").unwrap_err(), "Invalid escape sequence '\\ud800': not a Unicode character.");
+        assert!(unescape(r"\u{110000}").is_err());
+    }
+}
@@ -0,0 +1,510 @@
+use serde::{Deserialize, Serialize};
+
+use crate::escape::unescape;
+use crate::byte_send::to_hex;
+
+// Wraps outbound commands in a frame and finds frames in the inbound stream:
+//
+//   start marker | length prefix | payload | checksum | end marker
+//
+// Every part is optional. The length prefix counts payload bytes and the checksum
+// covers the payload only.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FramingConfig {
+    // Markers take escape sequences, e.g. "\x02" (STX) and "\x03" (ETX)
+    #[serde(default)]
+    pub start: Option<String>,
+    #[serde(default)]
+    pub end: Option<String>,
+    #[serde(default)]
+    pub length_prefix: Option<LengthPrefix>,
+    #[serde(default)]
+    pub checksum: Option<ChecksumAlgorithm>,
+    // Defaults to little-endian for CRC-16/Modbus and big-endian for everything else
+    #[serde(default)]
+    pub checksum_byte_order: Option<ByteOrder>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthPrefix {
+    U8,
+    U16be,
+    U16le,
+    U32be,
+    U32le,
+}
+
+impl LengthPrefix {
+    fn size(self) -> usize {
+        match self {
+            LengthPrefix::U8 => 1,
+            LengthPrefix::U16be | LengthPrefix::U16le => 2,
+            LengthPrefix::U32be | LengthPrefix::U32le => 4,
+        }
+    }
+
+    fn encode(self, len: usize) -> Result<Vec<u8>, String> {
+        let too_long = || format!("Invalid frame: a payload of {} bytes does not fit a {}-byte length prefix.", len, self.size());
+        Ok(match self {
+            LengthPrefix::U8 => vec![u8::try_from(len).map_err(|_| too_long())?],
+            LengthPrefix::U16be => u16::try_from(len).map_err(|_| too_long())?.to_be_bytes().to_vec(),
+            LengthPrefix::U16le => u16::try_from(len).map_err(|_| too_long())?.to_le_bytes().to_vec(),
+            LengthPrefix::U32be => u32::try_from(len).map_err(|_| too_long())?.to_be_bytes().to_vec(),
+            LengthPrefix::U32le => u32::try_from(len).map_err(|_| too_long())?.to_le_bytes().to_vec(),
+        })
+    }
+
+    fn decode(self, bytes: &[u8]) -> usize {
+        match self {
+            LengthPrefix::U8 => bytes[0] as usize,
+            LengthPrefix::U16be => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+            LengthPrefix::U16le => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            LengthPrefix::U32be => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+            LengthPrefix::U32le => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    // XOR of all bytes
+    Xor,
+    // Sum of all bytes, modulo 256
+    Sum8,
+    // CRC-16/CCITT-FALSE: poly 0x1021, init 0xFFFF, not reflected
+    Crc16Ccitt,
+    // CRC-16/MODBUS: poly 0x8005 reflected, init 0xFFFF
+    Crc16Modbus,
+    // CRC-32 as used by zip and Ethernet
+    Crc32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    Big,
+    Little,
+}
+
+impl ChecksumAlgorithm {
+    fn size(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Xor | ChecksumAlgorithm::Sum8 => 1,
+            ChecksumAlgorithm::Crc16Ccitt | ChecksumAlgorithm::Crc16Modbus => 2,
+            ChecksumAlgorithm::Crc32 => 4,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Xor => "XOR",
+            ChecksumAlgorithm::Sum8 => "sum8",
+            ChecksumAlgorithm::Crc16Ccitt => "CRC-16/CCITT",
+            ChecksumAlgorithm::Crc16Modbus => "CRC-16/Modbus",
+            ChecksumAlgorithm::Crc32 => "CRC-32",
+        }
+    }
+
+    fn default_byte_order(self) -> ByteOrder {
+        match self {
+            ChecksumAlgorithm::Crc16Modbus => ByteOrder::Little,
+            _ => ByteOrder::Big,
+        }
+    }
+
+    fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            ChecksumAlgorithm::Xor => data.iter().fold(0u8, |acc, b| acc ^ b) as u32,
+            ChecksumAlgorithm::Sum8 => data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) as u32,
+            ChecksumAlgorithm::Crc16Ccitt => {
+                let mut crc: u16 = 0xFFFF;
+                for byte in data {
+                    crc ^= (*byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+                    }
+                }
+                crc as u32
+            }
+            ChecksumAlgorithm::Crc16Modbus => {
+                let mut crc: u16 = 0xFFFF;
+                for byte in data {
+                    crc ^= *byte as u16;
+                    for _ in 0..8 {
+                        crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+                    }
+                }
+                crc as u32
+            }
+            ChecksumAlgorithm::Crc32 => {
+                let mut crc: u32 = 0xFFFF_FFFF;
+                for byte in data {
+                    crc ^= *byte as u32;
+                    for _ in 0..8 {
+                        crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                    }
+                }
+                !crc
+            }
+        }
+    }
+}
+
+// A framing configuration with its markers unescaped, ready to use on a connection
+#[derive(Debug, Clone)]
+pub struct Framing {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    length_prefix: Option<LengthPrefix>,
+    checksum: Option<(ChecksumAlgorithm, ByteOrder)>,
+}
+
+fn checksum_bytes(algorithm: ChecksumAlgorithm, order: ByteOrder, data: &[u8]) -> Vec<u8> {
+    let value = algorithm.compute(data).to_be_bytes();
+    let mut bytes = value[4 - algorithm.size()..].to_vec();
+    if order == ByteOrder::Little {
+        bytes.reverse();
+    }
+    bytes
+}
+
+impl Framing {
+    pub fn from_config(config: &FramingConfig) -> Result<Framing, String> {
+        let marker = |marker: &Option<String>| marker.as_deref().map(unescape).transpose().map(Option::unwrap_or_default);
+        let framing = Framing {
+            start: marker(&config.start)?,
+            end: marker(&config.end)?,
+            length_prefix: config.length_prefix,
+            checksum: config.checksum.map(|algorithm| (algorithm, config.checksum_byte_order.unwrap_or(algorithm.default_byte_order()))),
+        };
+        if framing.checksum.is_some() && !framing.frames_inbound() {
+            return Err("Invalid framing: a checksum needs a length prefix or an end marker to find where a frame ends.".to_string());
+        }
+        Ok(framing)
+    }
+
+    // Without a length prefix or end marker there is no way to tell where an inbound
+    // frame ends, so the stream is read as plain JSON instead
+    pub fn frames_inbound(&self) -> bool {
+        self.length_prefix.is_some() || !self.end.is_empty()
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut frame = self.start.clone();
+        if let Some(prefix) = self.length_prefix {
+            frame.extend(prefix.encode(payload.len())?);
+        }
+        frame.extend_from_slice(payload);
+        if let Some((algorithm, order)) = self.checksum {
+            frame.extend(checksum_bytes(algorithm, order, payload));
+        }
+        frame.extend_from_slice(&self.end);
+        Ok(frame)
+    }
+
+    pub fn decoder(&self) -> FrameDecoder {
+        FrameDecoder { framing: self.clone(), buffer: Vec::new(), skipped: 0 }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameEvent {
+    // The payload of a frame that passed its checks
+    Frame(Vec<u8>),
+    // A frame that failed its checks, with the raw bytes that were dropped
+    Invalid { message: String, bytes: Vec<u8> },
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Splits the inbound byte stream into frames
+pub struct FrameDecoder {
+    framing: Framing,
+    buffer: Vec<u8>,
+    // Noise dropped while looking for a start marker
+    skipped: usize,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, data: &[u8]) -> Vec<FrameEvent> {
+        self.buffer.extend_from_slice(data);
+        let mut events = Vec::new();
+        while let Some(event) = self.next_frame() {
+            events.push(event);
+        }
+        events
+    }
+
+    // Bytes held for a frame that has not completed yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Gives up on the partial frame, returning its bytes
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    // Noise skipped since the last call
+    pub fn take_skipped(&mut self) -> usize {
+        std::mem::take(&mut self.skipped)
+    }
+
+    fn invalid(&mut self, message: String, len: usize) -> Option<FrameEvent> {
+        let bytes = self.buffer.drain(..len).collect();
+        Some(FrameEvent::Invalid { message, bytes })
+    }
+
+    fn next_frame(&mut self) -> Option<FrameEvent> {
+        let framing = &self.framing;
+        let start = framing.start.len();
+        if start > 0 {
+            // Bytes before the start marker are noise; keep a partial marker at the end
+            match find(&self.buffer, &framing.start) {
+                Some(0) => {}
+                Some(offset) => {
+                    self.buffer.drain(..offset);
+                    self.skipped += offset;
+                }
+                None => {
+                    let keep = self.buffer.len().min(start - 1);
+                    self.skipped += self.buffer.len() - keep;
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    return None;
+                }
+            }
+        } else if self.buffer.is_empty() {
+            return None;
+        }
+
+        let checksum_size = framing.checksum.map_or(0, |(algorithm, _)| algorithm.size());
+        let (payload_range, frame_len) = match framing.length_prefix {
+            Some(prefix) => {
+                let header = start + prefix.size();
+                if self.buffer.len() < header {
+                    return None;
+                }
+                let payload_len = prefix.decode(&self.buffer[start..header]);
+                let frame_len = header + payload_len + checksum_size + framing.end.len();
+                if self.buffer.len() < frame_len {
+                    return None;
+                }
+                if self.buffer[frame_len - framing.end.len()..frame_len] != framing.end[..] {
+                    // Probably not a real frame start; skip one byte and look again
+                    let message = "Frame does not end with the end marker where its length says it should.".to_string();
+                    return self.invalid(message, 1);
+                }
+                (header..header + payload_len, frame_len)
+            }
+            None => {
+                let body_len = find(&self.buffer[start..], &framing.end)?;
+                let frame_len = start + body_len + framing.end.len();
+                if body_len < checksum_size {
+                    return self.invalid("Frame is too short to hold its checksum.".to_string(), frame_len);
+                }
+                (start..start + body_len - checksum_size, frame_len)
+            }
+        };
+
+        if let Some((algorithm, order)) = framing.checksum {
+            let expected = checksum_bytes(algorithm, order, &self.buffer[payload_range.clone()]);
+            let actual = &self.buffer[payload_range.end..payload_range.end + checksum_size];
+            if expected != actual {
+                let message = format!(
+                    "{} checksum mismatch: frame has {}, payload gives {}.",
+                    algorithm.label(),
+                    to_hex(actual),
+                    to_hex(&expected)
+                );
+                return self.invalid(message, frame_len);
+            }
+        }
+        let payload = self.buffer[payload_range].to_vec();
+        self.buffer.drain(..frame_len);
+        Some(FrameEvent::Frame(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    fn framing(start: &str, end: &str, length_prefix: Option<LengthPrefix>, checksum: Option<ChecksumAlgorithm>) -> Framing {
+        let config = FramingConfig {
+            start: Some(start.to_string()),
+            end: Some(end.to_string()),
+            length_prefix,
+            checksum,
+            checksum_byte_order: None,
+        };
+        Framing::from_config(&config).unwrap()
+    }
+
+    fn frames(events: Vec<FrameEvent>) -> Vec<Vec<u8>> {
+        events.into_iter().map(|event| match event {
+            FrameEvent::Frame(payload) => payload,
+            FrameEvent::Invalid { message, .. } => panic!("unexpected invalid frame: {}", message),
+        }).collect()
+    }
+
+    fn framing_without_markers() -> Framing {
+        Framing::from_config(&FramingConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn checksum_check_values() {
+        assert_eq!(ChecksumAlgorithm::Xor.compute(CHECK), 0x31);
+        assert_eq!(ChecksumAlgorithm::Sum8.compute(CHECK), 0xDD);
+        assert_eq!(ChecksumAlgorithm::Crc16Ccitt.compute(CHECK), 0x29B1);
+        assert_eq!(ChecksumAlgorithm::Crc16Modbus.compute(CHECK), 0x4B37);
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(CHECK), 0xCBF4_3926);
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(b""), 0);
+    }
+
+    #[test]
+    fn checksum_byte_order() {
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::Crc16Ccitt, ByteOrder::Big, CHECK), [0x29, 0xB1]);
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::Crc16Ccitt, ByteOrder::Little, CHECK), [0xB1, 0x29]);
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::Crc32, ByteOrder::Big, CHECK), [0xCB, 0xF4, 0x39, 0x26]);
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::Crc32, ByteOrder::Little, CHECK), [0x26, 0x39, 0xF4, 0xCB]);
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::Xor, ByteOrder::Little, CHECK), [0x31]);
+        // Modbus sends the low byte first unless told otherwise
+        let modbus = framing("", "\\r", None, Some(ChecksumAlgorithm::Crc16Modbus));
+        assert_eq!(modbus.encode(CHECK).unwrap(), [CHECK, &[0x37, 0x4B, b'\r']].concat());
+    }
+
+    #[test]
+    fn encodes_every_part_in_order() {
+        let framing = framing("\\x02", "\\x03", Some(LengthPrefix::U16be), Some(ChecksumAlgorithm::Sum8));
+        assert_eq!(framing.encode(b"AB").unwrap(), [0x02, 0x00, 0x02, b'A', b'B', 0x83, 0x03]);
+        let plain = framing_without_markers();
+        assert_eq!(plain.encode(b"AB").unwrap(), b"AB");
+        assert!(!plain.frames_inbound());
+    }
+
+    #[test]
+    fn payloads_must_fit_the_length_prefix() {
+        let framing = framing("", "", Some(LengthPrefix::U8), None);
+        assert!(framing.encode(&[0; 255]).is_ok());
+        assert_eq!(
+            framing.encode(&[0; 256]).unwrap_err(),
+            "Invalid frame: a payload of 256 bytes does not fit a 1-byte length prefix."
+        );
+    }
+
+    #[test]
+    fn checksums_need_a_way_to_find_the_frame_end() {
+        let config = FramingConfig { start: Some("\\x02".to_string()), checksum: Some(ChecksumAlgorithm::Xor), ..Default::default() };
+        assert!(Framing::from_config(&config).unwrap_err().starts_with("Invalid framing:"));
+        let config = FramingConfig { start: Some("\\xZZ".to_string()), ..Default::default() };
+        assert!(Framing::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn round_trips_every_length_prefix_and_byte_order() {
+        let prefixes = [LengthPrefix::U8, LengthPrefix::U16be, LengthPrefix::U16le, LengthPrefix::U32be, LengthPrefix::U32le];
+        let algorithms = [ChecksumAlgorithm::Xor, ChecksumAlgorithm::Sum8, ChecksumAlgorithm::Crc16Ccitt, ChecksumAlgorithm::Crc16Modbus, ChecksumAlgorithm::Crc32];
+        for prefix in prefixes {
+            for algorithm in algorithms {
+                for order in [ByteOrder::Big, ByteOrder::Little] {
+                    let config = FramingConfig {
+                        start: Some("\\x02".to_string()),
+                        length_prefix: Some(prefix),
+                        checksum: Some(algorithm),
+                        checksum_byte_order: Some(order),
+                        ..Default::default()
+                    };
+                    let framing = Framing::from_config(&config).unwrap();
+                    let frame = framing.encode(CHECK).unwrap();
+                    assert_eq!(frame.len(), 1 + prefix.size() + CHECK.len() + algorithm.size());
+                    assert_eq!(prefix.decode(&frame[1..]), CHECK.len());
+                    let mut decoder = framing.decoder();
+                    assert_eq!(frames(decoder.push(&frame)), vec![CHECK.to_vec()], "{:?} {:?} {:?}", prefix, algorithm, order);
+                    assert_eq!(decoder.buffered(), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn length_prefix_byte_orders() {
+        assert_eq!(LengthPrefix::U16be.encode(0x0102).unwrap(), [0x01, 0x02]);
+        assert_eq!(LengthPrefix::U16le.encode(0x0102).unwrap(), [0x02, 0x01]);
+        assert_eq!(LengthPrefix::U32be.encode(0x0102_0304).unwrap(), [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(LengthPrefix::U32le.encode(0x0102_0304).unwrap(), [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(LengthPrefix::U16le.decode(&[0x02, 0x01]), 0x0102);
+        assert_eq!(LengthPrefix::U32le.decode(&[0x04, 0x03, 0x02, 0x01]), 0x0102_0304);
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let framing = framing("\\x02", "\\x03", Some(LengthPrefix::U16le), Some(ChecksumAlgorithm::Crc16Ccitt));
+        let frame = framing.encode(b"hello").unwrap();
+        let mut decoder = framing.decoder();
+        for byte in &frame[..frame.len() - 1] {
+            assert!(decoder.push(&[*byte]).is_empty());
+        }
+        assert_eq!(decoder.buffered(), frame.len() - 1);
+        assert_eq!(frames(decoder.push(&frame[frame.len() - 1..])), vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn concatenated_frames_and_noise() {
+        let framing = framing("<<", ">", None, Some(ChecksumAlgorithm::Xor));
+        let mut stream = b"noise>".to_vec();
+        stream.extend(framing.encode(b"one").unwrap());
+        stream.extend(framing.encode(b"two").unwrap());
+        let mut decoder = framing.decoder();
+        assert_eq!(frames(decoder.push(&stream)), vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(decoder.take_skipped(), 6);
+        assert_eq!(decoder.take_skipped(), 0);
+
+        // Half a start marker is kept until the rest arrives
+        assert!(decoder.push(b"xx<").is_empty());
+        assert_eq!(decoder.buffered(), 1);
+        assert_eq!(frames(decoder.push(&framing.encode(b"three").unwrap()[1..])), vec![b"three".to_vec()]);
+    }
+
+    #[test]
+    fn bad_checksums_are_reported_and_skipped() {
+        let framing = framing("\\x02", "\\x03", None, Some(ChecksumAlgorithm::Sum8));
+        let mut bad = framing.encode(b"AB").unwrap();
+        bad[3] ^= 0xFF;
+        let mut stream = bad.clone();
+        stream.extend(framing.encode(b"CD").unwrap());
+        let events = framing.decoder().push(&stream);
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            FrameEvent::Invalid { message, bytes } => {
+                assert_eq!(message, "sum8 checksum mismatch: frame has 7c, payload gives 83.");
+                assert_eq!(bytes, &bad);
+            }
+            other => panic!("expected an invalid frame, got {:?}", other),
+        }
+        assert!(matches!(&events[1], FrameEvent::Frame(payload) if payload == b"CD"));
+    }
+
+    #[test]
+    fn frames_too_short_for_their_checksum() {
+        let framing = framing("", "\\n", None, Some(ChecksumAlgorithm::Crc16Ccitt));
+        let events = framing.decoder().push(b"x\nok");
+        assert!(matches!(&events[..], [FrameEvent::Invalid { message, bytes }] if message == "Frame is too short to hold its checksum." && bytes == b"x\n"));
+    }
+
+    #[test]
+    fn a_misplaced_end_marker_skips_one_byte() {
+        let framing = framing("\\x02", "\\x03", Some(LengthPrefix::U8), None);
+        let mut stream = vec![0x02, 0x04, b'A', 0x03];
+        stream.extend(framing.encode(b"B").unwrap());
+        stream.extend([0; 4]);
+        let events = framing.decoder().push(&stream);
+        assert!(matches!(&events[0], FrameEvent::Invalid { bytes, .. } if bytes == &[0x02]));
+        assert!(matches!(&events[1], FrameEvent::Frame(payload) if payload == b"B"));
+    }
+}
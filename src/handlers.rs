@@ -1,280 +1,235 @@
-use actix_web::{post, get, delete, web, HttpRequest, HttpResponse, Responder, put};
-use serde_json::{Value as JsonValue, Deserializer};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, AsyncReadExt, BufReader};
+use actix_web::{post, get, delete, patch, web, HttpRequest, HttpResponse, Responder, put};
+use serde_json::Value as JsonValue;
+use indexmap::IndexMap;
 use rust_embed::RustEmbed;
-use mime_guess;
-use indexmap;
 
-use crate::types::{CommandPayload, ConnectPayload, TextCommandPayload, PalettePayload, Palette, AddCommandPayload};
+use crate::types::{CommandPayload, ConnectPayload, TextCommandPayload, BytesPayload, PalettePayload, Palette, AddCommandPayload, PaletteChange, DiffQuery, NewPaletteNamePayload, CreateCategoryPayload, RenameCategoryPayload, CategoryOrderPayload, MoveCommandPayload, DeleteCommandQuery, ValidationIssue, ImportQuery, ImportResult, ImportStatus, ConflictPolicy, ExportQuery, RestoreQuery, SearchQuery, Annotation, PaletteFilterQuery, PaletteConfirmPayload, PaletteSendPayload};
 use crate::state::AppState;
-use crate::palette_manager::{save_palette, load_palette, list_palettes as list_palettes_fs, delete_palette as delete_palette_fs, import_palette as import_palette_fs};
+use crate::palette_name::PaletteName;
+use actix_web::http::header::{ETAG, IF_MATCH};
+use actix_web::HttpResponseBuilder;
+use crate::palette_manager::{list_backups, palette_dir, palette_etag};
+use crate::config::app_config;
+use crate::palette_repository::PaletteRepository;
+use crate::palette_edit;
+use crate::palette_validation::{validate_palette, validate_palette_json, has_errors};
+use crate::palette_import::{parse_palette_file, read_archive};
+use crate::backup_archive::{create_full_backup, restore_full_backup};
+use crate::search::search_palettes;
+use crate::send_guard::{command_digest, CommandRef, SendRecord, SendStatus, CONFIRMATION_TTL};
+use crate::transport::{encode_command, TransportSettings};
+use crate::escape::unescape;
+use crate::byte_send::{decode_payload, send_bytes, to_hex};
+use crate::framing::Framing;
+use crate::payload_encoding::PayloadEncoding;
+use crate::jsonrpc::JsonRpcSession;
+use crate::reader_limits::ReaderStats;
+use crate::connection::{Connection, ConnectionSettings, WRITE_QUEUE_LEN};
+use crate::net_error::NetError;
+use crate::error::{AppError, ErrorCode};
+use std::sync::Arc;
+use crate::palette_history::{list_revisions, load_revision, latest_revision_number, diff_palettes};
 
 // Needed for file uploads
 use actix_multipart::Multipart;
 use futures_util::TryStreamExt as _;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use uuid::Uuid; // For generating unique temp file names
 
 #[post("/connect")]
 pub async fn connect_route(
     payload: web::Json<ConnectPayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let mut tcp_stream_guard = app_state.tcp_stream.lock().unwrap();
-    let mut reader_handle_guard = app_state.tcp_reader_handle.lock().unwrap();
-
-    if tcp_stream_guard.is_some() {
-        if let Some(stream) = tcp_stream_guard.take() {
-            drop(stream);
-        }
-        if let Some(handle) = reader_handle_guard.take() {
-            handle.abort();
-        }
+) -> Result<HttpResponse, AppError> {
+    if let Some(connection) = app_state.connection.lock().unwrap().take() {
+        connection.close();
         println!("Closed existing TCP connection before establishing a new one.");
     }
 
     let socket_path = &payload.socket_path;
+    let framing = payload.framing.as_ref().map(Framing::from_config).transpose().map_err(AppError::invalid)?;
+    payload.limits.validate()
+        .and_then(|_| payload.timeouts.validate())
+        .and_then(|_| payload.jsonrpc.as_ref().map_or(Ok(()), |config| config.validate()))
+        .map_err(AppError::invalid)?;
+    let stats = Arc::new(ReaderStats::default());
+    *app_state.reader_stats.lock().unwrap() = stats.clone();
+    let settings = ConnectionSettings {
+        framing,
+        encoding: payload.encoding,
+        jsonrpc: payload.jsonrpc.as_ref().map(|config| Arc::new(JsonRpcSession::new(config))),
+        limits: payload.limits,
+        timeouts: payload.timeouts,
+        stats,
+    };
     println!("Attempting to connect to TCP socket: {}", socket_path);
 
-    match TcpStream::connect(socket_path).await {
-        Ok(stream) => {
+    match Connection::open(socket_path, settings, app_state.tcp_message_tx.clone()).await {
+        Ok(connection) => {
             println!("Successfully connected to TCP socket: {}", socket_path);
-            let broadcast_tx_clone = app_state.tcp_message_tx.clone();
-
-            let (tcp_reader_stream, tcp_writer_stream) = stream.into_split();
-            *tcp_stream_guard = Some(tcp_writer_stream);
-
-            let reader_task = tokio::spawn(async move {
-                let mut buf_reader = BufReader::new(tcp_reader_stream);
-                let mut data_buffer = Vec::new();
-                let mut current_read_offset = 0; // Offset for the start of data to process in data_buffer
-
-                loop { // Outer loop: Read more data from socket, then process data_buffer
-
-                    loop { // Inner loop: Process available data
-                        // Pre-skip any leading null bytes from the current_read_offset
-                        while current_read_offset < data_buffer.len() && data_buffer[current_read_offset] == 0u8 {
-                            current_read_offset += 1;
-                        }
-
-                        if current_read_offset >= data_buffer.len() {
-                            break; 
-                        }
-
-                        // Create a deserializer for the current segment of the buffer.
-                        let mut stream_deserializer = Deserializer::from_slice(&data_buffer[current_read_offset..]).into_iter::<JsonValue>();
-
-                        match stream_deserializer.next() {
-                            Some(Ok(json_value)) => {
-                                let message_to_send = match serde_json::to_string(&json_value) {
-                                    Ok(s) => s,
-                                    Err(e) => {
-                                        println!("Error re-serializing JSON from device: {}", e);
-                                        format!("{{\"error\": \"Failed to re-serialize device response: {}\"}}", e)
-                                    }
-                                };
-                                println!("TCP In (Streamed JSON): {}", message_to_send);
-
-                                if let Err(e) = broadcast_tx_clone.send(message_to_send.clone()) {
-                                    println!("Failed to broadcast TCP message: {}. Msg: {}", e, message_to_send);
-                                    // If broadcast fails, we might want to stop, but for now, continue processing.
-                                }
-                                // Advance current_read_offset by the number of bytes consumed for this JSON object.
-                                current_read_offset += stream_deserializer.byte_offset();
-                            }
-                            Some(Err(ref e)) if e.is_eof() => {
-                                // EOF in the current slice means an incomplete JSON object.
-                                // We need to read more data from the socket.
-                                // current_read_offset is not advanced here, as the data from this point is partial.
-                                // The buffer compaction logic later will preserve this partial data.
-                                break; // Break inner loop to read more data.
-                            }
-                            Some(Err(e)) => {
-                                // A syntax error or other non-EOF error occurred.
-                                let error_offset_in_slice = stream_deserializer.byte_offset();
-                                println!(
-                                    "TCP stream: Encountered non-JSON data or syntax error: '{}'. Occurred at offset {} within the current data segment being parsed. Attempting to skip.",
-                                    e,
-                                    error_offset_in_slice
-                                );
-
-                                // We must advance past the problematic data to avoid an infinite loop.
-                                // Advance by the offset where the error occurred in the slice + 1 to skip the char causing it.
-                                current_read_offset += error_offset_in_slice + 1;
-                            }
-                            None => {
-                                // The deserializer's iterator is exhausted for the current slice.
-                                // This means the slice was empty or contained only data that serde_json
-                                // considers "trailing" after any valid JSON (e.g., whitespace it skipped).
-                                // Advance current_read_offset by the number of bytes consumed from the slice.
-                                current_read_offset += stream_deserializer.byte_offset();
-                                break; // Break inner loop, as this slice is fully processed or no more JSON can be formed from it.
-                            }
-                        }
-                    } // End inner processing loop
-
-                    // Buffer compaction: Remove processed data from the beginning of data_buffer.
-                    if current_read_offset > 0 {
-                        if current_read_offset >= data_buffer.len() {
-                            data_buffer.clear(); // All data processed
-                        } else {
-                            data_buffer.drain(..current_read_offset); // Remove processed prefix
-                        }
-                        current_read_offset = 0; // Reset offset as we've modified the buffer's beginning
-                    }
-
-                    // Read more data from the socket.
-                    let mut temp_read_buf = [0u8; 4096];
-                    match buf_reader.read(&mut temp_read_buf).await {
-                        Ok(0) => {
-                            println!("TCP connection closed by peer (EOF).");
-                            if !data_buffer.is_empty() {
-                                println!(
-                                    "Warning: {} bytes remaining in buffer on EOF were not processed as complete JSON: {:?}",
-                                    data_buffer.len(),
-                                    String::from_utf8_lossy(&data_buffer)
-                                );
-                            }
-                            break; // Break outer loop, connection closed.
-                        }
-                        Ok(n) => {
-                            data_buffer.extend_from_slice(&temp_read_buf[..n]);
-                            // Loop back to inner processing loop with new data.
-                        }
-                        Err(e) => {
-                            println!("TCP read error: {}", e);
-                            // Don't broadcast this error either, just terminate the reader for socket errors.
-                            break; // Break outer loop on read error.
-                        }
-                    }
-                } // End outer loop (socket read loop)
-
-                println!("TCP reader task finished.");
-                let close_msg = "TCP_CONNECTION_CLOSED_OR_STREAM_ENDED".to_string();
-                if let Err(e) = broadcast_tx_clone.send(close_msg.clone()) {
-                    println!("Failed to broadcast TCP close/end message: {}. Msg: {}", e, close_msg);
-                }
-            });
-
-            *reader_handle_guard = Some(reader_task);
-            HttpResponse::Ok().body(format!("Connected to {}", socket_path))
+            // Another /connect may have finished while this one was waiting
+            if let Some(previous) = app_state.connection.lock().unwrap().replace(Arc::new(connection)) {
+                previous.close();
+            }
+            Ok(HttpResponse::Ok().body(format!("Connected to {}", socket_path)))
         }
         Err(e) => {
             println!("TCP connection error to {}: {}", socket_path, e);
-            HttpResponse::InternalServerError().body(format!("TCP connection error: {}", e))
+            Err(e.into())
         }
     }
 }
 
-#[post("/disconnect")]
-pub async fn disconnect_route(app_state: web::Data<AppState>) -> impl Responder {
-    println!("Received request to disconnect TCP.");
-    let mut tcp_stream_guard = app_state.tcp_stream.lock().unwrap();
-    let mut reader_handle_guard = app_state.tcp_reader_handle.lock().unwrap();
+// The open connection, or a not_connected error
+fn require_connection(app_state: &AppState) -> Result<Arc<Connection>, AppError> {
+    app_state.current_connection().ok_or_else(|| NetError::not_connected().into())
+}
+
+// On JSON-RPC connections, wraps a {"method", "params"} command as a request with an id
+fn jsonrpc_request(connection: &Connection, command: &JsonValue) -> Result<JsonValue, AppError> {
+    match &connection.jsonrpc {
+        Some(rpc) => rpc.request(command).map_err(AppError::invalid),
+        None => Ok(command.clone()),
+    }
+}
 
-    if let Some(stream) = tcp_stream_guard.take() {
-        drop(stream);
-        println!("TCP stream dropped.");
+// Writes through the connection's queue, tracking JSON-RPC requests while they wait
+// for a response
+async fn write_command(connection: &Connection, command: &JsonValue, bytes: Vec<u8>) -> Result<(), NetError> {
+    let rpc_id = connection.jsonrpc.as_ref().and_then(|rpc| rpc.track(command));
+    let result = connection.write(bytes).await;
+    if let (Some(rpc), Some(id), Err(_)) = (&connection.jsonrpc, rpc_id, &result) {
+        rpc.cancel(id);
     }
+    result
+}
 
-    if let Some(handle) = reader_handle_guard.take() {
-        handle.abort();
-        println!("TCP reader task aborted.");
+#[post("/disconnect")]
+pub async fn disconnect_route(app_state: web::Data<AppState>) -> impl Responder {
+    println!("Received request to disconnect TCP.");
+    if let Some(connection) = app_state.connection.lock().unwrap().take() {
+        connection.close();
+        println!("TCP reader and writer tasks stopped.");
     }
 
     HttpResponse::Ok().body("Disconnected")
 }
 
+// The current connection and how many writes are queued for it, so clients can see
+// back-pressure building before sends start failing with 503
+#[get("/connection")]
+pub async fn connection_status_route(app_state: web::Data<AppState>) -> impl Responder {
+    let status = match app_state.current_connection() {
+        Some(connection) => serde_json::json!({
+            "connected": true,
+            "address": connection.address,
+            "encoding": connection.encoding,
+            "framing": connection.framing.is_some(),
+            "jsonrpc": connection.jsonrpc.is_some(),
+            "queued_writes": connection.queued_writes(),
+            "write_queue_len": WRITE_QUEUE_LEN,
+        }),
+        None => serde_json::json!({ "connected": false }),
+    };
+    HttpResponse::Ok().json(status)
+}
+
+// Reader counters for the current (or last) connection: bytes received and dropped,
+// and how often a partial message was flushed for being too big or going idle
+#[get("/reader-stats")]
+pub async fn reader_stats_route(app_state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(app_state.reader_stats.lock().unwrap().snapshot())
+}
+
 #[post("/send-command")]
 pub async fn send_command(
     cmd_payload: web::Json<CommandPayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let mut tcp_stream_guard = app_state.tcp_stream.lock().unwrap();
-
-    if let Some(stream) = tcp_stream_guard.as_mut() {
-        let mut command_bytes = match serde_json::to_vec(&cmd_payload.json_command) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to serialize command: {}", e));
-            }
-        };
-
-        // Append delimiter if provided
-        if let Some(delimiter_str) = &cmd_payload.delimiter {
-            if !delimiter_str.is_empty() {
-                let unescaped_delimiter_bytes = unescape_string_to_bytes(delimiter_str);
-                command_bytes.extend_from_slice(&unescaped_delimiter_bytes);
-                println!(
-                    "Appending custom delimiter: input=\"{}\", bytes={:?}",
-                    delimiter_str,
-                    unescaped_delimiter_bytes
-                );
-            }
+) -> Result<HttpResponse, AppError> {
+    let connection = require_connection(&app_state)?;
+    let json_command = jsonrpc_request(&connection, &cmd_payload.json_command)?;
+    let mut command_bytes = connection.encoding.encode(&json_command).map_err(AppError::internal)?;
+
+    // Append delimiter if provided
+    if let Some(delimiter_str) = &cmd_payload.delimiter {
+        if !delimiter_str.is_empty() {
+            let unescaped_delimiter_bytes = unescape(delimiter_str).map_err(AppError::invalid)?;
+            command_bytes.extend_from_slice(&unescaped_delimiter_bytes);
+            println!(
+                "Appending custom delimiter: input=\"{}\", bytes={:?}",
+                delimiter_str,
+                unescaped_delimiter_bytes
+            );
         }
+    }
 
-        // Log the exact bytes being sent
-        println!("Attempting to send to TCP ({} bytes): {:?}", command_bytes.len(), command_bytes);
-        println!("Attempting to send to TCP (as string lossy): {}", String::from_utf8_lossy(&command_bytes));
+    let command_bytes = connection.frame(command_bytes).map_err(AppError::invalid)?;
 
-        if let Err(e) = stream.write_all(&command_bytes).await {
-            return HttpResponse::InternalServerError().body(format!("TCP write error: {}", e));
-        }
-        // if let Err(e) = stream.write_all(b"\n").await {
-        //     return HttpResponse::InternalServerError().body(format!("TCP write newline error: {}", e));
-        // }
-        HttpResponse::Ok().body("TCP command sent")
-    } else {
-        HttpResponse::InternalServerError().body("Not connected to any TCP socket.")
+    // Log the exact bytes being sent
+    println!("Attempting to send to TCP ({} bytes): {:?}", command_bytes.len(), command_bytes);
+    println!("Attempting to send to TCP (as string lossy): {}", String::from_utf8_lossy(&command_bytes));
+
+    write_command(&connection, &json_command, command_bytes).await?;
+    if connection.jsonrpc.is_some() {
+        return Ok(HttpResponse::Ok().body(format!("TCP command sent (JSON-RPC id {})", json_command["id"])));
     }
+    // if let Err(e) = stream.write_all(b"\n").await {
+    //     return HttpResponse::InternalServerError().body(format!("TCP write newline error: {}", e));
+    // }
+    Ok(HttpResponse::Ok().body("TCP command sent"))
 }
 
 #[post("/send-text-command")]
 pub async fn send_text_command_route(
     payload: web::Json<TextCommandPayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let mut tcp_stream_guard = app_state.tcp_stream.lock().unwrap();
-
-    if let Some(stream) = tcp_stream_guard.as_mut() {
-        let command_to_send = payload.text_command.clone();
-        let mut command_bytes = payload.text_command.as_bytes().to_vec();
-
-        // Append delimiter if provided
-        if let Some(delimiter_str) = &payload.delimiter {
-            if !delimiter_str.is_empty() {
-                // For now, treat delimiter_str as a literal sequence of characters.
-                // We might need to add unescaping logic later (e.g., "\r" -> actual CR byte)
-                command_bytes.extend_from_slice(delimiter_str.as_bytes());
-                println!("Appending custom delimiter: \"{}\"", delimiter_str);
-            } else {
-                // If delimiter is present but an empty string, append nothing.
-                println!("Custom delimiter is empty, sending command as-is.");
-            }
+) -> Result<HttpResponse, AppError> {
+    let connection = require_connection(&app_state)?;
+    let command_to_send = payload.text_command.clone();
+    // The delimiter always takes escapes, the text only when asked to
+    let mut command_bytes = if payload.unescape {
+        unescape(&payload.text_command).map_err(AppError::invalid)?
+    } else {
+        payload.text_command.clone().into_bytes()
+    };
+
+    // Append delimiter if provided
+    if let Some(delimiter_str) = &payload.delimiter {
+        if !delimiter_str.is_empty() {
+            command_bytes.extend_from_slice(&unescape(delimiter_str).map_err(AppError::invalid)?);
+            println!("Appending custom delimiter: \"{}\"", delimiter_str);
         } else {
-            // If delimiter is None (not provided in payload), append nothing by default.
-            // Previous behavior was to always append '\r'.
-            println!("No custom delimiter provided, sending command as-is.");
+            // If delimiter is present but an empty string, append nothing.
+            println!("Custom delimiter is empty, sending command as-is.");
         }
+    } else {
+        // If delimiter is None (not provided in payload), append nothing by default.
+        // Previous behavior was to always append '\r'.
+        println!("No custom delimiter provided, sending command as-is.");
+    }
 
-        println!("Sending raw text command: {}", command_to_send);
+    let command_bytes = connection.frame(command_bytes).map_err(AppError::invalid)?;
 
-        if let Err(e) = stream.write_all(&command_bytes).await {
-            println!("TCP write error (text command): {}", e);
-            return HttpResponse::InternalServerError().body(format!("TCP write error (text command): {}", e));
-        }
-        // Also broadcast this raw command as sent?
-        // For now, the response to the HTTP request is enough.
-        // The client-side will add it to its local message log.
+    println!("Sending raw text command: {}", command_to_send);
 
-        HttpResponse::Ok().body(format!("Text command sent: {}", command_to_send))
-    } else {
-        HttpResponse::InternalServerError().body("Not connected to any TCP socket.")
+    if let Err(e) = connection.write(command_bytes).await {
+        println!("TCP write error (text command): {}", e);
+        return Err(e.into());
     }
+    // Also broadcast this raw command as sent?
+    // For now, the response to the HTTP request is enough.
+    // The client-side will add it to its local message log.
+
+    Ok(HttpResponse::Ok().body(format!("Text command sent: {}", command_to_send)))
+}
+
+// Writes exactly the given bytes (hex or base64), e.g. a wake-up sequence or a binary header
+#[post("/send-bytes")]
+pub async fn send_bytes_route(
+    payload: web::Json<BytesPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let bytes = decode_payload(&payload).map_err(AppError::invalid)?;
+    send_bytes(&app_state, &bytes).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "bytes": bytes.len(), "hex": to_hex(&bytes) })))
 }
 
 #[get("/api/version")]
@@ -295,7 +250,7 @@ pub async fn health_check() -> impl Responder {
 #[folder = "static/"]
 struct Asset;
 
-pub async fn embedded_file_handler(req: HttpRequest) -> impl Responder {
+pub async fn embedded_file_handler(req: HttpRequest) -> Result<HttpResponse, AppError> {
     let path = req.path().trim_start_matches('/');
     let file = if path.is_empty() { "index.html" } else { path };
 
@@ -304,344 +259,822 @@ pub async fn embedded_file_handler(req: HttpRequest) -> impl Responder {
             let body = actix_web::body::BoxBody::new(content.data.into_owned());
             let mime = mime_guess::from_path(file).first_or_octet_stream();
             //println!("Serving file: {} with MIME type: {}", file, mime);
-            HttpResponse::Ok()
+            Ok(HttpResponse::Ok()
                 .content_type(mime.as_ref())
-                .body(body)
+                .body(body))
         }
-        None => HttpResponse::NotFound().body("404 Not Found"),
+        None => Err(AppError::not_found(format!("'{}' not found.", req.path()))),
     }
 }
 
 // --- Palette Handlers ---
 
+// Validates a palette name taken from a URL path segment
+fn palette_name_from_path(raw: String) -> Result<PaletteName, AppError> {
+    PaletteName::parse(&raw).map_err(AppError::invalid)
+}
+
+fn if_match_header(req: &HttpRequest) -> Option<String> {
+    req.headers().get(IF_MATCH).and_then(|v| v.to_str().ok()).map(|v| v.to_string())
+}
+
+// Palette JSON response carrying the palette's ETag
+fn palette_response(mut builder: HttpResponseBuilder, palette: &Palette) -> HttpResponse {
+    builder.insert_header((ETAG, palette_etag(palette))).json(palette)
+}
+
+// Response for a palette that was just created, replaced or imported: the palette plus
+// a `warnings` array with whatever validation found that did not block saving
+fn saved_palette_response(mut builder: HttpResponseBuilder, palette: &Palette) -> HttpResponse {
+    let mut body = serde_json::to_value(palette).unwrap_or_default();
+    body["warnings"] = serde_json::json!(validate_palette(palette));
+    builder.insert_header((ETAG, palette_etag(palette))).json(body)
+}
+
+// Optional headers describing a palette change for the history
+const CHANGE_AUTHOR_HEADER: &str = "X-Commander-Author";
+const CHANGE_MESSAGE_HEADER: &str = "X-Commander-Message";
+
+fn palette_change_from_request(req: &HttpRequest, default_message: String) -> PaletteChange {
+    let header_value = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    PaletteChange {
+        author: header_value(CHANGE_AUTHOR_HEADER),
+        message: Some(header_value(CHANGE_MESSAGE_HEADER).unwrap_or(default_message)),
+    }
+}
+
 #[get("/api/palettes")]
-pub async fn list_palettes_handler() -> impl Responder {
-    match list_palettes_fs() {
-        Ok(palettes) => HttpResponse::Ok().json(palettes),
-        Err(e) => HttpResponse::InternalServerError().body(e),
+pub async fn list_palettes_handler(app_state: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(app_state.palettes.list()?))
+}
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+
+// Searches palette, category and command names and the keys and values of every command.
+// All whitespace-separated terms must match; hits come back ranked, best first.
+#[get("/api/search")]
+pub async fn search_handler(query: web::Query<SearchQuery>, app_state: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    if query.q.trim().is_empty() {
+        return Err(AppError::invalid("Query parameter 'q' cannot be empty."));
     }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    Ok(HttpResponse::Ok().json(search_palettes(&app_state.palettes, &query.q, limit)?))
+}
+
+// Lists the configured palette roots. Palettes in a root other than the default one
+// are addressed as "<root>:<name>".
+#[get("/api/palette-roots")]
+pub async fn list_palette_roots_handler() -> impl Responder {
+    let config = app_config();
+    let roots: Vec<_> = config.palette_roots.iter().map(|root| serde_json::json!({
+        "name": root.name,
+        "path": root.path,
+        "read_only": root.read_only,
+        "default": config.root_qualifier(root).is_none(),
+    })).collect();
+    HttpResponse::Ok().json(roots)
 }
 
 #[post("/api/palettes")]
 pub async fn create_palette(
+    req: HttpRequest,
     palette_payload: web::Json<PalettePayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let new_palette = Palette {
+) -> Result<HttpResponse, AppError> {
+    let mut new_palette = Palette {
         name: palette_payload.name.clone(),
         commands: palette_payload.commands.clone(),
+        meta: palette_payload.meta.clone().unwrap_or_default(),
     };
+    palette_edit::prune_annotations(&mut new_palette);
+    let change = palette_change_from_request(&req, "Created palette".to_string());
 
-    match save_palette(&new_palette) {
-        Ok(_) => {
-            app_state.palettes.lock().unwrap().insert(new_palette.name.clone(), new_palette.clone());
-            HttpResponse::Ok().json(new_palette)
-        }
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to save palette: {}", e)),
+    let issues = validate_palette(&new_palette);
+    if has_errors(&issues) {
+        return Err(invalid_palette(issues));
     }
+    app_state.palettes.save(&new_palette, &change)?;
+    Ok(saved_palette_response(HttpResponse::Ok(), &new_palette))
+}
+
+// Checks a palette document without saving it. Always 200; `valid` is false if there are errors.
+#[post("/api/palettes/validate")]
+pub async fn validate_palette_handler(payload: web::Json<JsonValue>) -> impl Responder {
+    let issues = validate_palette_json(&payload);
+    HttpResponse::Ok().json(serde_json::json!({
+        "valid": !has_errors(&issues),
+        "issues": issues,
+    }))
+}
+
+// 422 error listing everything wrong with a palette in details.issues
+fn invalid_palette(issues: Vec<ValidationIssue>) -> AppError {
+    AppError::new(ErrorCode::InvalidPalette, "Invalid palette")
+        .with_details(serde_json::json!({ "issues": issues }))
 }
 
 #[put("/api/palettes/{name}")]
 async fn update_palette(
+    req: HttpRequest,
     path: web::Path<String>,
     palette_payload: web::Json<PalettePayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let palette_name_from_path = path.into_inner();
+) -> Result<HttpResponse, AppError> {
+    let palette_name_from_path = palette_name_from_path(path.into_inner())?;
     let incoming_palette_data = palette_payload.into_inner();
+    let change = palette_change_from_request(&req, "Updated palette".to_string());
 
     if palette_name_from_path != incoming_palette_data.name {
-        return HttpResponse::BadRequest().body(
+        return Err(AppError::invalid(
             format!(
-                "Palette name in URL ('{}') does not match name in payload ('{}'). Renaming not supported via this update method.",
+                "Palette name in URL ('{}') does not match name in payload ('{}'). Use POST /api/palettes/{{name}}/rename to rename a palette.",
                 palette_name_from_path, incoming_palette_data.name
             )
-        );
+        ));
     }
 
-    let mut palettes_locked = app_state.palettes.lock().unwrap();
-
-    if let Some(palette_in_memory) = palettes_locked.get_mut(&palette_name_from_path) {
-        // Palette found in memory, proceed to update
-        palette_in_memory.commands = incoming_palette_data.commands.clone();
-        let palette_to_save_to_disk = palette_in_memory.clone();
-        drop(palettes_locked); // Release lock before file I/O
+    let issues = validate_palette(&Palette {
+        name: palette_name_from_path.clone(),
+        commands: incoming_palette_data.commands.clone(),
+        meta: Default::default(),
+    });
+    if has_errors(&issues) {
+        return Err(invalid_palette(issues));
+    }
 
-        match save_palette(&palette_to_save_to_disk) {
-            Ok(_) => {
-                HttpResponse::Ok().json(palette_to_save_to_disk)
-            }
-            Err(e) => {
-                eprintln!("Failed to save updated palette '{}' to disk: {}", palette_name_from_path, e);
-                // Attempt to revert in-memory state by reloading the original palette from disk
-                let mut palettes_re_locked = app_state.palettes.lock().unwrap();
-                match load_palette(&palette_name_from_path) {
-                    Ok(original_palette_from_disk) => {
-                        palettes_re_locked.insert(palette_name_from_path.clone(), original_palette_from_disk);
-                        eprintln!("Successfully reloaded palette '{}' from disk into memory after save failure.", palette_name_from_path);
-                    }
-                    Err(load_err) => {
-                        eprintln!("Failed to reload original palette '{}' from disk after save failure: {}. Removing from memory.", palette_name_from_path, load_err);
-                        palettes_re_locked.remove(&palette_name_from_path);
-                    }
-                }
-                HttpResponse::InternalServerError().body(format!("Failed to save updated palette to disk: {}. In-memory state was attempted to be reverted.", e))
-            }
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name_from_path, &change, if_match.as_deref(), |palette| {
+        palette.commands = incoming_palette_data.commands;
+        if let Some(meta) = incoming_palette_data.meta {
+            palette.meta = meta;
         }
-    } else {
-        // Palette not in memory, try loading from disk
-        drop(palettes_locked); // Release current lock before disk I/O
-
-        match load_palette(&palette_name_from_path) {
-            Ok(mut palette_from_disk) => {
-                // Palette loaded successfully from disk.
-                // Update its commands with the incoming data.
-                palette_from_disk.commands = incoming_palette_data.commands; // .clone() not needed as incoming_palette_data is consumed here or its field is.
-
-                // Save the modified palette back to disk.
-                match save_palette(&palette_from_disk) {
-                    Ok(_) => {
-                        // Successfully saved to disk. Now, update the in-memory cache.
-                        let mut palettes_locked_again = app_state.palettes.lock().unwrap();
-                        palettes_locked_again.insert(palette_name_from_path.clone(), palette_from_disk.clone());
-                        HttpResponse::Ok().json(palette_from_disk)
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to save updated palette ('{}') (after loading from disk): {}", palette_name_from_path, e);
-                        HttpResponse::InternalServerError().body(format!("Failed to save updated palette: {}", e))
-                    }
-                }
-            }
-            Err(load_error) => {
-                // Failed to load from disk (e.g., truly not found, or other FS error).
-                eprintln!("Update failed: Palette '{}' not found in memory and also failed to load from disk: {}", palette_name_from_path, load_error);
-                HttpResponse::NotFound().body(format!("Palette '{}' not found on disk. Cannot update.", palette_name_from_path))
-            }
-        }
-    }
+        palette_edit::prune_annotations(palette);
+        Ok(())
+    }).inspect_err(|e| eprintln!("Failed to save updated palette '{}': {}", palette_name_from_path, e))?;
+    Ok(saved_palette_response(HttpResponse::Ok(), &palette))
 }
 
+// `?tag=` and `?danger=` narrow the palette down to matching commands. The ETag is
+// always that of the whole palette, so it can still be used for If-Match.
 #[get("/api/palettes/{name}")]
-pub async fn get_palette_handler(name: web::Path<String>) -> impl Responder {
-    let palette_name = name.into_inner(); // name is moved here
-    match load_palette(&palette_name) {
-        Ok(palette) => HttpResponse::Ok().json(palette),
-        Err(e) => {
-            if e.contains("not found") {
-                HttpResponse::NotFound().body(format!("Palette '{}' not found: {}", palette_name, e))
-            } else {
-                HttpResponse::InternalServerError().body(format!("Error loading palette '{}': {}", palette_name, e))
-            }
-        }
+pub async fn get_palette_handler(
+    name: web::Path<String>,
+    filter: web::Query<PaletteFilterQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(name.into_inner())?;
+    let palette = app_state.palettes.get(&palette_name)?;
+    if filter.is_empty() {
+        return Ok(palette_response(HttpResponse::Ok(), &palette));
     }
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, palette_etag(&palette)))
+        .json(palette_edit::filter_palette(&palette, &filter)))
 }
 
 #[delete("/api/palettes/{name}")]
-pub async fn delete_palette_handler(name: web::Path<String>) -> impl Responder {
-    let palette_name_for_response = name.as_str().to_string(); // Clone the name for the response *before* it's moved.
-    match delete_palette_fs(&name.into_inner()) { // name is moved here
-        Ok(_) => HttpResponse::Ok().body(format!("Palette '{}' deleted successfully.", palette_name_for_response)),
-        Err(e) => {
-            if e.contains("not found") {
-                HttpResponse::NotFound().body(format!("Palette '{}' not found for deletion: {}", palette_name_for_response, e))
-            } else {
-                HttpResponse::InternalServerError().body(format!("Error deleting palette '{}': {}", palette_name_for_response, e))
-            }
-        }
-    }
+pub async fn delete_palette_handler(
+    req: HttpRequest,
+    name: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name_for_response = palette_name_from_path(name.into_inner())?;
+    app_state.palettes.delete(&palette_name_for_response, if_match_header(&req).as_deref())?;
+    Ok(HttpResponse::Ok().body(format!("Palette '{}' deleted successfully.", palette_name_for_response)))
 }
 
-#[post("/api/palettes/import")]
-pub async fn import_palette_handler(mut payload: Multipart) -> impl Responder {
-    let mut temp_file_path: Option<PathBuf> = None;
+// Largest upload accepted by the palette import, archives included
+const MAX_IMPORT_SIZE: usize = 50 * 1024 * 1024;
 
+// Reads the named file field of a multipart upload into memory.
+// Returns None if the request has no such field.
+async fn read_upload(payload: &mut Multipart, field_name: &str) -> Result<Option<(String, Vec<u8>)>, AppError> {
     // Iterate over multipart items
     while let Some(item) = payload.try_next().await.ok().flatten() {
         let mut field = item;
         let content_disposition = field.content_disposition();
-        let field_name = content_disposition.get_name().unwrap_or_default();
-
-        if field_name == "palette_file" {
-            let filename = content_disposition.get_filename().unwrap_or_else(|| "upload.json");
-            let unique_filename = format!("{}-{}", Uuid::new_v4(), filename);
-            
-            // Create a temporary path
-            let mut path = std::env::temp_dir();
-            path.push(unique_filename);
-            temp_file_path = Some(path.clone());
-
-            let mut f = match File::create(&path) {
-                Ok(f) => f,
-                Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to create temp file: {}", e)),
-            };
-
-            // Field in turn is stream of *Bytes* object
-            while let Some(chunk) = field.try_next().await.ok().flatten() {
-                if let Err(e) = f.write_all(&chunk) {
-                    // Cleanup temp file on error
-                    if let Some(p) = &temp_file_path {
-                        let _ = std::fs::remove_file(p);
-                    }
-                    return HttpResponse::InternalServerError().body(format!("Failed to write to temp file: {}", e));
-                }
+        if content_disposition.get_name().unwrap_or_default() != field_name {
+            continue;
+        }
+        let filename = content_disposition.get_filename().unwrap_or("upload.json");
+        // Only keep the final path component of the client-supplied filename
+        let filename = filename.rsplit(['/', '\\']).next().unwrap_or("upload.json").to_string();
+
+        // Field in turn is stream of *Bytes* object
+        let mut contents = Vec::new();
+        while let Some(chunk) = field.try_next().await.ok().flatten() {
+            if contents.len() + chunk.len() > MAX_IMPORT_SIZE {
+                return Err(AppError::new(ErrorCode::PayloadTooLarge, format!("Uploads are limited to {} MB.", MAX_IMPORT_SIZE / (1024 * 1024))));
             }
-            break;
+            contents.extend_from_slice(&chunk);
         }
+        return Ok(Some((filename, contents)));
     }
+    Ok(None)
+}
 
-    if let Some(path) = temp_file_path {
-        match import_palette_fs(&path) {
-            Ok(palette) => {
-                let _ = std::fs::remove_file(&path); // Clean up temp file
-                HttpResponse::Ok().json(palette)
-            }
-            Err(e) => {
-                let _ = std::fs::remove_file(&path); // Clean up temp file
-                HttpResponse::BadRequest().body(e) // Bad request if import fails (e.g., bad JSON)
+// Imports a palette file, or a zip/tar/tar.gz archive of palette files.
+// Files may be full palette documents or bare category maps named after the file.
+// `?on_conflict=reject|overwrite|rename|merge` decides what happens to existing palettes.
+#[post("/api/palettes/import")]
+pub async fn import_palette_handler(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<ImportQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let Some((filename, contents)) = read_upload(&mut payload, "palette_file").await? else {
+        return Err(AppError::invalid("No palette file uploaded or field name is not 'palette_file'."));
+    };
+    let policy = query.on_conflict;
+    let change = palette_change_from_request(&req, format!("Imported from '{}'", filename));
+
+    match read_archive(&contents).map_err(AppError::invalid)? {
+        Some(entries) => {
+            let results: Vec<ImportResult> = entries.iter()
+                .map(|entry| import_palette_file(&app_state.palettes, &entry.file_name, &entry.contents, policy, &change))
+                .collect();
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "imported": results })))
+        }
+        None => {
+            let (palette, _) = parse_palette_file(&filename, &contents).map_err(invalid_palette)?;
+            match app_state.palettes.import(palette, policy, &change) {
+                Ok((palette, _)) => Ok(saved_palette_response(HttpResponse::Ok(), &palette)),
+                Err(e) if e.is(ErrorCode::AlreadyExists) => Err(AppError::already_exists(format!(
+                    "{} Use ?on_conflict=overwrite, rename or merge to import it anyway.", e
+                ))),
+                Err(e) => Err(e),
             }
         }
-    } else {
-        HttpResponse::BadRequest().body("No palette file uploaded or field name is not 'palette_file'.")
     }
 }
 
+// Imports one file of an archive; failures are reported in the result instead of aborting the rest
+fn import_palette_file(
+    palettes: &PaletteRepository,
+    file_name: &str,
+    contents: &[u8],
+    policy: ConflictPolicy,
+    change: &PaletteChange,
+) -> ImportResult {
+    let (palette, warnings) = match parse_palette_file(file_name, contents) {
+        Ok(parsed) => parsed,
+        Err(issues) => return ImportResult {
+            file: file_name.to_string(),
+            name: None,
+            status: ImportStatus::Invalid,
+            message: None,
+            issues,
+        },
+    };
+    let name = palette.name.clone();
+    match palettes.import(palette, policy, change) {
+        Ok((palette, status)) => ImportResult {
+            file: file_name.to_string(),
+            name: Some(palette.name),
+            status,
+            message: None,
+            issues: warnings,
+        },
+        Err(e) => ImportResult {
+            file: file_name.to_string(),
+            name: Some(name),
+            status: if e.is(ErrorCode::AlreadyExists) { ImportStatus::Conflict } else { ImportStatus::Failed },
+            message: Some(e.message),
+            issues: warnings,
+        },
+    }
+}
+
+// Downloads every palette and the settings as one zip archive with a manifest
+#[get("/api/backup")]
+pub async fn backup_handler(app_state: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let archive = create_full_backup(&app_state.palettes)?;
+    let file_name = format!("commander-backup-{}.zip", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", file_name)))
+        .body(archive))
+}
+
+// Restores a backup made by GET /api/backup (multipart field `backup_file`).
+// `?dry_run=true` reports what would change without writing anything.
+#[post("/api/restore")]
+pub async fn restore_handler(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<RestoreQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let Some((_, contents)) = read_upload(&mut payload, "backup_file").await? else {
+        return Err(AppError::invalid("No backup uploaded or field name is not 'backup_file'."));
+    };
+    let change = palette_change_from_request(&req, "Restored from full backup".to_string());
+    let report = restore_full_backup(&app_state.palettes, &contents, query.dry_run, query.settings, &change)?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
 #[get("/api/palettes/{name}/export")]
-pub async fn export_palette_handler(name: web::Path<String>) -> impl Responder {
-    let palette_name = name.into_inner(); // name is moved here
-    match load_palette(&palette_name) {
-        Ok(palette) => {
-            match serde_json::to_string_pretty(&palette) {
-                Ok(json_string) => {
-                    HttpResponse::Ok()
-                        .content_type("application/json")
-                        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.json\"", palette_name)))
-                        .body(json_string)
-                }
-                Err(e) => HttpResponse::InternalServerError().body(format!("Failed to serialize palette '{}': {}", palette_name, e)),
-            }
-        }
-        Err(e) => {
-            if e.contains("not found") {
-                HttpResponse::NotFound().body(format!("Palette '{}' not found for export: {}", palette_name, e))
-            } else {
-                HttpResponse::InternalServerError().body(format!("Error exporting palette '{}': {}", palette_name, e))
-            }
-        }
+pub async fn export_palette_handler(
+    name: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(name.into_inner())?;
+    let format = query.format;
+    let palette = app_state.palettes.get(&palette_name)?;
+    let body = format.serialize(&palette)?;
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.{}\"", palette_name.file_stem(), format.extension())))
+        .body(body))
+}
+
+#[post("/api/palettes/{name}/rename")]
+pub async fn rename_palette_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Json<NewPaletteNamePayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
+    let new_name = payload.into_inner().new_name;
+    if new_name == palette_name {
+        return Err(AppError::invalid(format!("Palette is already named '{}'.", palette_name)));
     }
+    let change = palette_change_from_request(&req, format!("Renamed from '{}'", palette_name));
+
+    let palette = app_state.palettes.rename(&palette_name, &new_name, &change)?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[post("/api/palettes/{name}/duplicate")]
+pub async fn duplicate_palette_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Json<NewPaletteNamePayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
+    let new_name = payload.into_inner().new_name;
+    let change = palette_change_from_request(&req, format!("Duplicated from '{}'", palette_name));
+
+    let palette = app_state.palettes.duplicate(&palette_name, &new_name, &change)?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[get("/api/palettes/{name}/backups")]
+pub async fn list_palette_backups_handler(name: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(name.into_inner())?;
+    Ok(HttpResponse::Ok().json(list_backups(&palette_name)?))
+}
+
+#[post("/api/palettes/{name}/backups/{backup_id}/restore")]
+pub async fn restore_palette_backup_handler(
+    path: web::Path<(String, String)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, backup_id) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let palette = app_state.palettes.restore_backup(&palette_name, &backup_id)?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[get("/api/palettes/{name}/history")]
+pub async fn palette_history_handler(name: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(name.into_inner())?;
+    let dir = palette_dir(&palette_name)?;
+    Ok(HttpResponse::Ok().json(list_revisions(&dir, &palette_name)?))
+}
+
+// Registered before the single-revision route so "diff" is not taken for a revision number
+#[get("/api/palettes/{name}/history/diff")]
+pub async fn palette_diff_handler(
+    name: web::Path<String>,
+    query: web::Query<DiffQuery>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(name.into_inner())?;
+    let dir = palette_dir(&palette_name)?;
+    let to_rev = match query.to {
+        Some(rev) => rev,
+        None => latest_revision_number(&dir, &palette_name)?
+            .ok_or_else(|| AppError::not_found(format!("Palette '{}' has no history.", palette_name)))?,
+    };
+    let from_rev = query.from.unwrap_or(to_rev.saturating_sub(1));
+
+    let to = load_revision(&dir, &palette_name, to_rev)?;
+    // Revision 0 is the empty palette before the first revision, so the first revision diffs as all-added
+    let from = match from_rev {
+        0 => Palette { name: palette_name.clone(), commands: Default::default(), meta: Default::default() },
+        rev => load_revision(&dir, &palette_name, rev)?.palette,
+    };
+    Ok(HttpResponse::Ok().json(diff_palettes(from_rev, &from, to_rev, &to.palette)))
+}
+
+#[get("/api/palettes/{name}/history/{rev}")]
+pub async fn palette_revision_handler(path: web::Path<(String, u64)>) -> Result<HttpResponse, AppError> {
+    let (raw_name, rev) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let dir = palette_dir(&palette_name)?;
+    Ok(HttpResponse::Ok().json(load_revision(&dir, &palette_name, rev)?))
 }
 
+#[post("/api/palettes/{name}/rollback/{rev}")]
+pub async fn rollback_palette_handler(
+    req: HttpRequest,
+    path: web::Path<(String, u64)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, rev) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let dir = palette_dir(&palette_name)?;
+    let revision = load_revision(&dir, &palette_name, rev)?;
+
+    let mut palette = revision.palette;
+    palette.name = palette_name.clone();
+    let change = palette_change_from_request(&req, format!("Rolled back to revision {}", rev));
+    app_state.palettes.save(&palette, &change)?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+const SAVED_COMMANDS_CATEGORY: &str = "Saved Commands";
+
 #[post("/api/palettes/{name}/commands")]
 pub async fn add_command_to_palette(
+    req: HttpRequest,
     path: web::Path<String>,
     command_payload: web::Json<AddCommandPayload>,
     app_state: web::Data<AppState>,
-) -> impl Responder {
-    let palette_name = path.into_inner();
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
     let command_data = command_payload.into_inner();
-    let command_value_to_insert = command_data.command_data.clone();
 
     // Check if command name is empty
     if command_data.command_name.trim().is_empty() {
-        return HttpResponse::BadRequest().body("Command name cannot be empty.");
+        return Err(AppError::invalid("Command name cannot be empty."));
     }
+    let category = command_data.category.clone().unwrap_or_else(|| SAVED_COMMANDS_CATEGORY.to_string());
 
-    let mut palette: Palette;
-    { // Scope for initial lock to get palette data
-        let app_palettes_lock_guard = app_state.palettes.lock().unwrap();
-        if let Some(palette_in_memory) = app_palettes_lock_guard.get(&palette_name) {
-            palette = palette_in_memory.clone();
-            // Lock `app_palettes_lock_guard` is dropped here as it goes out of scope.
-        } else {
-            drop(app_palettes_lock_guard); // Explicitly drop before disk I/O
-            match load_palette(&palette_name) {
-                Ok(palette_from_disk) => {
-                    // Clone palette_from_disk before inserting into cache,
-                    // as palette_from_disk will be moved into the cache.
-                    palette = palette_from_disk.clone(); 
-                    // Update cache with the loaded palette
-                    let mut cache_update_lock_guard = app_state.palettes.lock().unwrap();
-                    cache_update_lock_guard.insert(palette_name.clone(), palette_from_disk);
-                    // cache_update_lock_guard drops here
-                }
-                Err(_) => {
-                    return HttpResponse::NotFound().body(format!("Palette '{}' not found.", palette_name));
-                }
-            }
+    let change = palette_change_from_request(&req, format!("Added command '{}'", command_data.command_name));
+
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| {
+        if palette.commands.get(&category).is_some_and(|c| c.contains_key(&command_data.command_name)) {
+            return Err(AppError::already_exists(format!("Command '{}' already exists in palette '{}'", command_data.command_name, palette_name)));
         }
-    } // Initial lock `app_palettes_lock_guard` is guaranteed to be released here.
+        palette_edit::put_command(palette, &category, &command_data.command_name, command_data.command_data.clone())
+    }).inspect_err(|e| eprintln!("Failed to add command to palette '{}': {}", palette_name, e))?;
 
-    // `palette` is now populated and no locks on `app_state.palettes` are held.
-    // Now, modify `palette` as before.
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(serde_json::json!({
+        "message": format!("Command '{}' added to palette '{}' successfully", command_data.command_name, palette_name),
+        "palette": palette
+    })))
+}
 
-    // Ensure "Saved Commands" category exists
-    if !palette.commands.contains_key("Saved Commands") {
-        palette.commands.insert("Saved Commands".to_string(), indexmap::IndexMap::new());
-    }
+// --- Category and Command Handlers ---
 
-    // Check if command already exists in "Saved Commands" category
-    // This check is on the local `palette` clone.
-    if let Some(saved_commands) = palette.commands.get("Saved Commands") {
-        if saved_commands.contains_key(&command_data.command_name) {
-            return HttpResponse::Conflict().json(serde_json::json!({
-                "error": "Command already exists",
-                "message": format!("Command '{}' already exists in palette '{}'", command_data.command_name, palette_name)
-            }));
-        }
-    }
+#[post("/api/palettes/{name}/categories")]
+pub async fn create_category_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Json<CreateCategoryPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
+    let change = palette_change_from_request(&req, format!("Added category '{}'", payload.name));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::create_category(palette, &payload.name, payload.index))?;
+    Ok(palette_response(HttpResponse::Created(), &palette))
+}
 
-    // Add the command to the "Saved Commands" category in the local `palette` clone.
-    // Use the `command_value_to_insert` (the clone made at the beginning).
-    if let Some(saved_commands) = palette.commands.get_mut("Saved Commands") {
-        saved_commands.insert(command_data.command_name.clone(), command_value_to_insert);
+#[put("/api/palettes/{name}/categories")]
+pub async fn reorder_categories_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Json<CategoryOrderPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
+    let change = palette_change_from_request(&req, "Reordered categories".to_string());
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::reorder_categories(palette, &payload.order))?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[patch("/api/palettes/{name}/categories/{category}")]
+pub async fn rename_category_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    payload: web::Json<RenameCategoryPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let change = palette_change_from_request(&req, format!("Renamed category '{}' to '{}'", category, payload.new_name));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::rename_category(palette, &category, &payload.new_name))?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[delete("/api/palettes/{name}/categories/{category}")]
+pub async fn delete_category_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let change = palette_change_from_request(&req, format!("Deleted category '{}'", category));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::delete_category(palette, &category))?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+#[get("/api/palettes/{name}/commands/{category}/{command}")]
+pub async fn get_command_handler(
+    path: web::Path<(String, String, String)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let palette = app_state.palettes.get(&palette_name)?;
+    let value = palette_edit::get_command(&palette, &category, &command)?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(value))
+}
+
+#[put("/api/palettes/{name}/commands/{category}/{command}")]
+pub async fn put_command_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    command_value: web::Json<JsonValue>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let value = command_value.into_inner();
+    let change = palette_change_from_request(&req, format!("Saved command '{}' in '{}'", command, category));
+    let if_match = if_match_header(&req);
+    let (palette, created) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::put_command(palette, &category, &command, value.clone()))?;
+    if created {
+        Ok(HttpResponse::Created().insert_header((ETAG, palette_etag(&palette))).json(value))
     } else {
-        eprintln!("Critical error: 'Saved Commands' category existed but could not get mut ref, or was unexpectedly removed.");
-        return HttpResponse::InternalServerError().body("Failed to access 'Saved Commands' category internally for command insertion.");
+        Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(value))
     }
+}
 
-    // Save the updated palette to disk. No locks on app_state.palettes are held here.
-    match save_palette(&palette) {
-        Ok(_) => {
-            // Update in-memory cache with the successfully saved version.
-            let mut final_cache_lock = app_state.palettes.lock().unwrap();
-            final_cache_lock.insert(palette_name.clone(), palette.clone());
-            // final_cache_lock drops here.
-            
-            HttpResponse::Ok().json(serde_json::json!({
-                "message": format!("Command '{}' added to palette '{}' successfully", command_data.command_name, palette_name),
-                "palette": palette
-            }))
-        }
-        Err(e) => {
-            eprintln!("Failed to save palette '{}' after command add: {}", palette_name, e);
-            HttpResponse::InternalServerError().body(format!("Failed to save palette: {}", e))
-        }
+#[patch("/api/palettes/{name}/commands/{category}/{command}")]
+pub async fn patch_command_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    patch: web::Json<JsonValue>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let change = palette_change_from_request(&req, format!("Patched command '{}' in '{}'", command, category));
+    let if_match = if_match_header(&req);
+    let (palette, patched) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::patch_command(palette, &category, &command, &patch))?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(patched))
+}
+
+#[delete("/api/palettes/{name}/commands/{category}/{command}")]
+pub async fn delete_command_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<DeleteCommandQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let change = palette_change_from_request(&req, format!("Deleted command '{}' from '{}'", command, category));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| {
+        palette_edit::delete_command(palette, &category, &command, query.remove_empty_category)
+    })?;
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, palette_etag(&palette)))
+        .body(format!("Command '{}' deleted from category '{}' of palette '{}'.", command, category, palette_name)))
+}
+
+#[post("/api/palettes/{name}/commands/{category}/{command}/move")]
+pub async fn move_command_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    payload: web::Json<MoveCommandPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let to_category = payload.to_category.clone().unwrap_or_else(|| category.clone());
+    let to_name = payload.new_name.clone().unwrap_or_else(|| command.clone());
+    let change = palette_change_from_request(&req, format!("Moved command '{}' from '{}' to '{}' in '{}'", command, category, to_name, to_category));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| {
+        palette_edit::move_command(palette, &category, &command, &to_category, &to_name, payload.index)
+    })?;
+    Ok(palette_response(HttpResponse::Ok(), &palette))
+}
+
+// Replaces the annotation (description, tags, warning, danger flag, links) of a category.
+// An empty object removes it.
+#[put("/api/palettes/{name}/categories/{category}/meta")]
+pub async fn put_category_annotation_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    annotation: web::Json<Annotation>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let annotation = annotation.into_inner();
+    let change = palette_change_from_request(&req, format!("Annotated category '{}'", category));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::set_category_annotation(palette, &category, annotation.clone()))?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(annotation))
+}
+
+// Same for a single command
+#[put("/api/palettes/{name}/commands/{category}/{command}/meta")]
+pub async fn put_command_annotation_handler(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    annotation: web::Json<Annotation>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (raw_name, category, command) = path.into_inner();
+    let palette_name = palette_name_from_path(raw_name)?;
+    let annotation = annotation.into_inner();
+    let change = palette_change_from_request(&req, format!("Annotated command '{}' in '{}'", command, category));
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| palette_edit::set_command_annotation(palette, &category, &command, annotation.clone()))?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(annotation))
+}
+
+// Looks up a palette command for the confirm and send calls
+fn load_palette_command(
+    palettes: &PaletteRepository,
+    path: (String, String, String),
+) -> Result<(Palette, CommandRef, JsonValue), AppError> {
+    let (raw_name, category, command) = path;
+    let palette_name = palette_name_from_path(raw_name)?;
+    let palette = palettes.get(&palette_name)?;
+    let value = palette_edit::get_command(&palette, &category, &command)?.clone();
+    Ok((palette, CommandRef { palette: palette_name, category, command }, value))
+}
+
+// The command body a send will use: the edited `command`, or the stored one with its
+// placeholders filled from `values`
+fn command_to_send(stored: &JsonValue, values: &IndexMap<String, JsonValue>, edited: Option<&JsonValue>) -> Result<JsonValue, AppError> {
+    match edited {
+        Some(_) if !values.is_empty() => Err(AppError::invalid("Pass either \"values\" or an edited \"command\", not both.")),
+        Some(edited) => Ok(edited.clone()),
+        None => palette_edit::fill_placeholders(stored, values),
     }
 }
 
-// Helper function to unescape a string into a byte vector
-// Translates common escape sequences like \r, \n, \t, \0, \\
-// Other characters are passed through as their UTF-8 bytes.
-fn unescape_string_to_bytes(s: &str) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    let mut chars = s.chars().peekable();
-    while let Some(c) = chars.next() {
-        if c == '\\' {
-            match chars.next() {
-                Some('r') => bytes.push(b'\r'),
-                Some('n') => bytes.push(b'\n'),
-                Some('t') => bytes.push(b'\t'),
-                Some('0') => bytes.push(b'\0'),
-                Some('\\') => bytes.push(b'\\'),
-                Some(other) => {
-                    // Not a recognized escape sequence, push backslash and the char
-                    bytes.push(b'\\');
-                    bytes.extend(other.to_string().as_bytes());
-                }
-                None => bytes.push(b'\\'), // Trailing backslash
-            }
-        } else {
-            bytes.extend(c.to_string().as_bytes());
+// Issues a single-use token that allows sending a dangerous command once, within a minute,
+// as long as the palette is not modified in the meantime. The token is bound to the body
+// given here (`values` or an edited `command`, the stored command if there is no body).
+#[post("/api/palettes/{name}/commands/{category}/{command}/confirm")]
+pub async fn confirm_palette_command_handler(
+    path: web::Path<(String, String, String)>,
+    payload: Option<web::Json<PaletteConfirmPayload>>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (palette, target, value) = load_palette_command(&app_state.palettes, path.into_inner())?;
+    let payload = payload.map(web::Json::into_inner).unwrap_or_default();
+    let command = command_to_send(&value, &payload.values, payload.command.as_ref())?;
+    let danger = palette.meta.is_dangerous(&target.category, &target.command);
+    let warning = palette.meta.warning(&target.category, &target.command).map(str::to_string);
+    let token = app_state.confirmations.issue(target, palette_etag(&palette), command_digest(&command));
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "confirm_token": token,
+        "expires_in": CONFIRMATION_TTL.as_secs(),
+        "danger": danger,
+        "warning": warning,
+    })))
+}
+
+// Sends a command as stored in a palette, with its placeholders filled from `values`.
+// The delimiter, encoding and target come from the request, else from the command,
+// its category or the palette. Commands marked as dangerous are refused with 428
+// unless `confirm_token` is given.
+#[post("/api/palettes/{name}/commands/{category}/{command}/send")]
+pub async fn send_palette_command_handler(
+    path: web::Path<(String, String, String)>,
+    payload: web::Json<PaletteSendPayload>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let (palette, target, value) = load_palette_command(&app_state.palettes, path.into_inner())?;
+    let danger = palette.meta.is_dangerous(&target.category, &target.command);
+    if danger && payload.confirm_token.is_none() {
+        return Err(AppError::new(
+            ErrorCode::ConfirmationRequired,
+            format!("Command '{}' is marked as dangerous. Confirm it first with POST .../confirm and pass the token as confirm_token.", target.command),
+        ).with_details(serde_json::json!({ "warning": palette.meta.warning(&target.category, &target.command) })));
+    }
+
+    let transport = TransportSettings {
+        delimiter: payload.delimiter.clone(),
+        encoding: payload.encoding,
+        target: payload.target.clone(),
+    }.or(&palette.meta.transport_for(&target.category, &target.command));
+    let connection = app_state.current_connection();
+    if let Some(address) = transport.target.as_deref().filter(|t| !t.is_empty()) {
+        let connected = match connection.as_deref() {
+            Some(connection) => connection.is_connected_to(address).await,
+            None => false,
+        };
+        if !connected {
+            let connected_to = connection.as_ref().map(|connection| connection.address.clone());
+            let message = format!(
+                "Command '{}' is meant for {}, but Commander is {}. Connect to {} first, or pass \"target\" to override.",
+                target.command,
+                address,
+                connected_to.as_ref().map_or("not connected".to_string(), |c| format!("connected to {}", c)),
+                address
+            );
+            return Err(AppError::new(ErrorCode::WrongTarget, message)
+                .with_details(serde_json::json!({ "target": address, "connected_to": connected_to })));
         }
     }
-    bytes
+
+    let filled = command_to_send(&value, &payload.values, payload.command.as_ref())?;
+    let digest = command_digest(&filled);
+    let filled = match connection.as_deref() {
+        Some(connection) => jsonrpc_request(connection, &filled)?,
+        None => filled,
+    };
+    // Text encodings only apply to JSON text; binary connections encode the value themselves
+    let payload_encoding = connection.as_ref().map_or(PayloadEncoding::Json, |connection| connection.encoding);
+    let mut command_bytes = match payload_encoding {
+        PayloadEncoding::Json => encode_command(&filled, transport.encoding.unwrap_or_default())?,
+        binary => binary.encode(&filled).map_err(AppError::internal)?,
+    };
+    if let Some(delimiter_str) = transport.delimiter.as_deref().filter(|d| !d.is_empty()) {
+        command_bytes.extend_from_slice(&unescape(delimiter_str).map_err(AppError::invalid)?);
+    }
+
+    let command_bytes = match &connection {
+        Some(connection) => connection.frame(command_bytes).map_err(AppError::invalid)?,
+        None => command_bytes,
+    };
+
+    // Only use up the token once everything else checked out
+    let confirmed_at = match &payload.confirm_token {
+        Some(token) if danger => Some(
+            app_state.confirmations.redeem(token, &target, &palette_etag(&palette), &digest)
+                .map_err(|e| AppError::new(ErrorCode::ConfirmationRejected, e))?,
+        ),
+        _ => None,
+    };
+
+    let bytes = command_bytes.len();
+    let result = match &connection {
+        Some(connection) => write_command(connection, &filled, command_bytes).await,
+        None => Err(NetError::not_connected()),
+    };
+    app_state.send_history.record(SendRecord {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        target,
+        bytes,
+        danger,
+        confirmed_at,
+        status: if result.is_ok() { SendStatus::Sent } else { SendStatus::Failed },
+        error: result.as_ref().err().map(NetError::to_string),
+    });
+    result?;
+    Ok(HttpResponse::Ok().json(filled))
+}
+
+// Replaces the palette's transport defaults; an empty object removes them
+#[put("/api/palettes/{name}/transport")]
+pub async fn put_palette_transport_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    settings: web::Json<TransportSettings>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let palette_name = palette_name_from_path(path.into_inner())?;
+    let settings = settings.into_inner();
+    let change = palette_change_from_request(&req, "Changed transport settings".to_string());
+    let if_match = if_match_header(&req);
+    let (palette, _) = app_state.palettes.modify(&palette_name, &change, if_match.as_deref(), |palette| {
+        palette.meta.transport = settings.clone();
+        Ok(())
+    })?;
+    Ok(HttpResponse::Ok().insert_header((ETAG, palette_etag(&palette))).json(settings))
+}
+
+// Palette commands sent since the server started, newest first
+#[get("/api/send-history")]
+pub async fn send_history_handler(app_state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(app_state.send_history.list())
 } 
\ No newline at end of file
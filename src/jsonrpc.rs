@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value as JsonValue};
+use tokio::sync::broadcast::Sender;
+
+// Sentinels on /ws for JSON-RPC traffic, so the UI can tell replies from device chatter
+pub const JSONRPC_RESPONSE_PREFIX: &str = "JSONRPC_RESPONSE:";
+pub const JSONRPC_NOTIFICATION_PREFIX: &str = "JSONRPC_NOTIFICATION:";
+pub const JSONRPC_TIMEOUT_PREFIX: &str = "JSONRPC_TIMEOUT:";
+
+// A call that has not been answered within an hour is not going to be
+const MAX_TIMEOUT_MS: u64 = 60 * 60 * 1000;
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+// Turns a connection into a JSON-RPC 2.0 client: commands are written as
+// {"method": ..., "params": ...} and sent as requests with an id the server picks
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JsonRpcConfig {
+    // How long to wait for a response before reporting the call as timed out
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl JsonRpcConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_ms == 0 || self.timeout_ms > MAX_TIMEOUT_MS {
+            return Err(format!("Invalid jsonrpc timeout: timeout_ms must be between 1 and {}.", MAX_TIMEOUT_MS));
+        }
+        Ok(())
+    }
+}
+
+struct PendingCall {
+    method: String,
+    sent: Instant,
+}
+
+// The ids handed out on one connection and the calls still waiting for a response
+pub struct JsonRpcSession {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, PendingCall>>,
+    timeout: Duration,
+}
+
+impl JsonRpcSession {
+    pub fn new(config: &JsonRpcConfig) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    // Wraps a {"method", "params"} command as a request with the next id
+    pub fn request(&self, command: &JsonValue) -> Result<JsonValue, String> {
+        let invalid = || "Invalid JSON-RPC command: expected {\"method\": \"...\", \"params\": [...] or {...}}.".to_string();
+        let object = command.as_object().ok_or_else(invalid)?;
+        let method = object.get("method").and_then(JsonValue::as_str).ok_or_else(invalid)?;
+        if object.keys().any(|key| !matches!(key.as_str(), "method" | "params" | "jsonrpc" | "id")) {
+            return Err(invalid());
+        }
+        let mut request = Map::new();
+        request.insert("jsonrpc".to_string(), json!("2.0"));
+        request.insert("method".to_string(), json!(method));
+        match object.get("params") {
+            None | Some(JsonValue::Null) => {}
+            Some(params @ (JsonValue::Array(_) | JsonValue::Object(_))) => {
+                request.insert("params".to_string(), params.clone());
+            }
+            Some(_) => return Err("Invalid JSON-RPC command: \"params\" must be an array or an object.".to_string()),
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        request.insert("id".to_string(), json!(id));
+        Ok(JsonValue::Object(request))
+    }
+
+    // Starts waiting for the response to a request made by `request`, just before it is
+    // written. Returns the id to cancel with if the write fails.
+    pub fn track(&self, request: &JsonValue) -> Option<u64> {
+        let id = request.get("id")?.as_u64()?;
+        let method = request.get("method")?.as_str()?.to_string();
+        self.pending.lock().unwrap().insert(id, PendingCall { method, sent: Instant::now() });
+        Some(id)
+    }
+
+    pub fn cancel(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    // Turns a message from the device into what is broadcast on /ws: responses are
+    // matched to their request, notifications are tagged, anything else is passed on.
+    // A batch yields one message per entry.
+    pub fn inbound(&self, value: JsonValue) -> Vec<String> {
+        match value {
+            JsonValue::Array(batch) if !batch.is_empty() && batch.iter().all(is_jsonrpc) => {
+                batch.into_iter().map(|entry| self.inbound_one(entry)).collect()
+            }
+            value => vec![self.inbound_one(value)],
+        }
+    }
+
+    fn inbound_one(&self, value: JsonValue) -> String {
+        if !is_jsonrpc(&value) {
+            return value.to_string();
+        }
+        let is_response = value.get("result").is_some() || value.get("error").is_some();
+        if !is_response {
+            if value.get("id").is_none() {
+                let notification = json!({ "method": value["method"], "params": value.get("params") });
+                return format!("{}{}", JSONRPC_NOTIFICATION_PREFIX, notification);
+            }
+            // A request from the device; we do not serve any, so show it as is
+            return value.to_string();
+        }
+        let id = value.get("id").cloned().unwrap_or(JsonValue::Null);
+        let call = id.as_u64().and_then(|id| self.pending.lock().unwrap().remove(&id));
+        let mut response = json!({
+            "id": id,
+            // null when the id does not belong to a call we are waiting for
+            "method": call.as_ref().map(|call| call.method.clone()),
+            "elapsed_ms": call.as_ref().map(|call| call.sent.elapsed().as_millis() as u64),
+        });
+        match value.get("error") {
+            Some(error) => response["error"] = error.clone(),
+            None => response["result"] = value["result"].clone(),
+        }
+        format!("{}{}", JSONRPC_RESPONSE_PREFIX, response)
+    }
+
+    // Removes the calls that have waited longer than the timeout
+    fn take_expired(&self) -> Vec<(u64, PendingCall)> {
+        let mut pending = self.pending.lock().unwrap();
+        let expired: Vec<u64> = pending.iter()
+            .filter(|(_, call)| call.sent.elapsed() >= self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut calls: Vec<(u64, PendingCall)> = expired.into_iter()
+            .filter_map(|id| pending.remove(&id).map(|call| (id, call)))
+            .collect();
+        calls.sort_by_key(|(id, _)| *id);
+        calls
+    }
+}
+
+fn is_jsonrpc(value: &JsonValue) -> bool {
+    value.get("jsonrpc").and_then(JsonValue::as_str) == Some("2.0")
+}
+
+// Reports calls that never got a response as JSONRPC_TIMEOUT:{...}. Runs until the
+// session is dropped, i.e. until the connection is closed or replaced.
+pub fn spawn_timeout_sweeper(session: &Arc<JsonRpcSession>, tx: Sender<String>) {
+    let session: Weak<JsonRpcSession> = Arc::downgrade(session);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(250));
+        loop {
+            interval.tick().await;
+            let Some(session) = session.upgrade() else { break };
+            for (id, call) in session.take_expired() {
+                let timeout = json!({ "id": id, "method": call.method, "timeout_ms": session.timeout.as_millis() as u64 });
+                println!("JSON-RPC call {} ({}) timed out.", id, call.method);
+                let _ = tx.send(format!("{}{}", JSONRPC_TIMEOUT_PREFIX, timeout));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(timeout_ms: u64) -> JsonRpcSession {
+        JsonRpcSession::new(&JsonRpcConfig { timeout_ms })
+    }
+
+    fn parse(message: &str, prefix: &str) -> JsonValue {
+        serde_json::from_str(message.strip_prefix(prefix).unwrap_or_else(|| panic!("{} lacks {}", message, prefix))).unwrap()
+    }
+
+    #[test]
+    fn timeouts_must_be_positive_and_bounded() {
+        for timeout_ms in [1, default_timeout_ms(), MAX_TIMEOUT_MS] {
+            JsonRpcConfig { timeout_ms }.validate().unwrap();
+        }
+        for timeout_ms in [0, MAX_TIMEOUT_MS + 1, u64::MAX] {
+            assert!(JsonRpcConfig { timeout_ms }.validate().unwrap_err().contains("timeout_ms"));
+        }
+    }
+
+    #[test]
+    fn requests_get_increasing_ids() {
+        let session = session(1000);
+        let first = session.request(&json!({"method": "ping"})).unwrap();
+        assert_eq!(first, json!({"jsonrpc": "2.0", "method": "ping", "id": 1}));
+        let second = session.request(&json!({"method": "set", "params": {"v": 1}, "id": 99, "jsonrpc": "1.0"})).unwrap();
+        assert_eq!(second, json!({"jsonrpc": "2.0", "method": "set", "params": {"v": 1}, "id": 2}));
+        assert_eq!(session.request(&json!({"method": "m", "params": null})).unwrap()["params"], JsonValue::Null);
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        let session = session(1000);
+        for command in [json!("ping"), json!({"params": []}), json!({"method": 1}), json!({"method": "m", "extra": 1})] {
+            assert!(session.request(&command).unwrap_err().starts_with("Invalid JSON-RPC command: expected"), "{}", command);
+        }
+        assert_eq!(
+            session.request(&json!({"method": "m", "params": 3})).unwrap_err(),
+            "Invalid JSON-RPC command: \"params\" must be an array or an object."
+        );
+    }
+
+    #[test]
+    fn responses_are_matched_to_their_call() {
+        let session = session(1000);
+        let request = session.request(&json!({"method": "status"})).unwrap();
+        assert_eq!(session.track(&request), Some(1));
+
+        let messages = session.inbound(json!({"jsonrpc": "2.0", "id": 1, "result": {"ok": true}}));
+        let response = parse(&messages[0], JSONRPC_RESPONSE_PREFIX);
+        assert_eq!(response["method"], "status");
+        assert_eq!(response["result"], json!({"ok": true}));
+        assert!(response["elapsed_ms"].is_u64());
+
+        // A second response to the same id no longer matches a call
+        let messages = session.inbound(json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000}}));
+        let response = parse(&messages[0], JSONRPC_RESPONSE_PREFIX);
+        assert_eq!(response["method"], JsonValue::Null);
+        assert_eq!(response["error"], json!({"code": -32000}));
+        assert!(response.get("result").is_none());
+    }
+
+    #[test]
+    fn cancelled_calls_are_forgotten() {
+        let session = session(0);
+        let request = session.request(&json!({"method": "m"})).unwrap();
+        let id = session.track(&request).unwrap();
+        session.cancel(id);
+        assert!(session.take_expired().is_empty());
+        assert_eq!(session.track(&json!({"method": "m"})), None);
+    }
+
+    #[test]
+    fn notifications_batches_and_other_messages() {
+        let session = session(1000);
+        let messages = session.inbound(json!({"jsonrpc": "2.0", "method": "tick", "params": [1]}));
+        assert_eq!(parse(&messages[0], JSONRPC_NOTIFICATION_PREFIX), json!({"method": "tick", "params": [1]}));
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "tick"},
+            {"jsonrpc": "2.0", "id": 5, "result": 1},
+        ]);
+        let messages = session.inbound(batch);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with(JSONRPC_NOTIFICATION_PREFIX));
+        assert!(messages[1].starts_with(JSONRPC_RESPONSE_PREFIX));
+
+        // Device requests, plain JSON and mixed arrays are passed on unchanged
+        let request = json!({"jsonrpc": "2.0", "method": "ask", "id": 3});
+        assert_eq!(session.inbound(request.clone()), vec![request.to_string()]);
+        assert_eq!(session.inbound(json!({"temp": 21})), vec!["{\"temp\":21}".to_string()]);
+        let mixed = json!([{"jsonrpc": "2.0", "method": "tick"}, 1]);
+        assert_eq!(session.inbound(mixed.clone()), vec![mixed.to_string()]);
+        assert_eq!(session.inbound(json!([])), vec!["[]".to_string()]);
+    }
+
+    #[test]
+    fn expired_calls_are_taken_once_in_id_order() {
+        let session = session(0);
+        for _ in 0..3 {
+            let request = session.request(&json!({"method": "slow"})).unwrap();
+            session.track(&request);
+        }
+        let expired: Vec<u64> = session.take_expired().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(expired, [1, 2, 3]);
+        assert!(session.take_expired().is_empty());
+
+        let waiting = JsonRpcSession::new(&JsonRpcConfig { timeout_ms: 60_000 });
+        waiting.track(&waiting.request(&json!({"method": "m"})).unwrap());
+        assert!(waiting.take_expired().is_empty());
+    }
+}
@@ -3,10 +3,33 @@ use local_ip_address::local_ip;
 use colored::*;
 
 mod types;
+mod config;
 mod state;
 mod websocket;
 mod handlers;
 mod palette_manager;
+mod palette_name;
+mod palette_history;
+mod palette_edit;
+mod palette_repository;
+mod palette_validation;
+mod palette_import;
+mod palette_formats;
+mod backup_archive;
+mod search;
+mod send_guard;
+mod transport;
+mod escape;
+mod byte_send;
+mod framing;
+mod payload_encoding;
+mod jsonrpc;
+mod reader_limits;
+mod connection;
+mod net_error;
+mod error;
+#[cfg(test)]
+mod test_support;
 
 use state::AppState;
 use handlers::{connect_route, disconnect_route, send_command, send_text_command_route, version_route, embedded_file_handler};
@@ -21,23 +44,67 @@ async fn main() -> std::io::Result<()> {
 
     print_setup_message(server_address, server_port);
 
+    // Kept alive for as long as the server runs
+    let _palette_watcher = match palette_repository::watch_palette_dirs(app_state.palettes.clone(), app_state.tcp_message_tx.clone()) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            println!("{}", format!("Palette directory changes will not be picked up automatically: {}", e).yellow());
+            None
+        }
+    };
+
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            // Extractor failures go through AppError too
+            .app_data(web::JsonConfig::default().error_handler(error::json_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error_handler))
+            .app_data(web::PathConfig::default().error_handler(error::path_error_handler))
             .service(connect_route)
             .service(disconnect_route)
             .service(send_command)
             .service(send_text_command_route)
+            .service(handlers::send_bytes_route)
+            .service(handlers::connection_status_route)
+            .service(handlers::reader_stats_route)
             .service(version_route)
             .service(handlers::health_check)
             .service(handlers::list_palettes_handler)
+            .service(handlers::list_palette_roots_handler)
+            .service(handlers::search_handler)
             .service(handlers::create_palette)
+            .service(handlers::validate_palette_handler)
             .service(handlers::update_palette)
             .service(handlers::get_palette_handler)
             .service(handlers::delete_palette_handler)
             .service(handlers::import_palette_handler)
             .service(handlers::export_palette_handler)
+            .service(handlers::backup_handler)
+            .service(handlers::restore_handler)
             .service(handlers::add_command_to_palette)
+            .service(handlers::create_category_handler)
+            .service(handlers::reorder_categories_handler)
+            .service(handlers::rename_category_handler)
+            .service(handlers::delete_category_handler)
+            .service(handlers::get_command_handler)
+            .service(handlers::put_command_handler)
+            .service(handlers::patch_command_handler)
+            .service(handlers::delete_command_handler)
+            .service(handlers::move_command_handler)
+            .service(handlers::put_category_annotation_handler)
+            .service(handlers::put_command_annotation_handler)
+            .service(handlers::confirm_palette_command_handler)
+            .service(handlers::send_palette_command_handler)
+            .service(handlers::send_history_handler)
+            .service(handlers::put_palette_transport_handler)
+            .service(handlers::rename_palette_handler)
+            .service(handlers::duplicate_palette_handler)
+            .service(handlers::list_palette_backups_handler)
+            .service(handlers::restore_palette_backup_handler)
+            .service(handlers::palette_history_handler)
+            .service(handlers::palette_diff_handler)
+            .service(handlers::palette_revision_handler)
+            .service(handlers::rollback_palette_handler)
             .service(ws_route)
             .default_service(web::route().to(embedded_file_handler))
     })
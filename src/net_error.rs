@@ -0,0 +1,138 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use serde::Serialize;
+
+// What went wrong on the network, so the UI can say "nothing listening on that port"
+// rather than echoing an OS error string
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NetErrorKind {
+    // The address is not "host:port"
+    InvalidAddress,
+    // The host name could not be resolved
+    Dns,
+    // The host answered, but nothing is listening on the port
+    Refused,
+    Timeout,
+    // No route to the host or its network
+    Unreachable,
+    // The device closed or reset the connection
+    Reset,
+    // Too many writes are already waiting for the device
+    QueueFull,
+    NotConnected,
+    Other,
+}
+
+impl NetErrorKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            NetErrorKind::InvalidAddress => "Invalid address",
+            NetErrorKind::Dns => "Host not found",
+            NetErrorKind::Refused => "Connection refused",
+            NetErrorKind::Timeout => "Timed out",
+            NetErrorKind::Unreachable => "Host unreachable",
+            NetErrorKind::Reset => "Connection reset",
+            NetErrorKind::QueueFull => "Write queue full",
+            NetErrorKind::NotConnected => "Not connected",
+            NetErrorKind::Other => "Network error",
+        }
+    }
+
+    fn from_io(e: &io::Error) -> NetErrorKind {
+        match e.kind() {
+            ErrorKind::InvalidInput => NetErrorKind::InvalidAddress,
+            ErrorKind::ConnectionRefused => NetErrorKind::Refused,
+            ErrorKind::TimedOut => NetErrorKind::Timeout,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => NetErrorKind::Unreachable,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => NetErrorKind::Reset,
+            ErrorKind::NotConnected => NetErrorKind::NotConnected,
+            _ => NetErrorKind::Other,
+        }
+    }
+}
+
+// A classified connect or write failure, sent to clients as
+// {"error": "Connection refused", "kind": "refused", "message": "...", "address": "..."}
+#[derive(Serialize, Debug, Clone)]
+pub struct NetError {
+    pub kind: NetErrorKind,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+impl NetError {
+    pub fn new(kind: NetErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into(), address: None }
+    }
+
+    pub fn from_io(context: &str, e: &io::Error) -> Self {
+        Self::new(NetErrorKind::from_io(e), format!("{}: {}", context, e))
+    }
+
+    pub fn at(mut self, address: &str) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    pub fn not_connected() -> Self {
+        Self::new(NetErrorKind::NotConnected, "Not connected to any TCP socket.")
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::json!({ "error": self.kind.label() });
+        if let (Some(body), serde_json::Value::Object(fields)) = (body.as_object_mut(), serde_json::json!(self)) {
+            body.extend(fields);
+        }
+        body
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_are_classified() {
+        let cases = [
+            (ErrorKind::InvalidInput, NetErrorKind::InvalidAddress),
+            (ErrorKind::ConnectionRefused, NetErrorKind::Refused),
+            (ErrorKind::TimedOut, NetErrorKind::Timeout),
+            (ErrorKind::HostUnreachable, NetErrorKind::Unreachable),
+            (ErrorKind::NetworkUnreachable, NetErrorKind::Unreachable),
+            (ErrorKind::ConnectionReset, NetErrorKind::Reset),
+            (ErrorKind::ConnectionAborted, NetErrorKind::Reset),
+            (ErrorKind::BrokenPipe, NetErrorKind::Reset),
+            (ErrorKind::UnexpectedEof, NetErrorKind::Reset),
+            (ErrorKind::NotConnected, NetErrorKind::NotConnected),
+            (ErrorKind::PermissionDenied, NetErrorKind::Other),
+        ];
+        for (io_kind, kind) in cases {
+            let e = NetError::from_io("TCP write error", &io::Error::new(io_kind, "boom"));
+            assert_eq!(e.kind, kind, "{:?}", io_kind);
+            assert_eq!(e.message, "TCP write error: boom");
+        }
+    }
+
+    #[test]
+    fn json_body_has_label_kind_and_address() {
+        let e = NetError::new(NetErrorKind::Refused, "Nothing listening.").at("127.0.0.1:9");
+        assert_eq!(e.to_string(), "Nothing listening.");
+        assert_eq!(e.to_json(), serde_json::json!({
+            "error": "Connection refused",
+            "kind": "refused",
+            "message": "Nothing listening.",
+            "address": "127.0.0.1:9",
+        }));
+        let body = NetError::not_connected().to_json();
+        assert_eq!(body["kind"], "not_connected");
+        assert!(body.get("address").is_none());
+    }
+}
@@ -0,0 +1,466 @@
+use indexmap::IndexMap;
+use serde_json::Value as JsonValue;
+
+use crate::types::{Annotation, Palette, PaletteFilterQuery};
+use crate::error::AppError;
+
+// Operations on a palette's categories and commands.
+// Each one edits a loaded palette in place; callers run them through
+// `palette_manager::modify_palette` so the load, edit and save happen atomically.
+
+fn check_entry_name(kind: &str, name: &str) -> Result<(), AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::invalid(format!("Invalid {} name: name cannot be empty.", kind)));
+    }
+    Ok(())
+}
+
+fn category_not_found(palette: &Palette, category: &str) -> AppError {
+    AppError::not_found(format!("Category '{}' not found in palette '{}'.", category, palette.name))
+}
+
+fn command_not_found(palette: &Palette, category: &str, command: &str) -> AppError {
+    AppError::not_found(format!("Command '{}' not found in category '{}' of palette '{}'.", command, category, palette.name))
+}
+
+pub fn create_category(palette: &mut Palette, category: &str, index: Option<usize>) -> Result<(), AppError> {
+    check_entry_name("category", category)?;
+    if palette.commands.contains_key(category) {
+        return Err(AppError::already_exists(format!("Category '{}' already exists in palette '{}'.", category, palette.name)));
+    }
+    let index = index.unwrap_or(palette.commands.len()).min(palette.commands.len());
+    palette.commands.shift_insert(index, category.to_string(), IndexMap::new());
+    Ok(())
+}
+
+// Renames a category, keeping its position and commands
+pub fn rename_category(palette: &mut Palette, category: &str, new_name: &str) -> Result<(), AppError> {
+    check_entry_name("category", new_name)?;
+    if category == new_name {
+        return Ok(());
+    }
+    if palette.commands.contains_key(new_name) {
+        return Err(AppError::already_exists(format!("Category '{}' already exists in palette '{}'.", new_name, palette.name)));
+    }
+    let (index, _, commands) = palette.commands.shift_remove_full(category)
+        .ok_or_else(|| category_not_found(palette, category))?;
+    palette.commands.shift_insert(index, new_name.to_string(), commands);
+    if let Some(annotation) = palette.meta.categories.shift_remove(category) {
+        palette.meta.categories.insert(new_name.to_string(), annotation);
+    }
+    if let Some(annotations) = palette.meta.commands.shift_remove(category) {
+        palette.meta.commands.insert(new_name.to_string(), annotations);
+    }
+    Ok(())
+}
+
+// Puts categories in the given order, which must name every category exactly once
+pub fn reorder_categories(palette: &mut Palette, order: &[String]) -> Result<(), AppError> {
+    let mut reordered = IndexMap::with_capacity(order.len());
+    for category in order {
+        if reordered.contains_key(category) {
+            return Err(AppError::invalid(format!("Invalid category order: '{}' is listed more than once.", category)));
+        }
+        let commands = palette.commands.get(category)
+            .ok_or_else(|| category_not_found(palette, category))?;
+        reordered.insert(category.clone(), commands.clone());
+    }
+    if reordered.len() != palette.commands.len() {
+        let missing: Vec<&String> = palette.commands.keys().filter(|c| !reordered.contains_key(*c)).collect();
+        return Err(AppError::invalid(format!("Invalid category order: missing categories {:?}.", missing)));
+    }
+    palette.commands = reordered;
+    Ok(())
+}
+
+pub fn delete_category(palette: &mut Palette, category: &str) -> Result<(), AppError> {
+    palette.commands.shift_remove(category)
+        .ok_or_else(|| category_not_found(palette, category))?;
+    palette.meta.categories.shift_remove(category);
+    palette.meta.commands.shift_remove(category);
+    Ok(())
+}
+
+pub fn get_command<'a>(palette: &'a Palette, category: &str, command: &str) -> Result<&'a JsonValue, AppError> {
+    palette.commands.get(category)
+        .ok_or_else(|| category_not_found(palette, category))?
+        .get(command)
+        .ok_or_else(|| command_not_found(palette, category, command))
+}
+
+// Creates or replaces a command, creating its category if needed.
+// Returns true if the command was newly created.
+pub fn put_command(palette: &mut Palette, category: &str, command: &str, value: JsonValue) -> Result<bool, AppError> {
+    check_entry_name("category", category)?;
+    check_entry_name("command", command)?;
+    let commands = palette.commands.entry(category.to_string()).or_default();
+    Ok(commands.insert(command.to_string(), value).is_none())
+}
+
+// Fills in a command's placeholders. Each key of `values` is the JSON pointer of a
+// placeholder string such as "%01"; the rest of the command is sent as it is stored.
+pub fn fill_placeholders(command: &JsonValue, values: &IndexMap<String, JsonValue>) -> Result<JsonValue, AppError> {
+    let mut filled = command.clone();
+    for (pointer, value) in values {
+        let target = filled.pointer_mut(pointer)
+            .ok_or_else(|| AppError::invalid(format!("Invalid placeholder '{}': the command has no value there.", pointer)))?;
+        if !target.as_str().is_some_and(|text| text.contains('%')) {
+            return Err(AppError::invalid(format!("Invalid placeholder '{}': the value there is not a placeholder.", pointer)));
+        }
+        *target = value.clone();
+    }
+    Ok(filled)
+}
+
+// Applies a JSON merge patch (RFC 7386) to a command and returns the result
+pub fn patch_command(palette: &mut Palette, category: &str, command: &str, patch: &JsonValue) -> Result<JsonValue, AppError> {
+    let not_found = command_not_found(palette, category, command);
+    let value = palette.commands.get_mut(category)
+        .and_then(|commands| commands.get_mut(command))
+        .ok_or(not_found)?;
+    merge_patch(value, patch);
+    Ok(value.clone())
+}
+
+fn merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    match patch {
+        JsonValue::Object(patch_map) => {
+            if !target.is_object() {
+                *target = JsonValue::Object(serde_json::Map::new());
+            }
+            if let JsonValue::Object(target_map) = target {
+                for (key, patch_value) in patch_map {
+                    if patch_value.is_null() {
+                        target_map.remove(key);
+                    } else {
+                        merge_patch(target_map.entry(key.clone()).or_insert(JsonValue::Null), patch_value);
+                    }
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+// Deletes a command, optionally removing its category if that leaves it empty
+pub fn delete_command(palette: &mut Palette, category: &str, command: &str, remove_empty_category: bool) -> Result<(), AppError> {
+    let not_found = command_not_found(palette, category, command);
+    let commands = palette.commands.get_mut(category).ok_or(not_found.clone())?;
+    commands.shift_remove(command).ok_or(not_found)?;
+    if remove_empty_category && commands.is_empty() {
+        palette.commands.shift_remove(category);
+        palette.meta.categories.shift_remove(category);
+    }
+    set_command_annotation_unchecked(palette, category, command, None);
+    Ok(())
+}
+
+// Moves a command to another category and/or name and position.
+// The target category is created if it does not exist yet.
+pub fn move_command(
+    palette: &mut Palette,
+    category: &str,
+    command: &str,
+    to_category: &str,
+    to_name: &str,
+    index: Option<usize>,
+) -> Result<(), AppError> {
+    check_entry_name("category", to_category)?;
+    check_entry_name("command", to_name)?;
+    let moving_in_place = category == to_category && command == to_name;
+    if !moving_in_place && palette.commands.get(to_category).is_some_and(|c| c.contains_key(to_name)) {
+        return Err(AppError::already_exists(format!("Command '{}' already exists in category '{}' of palette '{}'.", to_name, to_category, palette.name)));
+    }
+
+    let not_found = command_not_found(palette, category, command);
+    let value = palette.commands.get_mut(category)
+        .and_then(|commands| commands.shift_remove(command))
+        .ok_or(not_found)?;
+
+    let target = palette.commands.entry(to_category.to_string()).or_default();
+    let index = index.unwrap_or(target.len()).min(target.len());
+    target.shift_insert(index, to_name.to_string(), value);
+
+    let annotation = set_command_annotation_unchecked(palette, category, command, None);
+    set_command_annotation_unchecked(palette, to_category, to_name, annotation);
+    Ok(())
+}
+
+// Replaces a category's annotation; an empty annotation removes it
+pub fn set_category_annotation(palette: &mut Palette, category: &str, annotation: Annotation) -> Result<(), AppError> {
+    if !palette.commands.contains_key(category) {
+        return Err(category_not_found(palette, category));
+    }
+    if annotation.is_empty() {
+        palette.meta.categories.shift_remove(category);
+    } else {
+        palette.meta.categories.insert(category.to_string(), annotation);
+    }
+    Ok(())
+}
+
+// Replaces a command's annotation; an empty annotation removes it
+pub fn set_command_annotation(palette: &mut Palette, category: &str, command: &str, annotation: Annotation) -> Result<(), AppError> {
+    get_command(palette, category, command)?;
+    set_command_annotation_unchecked(palette, category, command, Some(annotation).filter(|a| !a.is_empty()));
+    Ok(())
+}
+
+// Sets or removes a command's annotation and returns the previous one
+fn set_command_annotation_unchecked(palette: &mut Palette, category: &str, command: &str, annotation: Option<Annotation>) -> Option<Annotation> {
+    match annotation {
+        Some(annotation) => palette.meta.commands.entry(category.to_string()).or_default().insert(command.to_string(), annotation),
+        None => {
+            let annotations = palette.meta.commands.get_mut(category)?;
+            let previous = annotations.shift_remove(command);
+            if annotations.is_empty() {
+                palette.meta.commands.shift_remove(category);
+            }
+            previous
+        }
+    }
+}
+
+// Drops annotations of categories and commands that no longer exist, e.g. after the
+// whole command map was replaced
+pub fn prune_annotations(palette: &mut Palette) {
+    let commands = &palette.commands;
+    palette.meta.categories.retain(|category, _| commands.contains_key(category));
+    palette.meta.commands.retain(|category, annotations| {
+        annotations.retain(|command, _| commands.get(category).is_some_and(|c| c.contains_key(command)));
+        !annotations.is_empty()
+    });
+}
+
+// Keeps only the commands matching the filter, together with their annotations.
+// Categories left without commands are dropped.
+pub fn filter_palette(palette: &Palette, filter: &PaletteFilterQuery) -> Palette {
+    let mut filtered = Palette { commands: IndexMap::new(), ..palette.clone() };
+    for (category, commands) in &palette.commands {
+        let category_annotation = palette.meta.category(category);
+        let kept: IndexMap<String, JsonValue> = commands.iter()
+            .filter(|(command, _)| {
+                let command_annotation = palette.meta.command(category, command);
+                // Tags and the danger flag of a category apply to all of its commands
+                let tag_match = filter.tag.as_deref().is_none_or(|tag| {
+                    [category_annotation, command_annotation].into_iter().flatten().any(|a| a.has_tag(tag))
+                });
+                let danger = [category_annotation, command_annotation].into_iter().flatten().any(|a| a.danger);
+                tag_match && filter.danger.is_none_or(|wanted| wanted == danger)
+            })
+            .map(|(command, value)| (command.clone(), value.clone()))
+            .collect();
+        if !kept.is_empty() {
+            filtered.commands.insert(category.clone(), kept);
+        }
+    }
+    prune_annotations(&mut filtered);
+    filtered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::error::ErrorCode;
+    use crate::test_support::palette;
+
+    fn categories(palette: &Palette) -> Vec<&str> {
+        palette.commands.keys().map(String::as_str).collect()
+    }
+
+    fn commands<'a>(palette: &'a Palette, category: &str) -> Vec<&'a str> {
+        palette.commands[category].keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        // The examples from RFC 7386, appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": "b", "b": "c"}), json!({"a": null}), json!({"b": "c"})),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}}), json!({"a": {"b": "d"}})),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b", "c": null}), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ];
+        for (target, patch, expected) in cases {
+            let mut patched = target.clone();
+            merge_patch(&mut patched, &patch);
+            assert_eq!(patched, expected, "{} patched with {}", target, patch);
+        }
+    }
+
+    #[test]
+    fn patch_command_edits_the_stored_command() {
+        let mut p = palette(json!({ "Power": { "On": { "cmd": "on", "args": [1] } } }));
+        let patched = patch_command(&mut p, "Power", "On", &json!({ "args": null, "force": true })).unwrap();
+        assert_eq!(patched, json!({ "cmd": "on", "force": true }));
+        assert_eq!(p.commands["Power"]["On"], patched);
+        assert!(patch_command(&mut p, "Power", "Off", &json!({})).unwrap_err().is(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn categories_can_be_created_renamed_reordered_and_deleted() {
+        let mut p = palette(json!({ "A": {}, "B": { "x": 1 } }));
+        create_category(&mut p, "C", Some(1)).unwrap();
+        assert_eq!(categories(&p), ["A", "C", "B"]);
+        assert!(create_category(&mut p, "A", None).unwrap_err().is(ErrorCode::AlreadyExists));
+        assert!(create_category(&mut p, "  ", None).unwrap_err().is(ErrorCode::InvalidRequest));
+
+        rename_category(&mut p, "B", "D").unwrap();
+        assert_eq!(categories(&p), ["A", "C", "D"]);
+        assert_eq!(p.commands["D"]["x"], json!(1));
+        assert!(rename_category(&mut p, "A", "C").unwrap_err().is(ErrorCode::AlreadyExists));
+        assert!(rename_category(&mut p, "Z", "Y").unwrap_err().is(ErrorCode::NotFound));
+
+        reorder_categories(&mut p, &["D".into(), "A".into(), "C".into()]).unwrap();
+        assert_eq!(categories(&p), ["D", "A", "C"]);
+        assert!(reorder_categories(&mut p, &["D".into(), "A".into()]).is_err());
+        assert!(reorder_categories(&mut p, &["D".into(), "D".into(), "A".into()]).is_err());
+
+        delete_category(&mut p, "A").unwrap();
+        assert_eq!(categories(&p), ["D", "C"]);
+        assert!(delete_category(&mut p, "A").unwrap_err().is(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn commands_can_be_put_moved_and_deleted() {
+        let mut p = palette(json!({ "A": { "one": 1, "two": 2 } }));
+        assert!(put_command(&mut p, "B", "three", json!(3)).unwrap());
+        assert!(!put_command(&mut p, "B", "three", json!(33)).unwrap());
+        assert_eq!(get_command(&p, "B", "three").unwrap(), &json!(33));
+
+        move_command(&mut p, "A", "two", "A", "two", Some(0)).unwrap();
+        assert_eq!(commands(&p, "A"), ["two", "one"]);
+        move_command(&mut p, "A", "one", "B", "uno", Some(0)).unwrap();
+        assert_eq!(commands(&p, "A"), ["two"]);
+        assert_eq!(commands(&p, "B"), ["uno", "three"]);
+        assert!(move_command(&mut p, "A", "two", "B", "uno", None).unwrap_err().is(ErrorCode::AlreadyExists));
+
+        delete_command(&mut p, "A", "two", false).unwrap();
+        assert!(p.commands["A"].is_empty());
+        delete_command(&mut p, "B", "uno", true).unwrap();
+        delete_command(&mut p, "B", "three", true).unwrap();
+        assert_eq!(categories(&p), ["A"]);
+        assert!(delete_command(&mut p, "A", "two", true).unwrap_err().is(ErrorCode::NotFound));
+    }
+
+    fn annotated() -> Palette {
+        serde_json::from_value(json!({
+            "name": "Test",
+            "commands": { "Power": { "On": {}, "Off": {} }, "Info": { "Ver": {} } },
+            "meta": {
+                "categories": { "Power": { "tags": ["HW"], "warning": "arm first" } },
+                "commands": { "Power": { "Off": { "danger": true, "warning": "cuts power" } }, "Info": { "Ver": { "tags": ["read"] } } },
+            },
+        })).unwrap()
+    }
+
+    fn note(description: &str) -> Annotation {
+        Annotation { description: Some(description.into()), ..Default::default() }
+    }
+
+    #[test]
+    fn annotations_follow_their_category_and_command() {
+        let mut p = annotated();
+        rename_category(&mut p, "Power", "PSU").unwrap();
+        assert!(p.meta.category("PSU").unwrap().has_tag("hw"));
+        assert!(p.meta.command("PSU", "Off").unwrap().danger);
+
+        move_command(&mut p, "PSU", "Off", "Info", "Shutdown", None).unwrap();
+        assert!(p.meta.command("PSU", "Off").is_none());
+        assert!(p.meta.command("Info", "Shutdown").unwrap().danger);
+        assert!(!p.meta.commands.contains_key("PSU"));
+
+        delete_command(&mut p, "Info", "Shutdown", false).unwrap();
+        assert!(p.meta.command("Info", "Shutdown").is_none());
+        delete_category(&mut p, "PSU").unwrap();
+        assert!(p.meta.category("PSU").is_none());
+    }
+
+    #[test]
+    fn empty_annotations_remove_the_note() {
+        let mut p = annotated();
+        set_command_annotation(&mut p, "Power", "On", note("turns it on")).unwrap();
+        assert_eq!(p.meta.command("Power", "On"), Some(&note("turns it on")));
+        set_command_annotation(&mut p, "Power", "On", Annotation::default()).unwrap();
+        assert!(p.meta.command("Power", "On").is_none());
+        set_category_annotation(&mut p, "Power", Annotation::default()).unwrap();
+        assert!(p.meta.category("Power").is_none());
+        assert!(set_command_annotation(&mut p, "Power", "Nope", note("x")).unwrap_err().is(ErrorCode::NotFound));
+        assert!(set_category_annotation(&mut p, "Nope", note("x")).unwrap_err().is(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn warnings_and_danger_fall_back_to_the_category() {
+        let p = annotated();
+        assert_eq!(p.meta.warning("Power", "Off"), Some("cuts power"));
+        assert_eq!(p.meta.warning("Power", "On"), Some("arm first"));
+        assert_eq!(p.meta.warning("Info", "Ver"), None);
+        assert!(p.meta.is_dangerous("Power", "Off"));
+        assert!(!p.meta.is_dangerous("Power", "On"));
+    }
+
+    #[test]
+    fn pruning_drops_notes_of_missing_entries() {
+        let mut p = annotated();
+        p.commands.shift_remove("Info");
+        p.commands["Power"].shift_remove("Off");
+        prune_annotations(&mut p);
+        assert!(p.meta.category("Power").is_some());
+        assert!(p.meta.commands.is_empty());
+    }
+
+    #[test]
+    fn filters_match_the_command_or_its_category() {
+        let p = annotated();
+        let filter = |tag: Option<&str>, danger: Option<bool>| {
+            let filtered = filter_palette(&p, &PaletteFilterQuery { tag: tag.map(str::to_string), danger });
+            filtered.commands.iter()
+                .flat_map(|(category, commands)| commands.keys().map(move |command| format!("{}/{}", category, command)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(filter(Some("hw"), None), ["Power/On", "Power/Off"]);
+        assert_eq!(filter(Some("READ"), None), ["Info/Ver"]);
+        assert_eq!(filter(None, Some(true)), ["Power/Off"]);
+        assert_eq!(filter(Some("hw"), Some(false)), ["Power/On"]);
+        assert!(filter(Some("none"), None).is_empty());
+        // Notes of filtered-out commands go with them
+        let filtered = filter_palette(&p, &PaletteFilterQuery { tag: Some("read".into()), danger: None });
+        assert!(filtered.meta.category("Power").is_none());
+    }
+
+    #[test]
+    fn placeholders_are_filled_by_pointer() {
+        let command = json!({ "cmd": "set", "params": { "id": "%01", "a/b": "%x" }, "list": ["%02"] });
+        let values: IndexMap<String, JsonValue> = [
+            ("/params/id".to_string(), json!(5)),
+            ("/params/a~1b".to_string(), json!("text")),
+            ("/list/0".to_string(), json!([1])),
+        ].into_iter().collect();
+        assert_eq!(
+            fill_placeholders(&command, &values).unwrap(),
+            json!({ "cmd": "set", "params": { "id": 5, "a/b": "text" }, "list": [[1]] })
+        );
+        assert_eq!(fill_placeholders(&command, &IndexMap::new()).unwrap(), command);
+    }
+
+    #[test]
+    fn only_placeholders_can_be_filled() {
+        let command = json!({ "cmd": "set", "n": 1, "id": "%01" });
+        for pointer in ["/cmd", "/n", "/missing", ""] {
+            let values: IndexMap<String, JsonValue> = [(pointer.to_string(), json!("x"))].into_iter().collect();
+            assert!(fill_placeholders(&command, &values).unwrap_err().is(ErrorCode::InvalidRequest), "{:?}", pointer);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::types::Palette;
+use crate::error::{AppError, ErrorCode};
+
+// File formats palettes can be exported to and imported from.
+// Categories and commands keep their order in every format.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PaletteFormat {
+    #[default]
+    Json,
+    #[serde(alias = "yml")]
+    Yaml,
+    Toml,
+    // One row per command: category, command, command as compact JSON
+    Csv,
+}
+
+const CSV_HEADER: [&str; 3] = ["category", "command", "json"];
+
+impl PaletteFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            PaletteFormat::Json => "json",
+            PaletteFormat::Yaml => "yaml",
+            PaletteFormat::Toml => "toml",
+            PaletteFormat::Csv => "csv",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PaletteFormat::Json => "application/json",
+            PaletteFormat::Yaml => "application/yaml",
+            PaletteFormat::Toml => "application/toml",
+            PaletteFormat::Csv => "text/csv",
+        }
+    }
+
+    // Picks the format from a file's extension; returns None for files that are not palettes
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "json" => Some(PaletteFormat::Json),
+            "yaml" | "yml" => Some(PaletteFormat::Yaml),
+            "toml" => Some(PaletteFormat::Toml),
+            "csv" => Some(PaletteFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn serialize(self, palette: &Palette) -> Result<String, AppError> {
+        let serialized = match self {
+            PaletteFormat::Json => serde_json::to_string_pretty(palette).map_err(|e| e.to_string()),
+            PaletteFormat::Yaml => serde_norway::to_string(palette).map_err(|e| e.to_string()),
+            PaletteFormat::Toml => {
+                check_no_nulls(palette)?;
+                toml::to_string_pretty(palette).map_err(|e| e.to_string())
+            }
+            PaletteFormat::Csv => palette_to_csv(palette),
+        };
+        serialized.map_err(|e| AppError::internal(format!("Failed to serialize palette '{}': {}", palette.name, e)))
+    }
+
+    // Parses a palette file into the same JSON value a .json file would give,
+    // so imports in every format go through the same checks.
+    pub fn parse(self, contents: &[u8]) -> Result<JsonValue, String> {
+        match self {
+            PaletteFormat::Json => serde_json::from_slice(contents).map_err(|e| format!("invalid JSON: {}", e)),
+            PaletteFormat::Yaml => serde_norway::from_slice(contents).map_err(|e| format!("invalid YAML: {}", e)),
+            PaletteFormat::Toml => {
+                let text = std::str::from_utf8(contents).map_err(|e| format!("invalid TOML: {}", e))?;
+                toml::from_str(text).map_err(|e| format!("invalid TOML: {}", e))
+            }
+            PaletteFormat::Csv => csv_to_commands(contents),
+        }
+    }
+}
+
+// TOML has no null; say where one is instead of failing with a generic serializer error
+fn check_no_nulls(palette: &Palette) -> Result<(), AppError> {
+    fn contains_null(value: &JsonValue) -> bool {
+        match value {
+            JsonValue::Null => true,
+            JsonValue::Array(items) => items.iter().any(contains_null),
+            JsonValue::Object(map) => map.values().any(contains_null),
+            _ => false,
+        }
+    }
+    for (category, commands) in &palette.commands {
+        for (command, value) in commands {
+            if contains_null(value) {
+                return Err(AppError::new(ErrorCode::Unrepresentable, format!(
+                    "Command '{}' in category '{}' contains null, which TOML cannot represent.",
+                    command, category
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Empty categories get a row with no command so they survive a round trip
+fn palette_to_csv(palette: &Palette) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(CSV_HEADER).map_err(|e| e.to_string())?;
+    for (category, commands) in &palette.commands {
+        if commands.is_empty() {
+            writer.write_record([category.as_str(), "", ""]).map_err(|e| e.to_string())?;
+        }
+        for (command, value) in commands {
+            let json = serde_json::to_string(value).map_err(|e| e.to_string())?;
+            writer.write_record([category.as_str(), command.as_str(), json.as_str()]).map_err(|e| e.to_string())?;
+        }
+    }
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+// Rebuilds the category map from CSV rows. The file carries no palette name.
+fn csv_to_commands(contents: &[u8]) -> Result<JsonValue, String> {
+    let mut reader = csv::ReaderBuilder::new().from_reader(contents);
+    let header = reader.headers().map_err(|e| format!("invalid CSV: {}", e))?;
+    if !header.iter().map(str::trim).eq(CSV_HEADER.iter().copied()) {
+        return Err(format!("invalid CSV: the header must be \"{}\".", CSV_HEADER.join(",")));
+    }
+
+    let mut categories = serde_json::Map::new();
+    for (i, record) in reader.records().enumerate() {
+        // Row 1 is the header
+        let row = i + 2;
+        let record = record.map_err(|e| format!("invalid CSV: {}", e))?;
+        let (category, command, json) = (&record[0], &record[1], &record[2]);
+        let commands = categories.entry(category.to_string())
+            .or_insert_with(|| JsonValue::Object(serde_json::Map::new()));
+        if command.is_empty() && json.is_empty() {
+            continue;
+        }
+        let value: JsonValue = serde_json::from_str(json)
+            .map_err(|e| format!("invalid CSV: row {} does not hold valid JSON in the json column: {}", row, e))?;
+        if let Some(commands) = commands.as_object_mut() {
+            commands.insert(command.to_string(), value);
+        }
+    }
+    Ok(JsonValue::Object(categories))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample() -> Palette {
+        serde_json::from_value(json!({
+            "name": "Round Trip",
+            "commands": {
+                "Zeta": { "B": { "cmd": "b", "args": [1, 2.5, "x"], "flag": true }, "A": { "nested": { "k": "%01" } } },
+                "Alpha": { "Only": { "cmd": "ünïcode, \"quoted\"" } },
+                "Empty": {},
+            },
+            "meta": { "categories": { "Zeta": { "description": "last first" } } },
+        })).unwrap()
+    }
+
+    #[test]
+    fn structured_formats_round_trip_with_order() {
+        let palette = sample();
+        for format in [PaletteFormat::Json, PaletteFormat::Yaml, PaletteFormat::Toml] {
+            let text = format.serialize(&palette).unwrap();
+            let parsed = format.parse(text.as_bytes()).unwrap();
+            assert_eq!(parsed, serde_json::to_value(&palette).unwrap(), "{:?}:\n{}", format, text);
+            // Equal maps can still differ in order
+            let categories: Vec<&String> = parsed["commands"].as_object().unwrap().keys().collect();
+            assert_eq!(categories, ["Zeta", "Alpha", "Empty"], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn csv_round_trips_the_commands() {
+        let palette = sample();
+        let text = PaletteFormat::Csv.serialize(&palette).unwrap();
+        assert!(text.starts_with("category,command,json\n"));
+        assert_eq!(PaletteFormat::Csv.parse(text.as_bytes()).unwrap(), serde_json::to_value(&palette.commands).unwrap());
+    }
+
+    #[test]
+    fn csv_needs_its_header_and_valid_json() {
+        assert!(PaletteFormat::Csv.parse(b"a,b,c\n").unwrap_err().contains("header"));
+        let error = PaletteFormat::Csv.parse(b"category,command,json\nA,B,{oops\n").unwrap_err();
+        assert!(error.contains("row 2"), "{}", error);
+    }
+
+    #[test]
+    fn toml_refuses_nulls() {
+        let palette: Palette = serde_json::from_value(json!({ "name": "N", "commands": { "A": { "B": { "x": [null] } } } })).unwrap();
+        assert!(PaletteFormat::Toml.serialize(&palette).unwrap_err().is(ErrorCode::Unrepresentable));
+        PaletteFormat::Yaml.serialize(&palette).unwrap();
+    }
+
+    #[test]
+    fn formats_are_picked_by_extension() {
+        assert_eq!(PaletteFormat::from_file_name("a.YML"), Some(PaletteFormat::Yaml));
+        assert_eq!(PaletteFormat::from_file_name("dir.v2/a.toml"), Some(PaletteFormat::Toml));
+        assert_eq!(PaletteFormat::from_file_name("a.txt"), None);
+        assert_eq!(PaletteFormat::from_file_name("json"), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::types::{Palette, PaletteChange, PaletteRevision, RevisionInfo, PaletteDiff, CommandRef, CommandChange};
+use crate::palette_name::PaletteName;
+use crate::error::AppError;
+
+const HISTORY_DIR_NAME: &str = ".history";
+
+pub fn history_dir_for(dir: &Path, name: &PaletteName) -> PathBuf {
+    dir.join(HISTORY_DIR_NAME).join(name.file_stem())
+}
+
+fn revision_path(history_dir: &Path, rev: u64) -> PathBuf {
+    history_dir.join(format!("{:06}.json", rev))
+}
+
+// Revision numbers present on disk, in ascending order
+fn revision_numbers(history_dir: &Path) -> Result<Vec<u64>, AppError> {
+    if !history_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut revs = Vec::new();
+    for entry in fs::read_dir(history_dir).map_err(|e| AppError::internal(format!("Failed to read history directory: {}", e)))? {
+        let entry = entry.map_err(|e| AppError::internal(format!("Failed to read directory entry: {}", e)))?;
+        let file_name = entry.file_name();
+        if let Some(rev) = file_name.to_str().and_then(|n| n.strip_suffix(".json")).and_then(|n| n.parse::<u64>().ok()) {
+            revs.push(rev);
+        }
+    }
+    revs.sort_unstable();
+    Ok(revs)
+}
+
+pub fn has_history(dir: &Path, name: &PaletteName) -> Result<bool, AppError> {
+    Ok(!revision_numbers(&history_dir_for(dir, name))?.is_empty())
+}
+
+// Appends a new revision holding `palette` and returns its metadata
+pub fn record_revision(dir: &Path, palette: &Palette, change: &PaletteChange) -> Result<RevisionInfo, AppError> {
+    let history_dir = history_dir_for(dir, &palette.name);
+    fs::create_dir_all(&history_dir).map_err(|e| AppError::internal(format!("Failed to create history directory: {}", e)))?;
+
+    let mut rev = revision_numbers(&history_dir)?.last().copied().unwrap_or(0) + 1;
+    loop {
+        let revision = PaletteRevision {
+            info: RevisionInfo {
+                rev,
+                author: change.author.clone(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                message: change.message.clone(),
+            },
+            palette: palette.clone(),
+        };
+        let contents = serde_json::to_string_pretty(&revision).map_err(|e| AppError::internal(format!("Failed to serialize revision: {}", e)))?;
+        // create_new so two concurrent saves never claim the same revision number
+        match OpenOptions::new().write(true).create_new(true).open(revision_path(&history_dir, rev)) {
+            Ok(mut file) => {
+                file.write_all(contents.as_bytes()).map_err(|e| AppError::internal(format!("Failed to write revision file: {}", e)))?;
+                file.sync_all().map_err(|e| AppError::internal(format!("Failed to flush revision file to disk: {}", e)))?;
+                return Ok(revision.info);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => rev += 1,
+            Err(e) => return Err(AppError::internal(format!("Failed to create revision file: {}", e))),
+        }
+    }
+}
+
+// Lists the revisions of a palette, newest first
+pub fn list_revisions(dir: &Path, name: &PaletteName) -> Result<Vec<RevisionInfo>, AppError> {
+    let history_dir = history_dir_for(dir, name);
+    let mut revisions = Vec::new();
+    for rev in revision_numbers(&history_dir)?.into_iter().rev() {
+        revisions.push(load_revision_from(&history_dir, name, rev)?.info);
+    }
+    Ok(revisions)
+}
+
+pub fn latest_revision_number(dir: &Path, name: &PaletteName) -> Result<Option<u64>, AppError> {
+    Ok(revision_numbers(&history_dir_for(dir, name))?.last().copied())
+}
+
+pub fn load_revision(dir: &Path, name: &PaletteName, rev: u64) -> Result<PaletteRevision, AppError> {
+    load_revision_from(&history_dir_for(dir, name), name, rev)
+}
+
+fn load_revision_from(history_dir: &Path, name: &PaletteName, rev: u64) -> Result<PaletteRevision, AppError> {
+    let path = revision_path(history_dir, rev);
+    if !path.exists() {
+        return Err(AppError::not_found(format!("Revision {} of palette '{}' not found.", rev, name)));
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| AppError::internal(format!("Failed to read revision file: {}", e)))?;
+    serde_json::from_str(&contents).map_err(|e| AppError::internal(format!("Failed to parse revision JSON: {}", e)))
+}
+
+// Structural diff between two versions of a palette: which categories and commands
+// were added or removed, and which commands kept their name but changed content.
+pub fn diff_palettes(from_rev: u64, from: &Palette, to_rev: u64, to: &Palette) -> PaletteDiff {
+    let mut diff = PaletteDiff {
+        from: from_rev,
+        to: to_rev,
+        categories_added: Vec::new(),
+        categories_removed: Vec::new(),
+        categories_reordered: false,
+        commands_added: Vec::new(),
+        commands_removed: Vec::new(),
+        commands_changed: Vec::new(),
+    };
+
+    for (category, old_commands) in &from.commands {
+        match to.commands.get(category) {
+            None => {
+                diff.categories_removed.push(category.clone());
+                for command in old_commands.keys() {
+                    diff.commands_removed.push(CommandRef { category: category.clone(), command: command.clone() });
+                }
+            }
+            Some(new_commands) => {
+                for (command, old_value) in old_commands {
+                    match new_commands.get(command) {
+                        None => diff.commands_removed.push(CommandRef { category: category.clone(), command: command.clone() }),
+                        Some(new_value) if new_value != old_value => diff.commands_changed.push(CommandChange {
+                            category: category.clone(),
+                            command: command.clone(),
+                            before: old_value.clone(),
+                            after: new_value.clone(),
+                        }),
+                        Some(_) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    for (category, new_commands) in &to.commands {
+        let old_commands = from.commands.get(category);
+        if old_commands.is_none() {
+            diff.categories_added.push(category.clone());
+        }
+        for command in new_commands.keys() {
+            if old_commands.is_none_or(|old| !old.contains_key(command)) {
+                diff.commands_added.push(CommandRef { category: category.clone(), command: command.clone() });
+            }
+        }
+    }
+
+    // Only compare the order of categories present in both versions
+    let common_old: Vec<&String> = from.commands.keys().filter(|c| to.commands.contains_key(*c)).collect();
+    let common_new: Vec<&String> = to.commands.keys().filter(|c| from.commands.contains_key(*c)).collect();
+    diff.categories_reordered = common_old != common_new;
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_support::{palette, temp_dir};
+
+    fn refs(refs: &[CommandRef]) -> Vec<(String, String)> {
+        refs.iter().map(|r| (r.category.clone(), r.command.clone())).collect()
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_changed_commands() {
+        let from = palette(json!({
+            "Power": { "On": { "cmd": "on" }, "Off": { "cmd": "off" } },
+            "Old": { "Ping": { "cmd": "ping" } },
+        }));
+        let to = palette(json!({
+            "Power": { "On": { "cmd": "on!" }, "Reset": { "cmd": "reset" } },
+            "New": { "Status": { "cmd": "status" } },
+        }));
+        let diff = diff_palettes(1, &from, 2, &to);
+        assert_eq!((diff.from, diff.to), (1, 2));
+        assert_eq!(diff.categories_added, vec!["New"]);
+        assert_eq!(diff.categories_removed, vec!["Old"]);
+        assert_eq!(refs(&diff.commands_added), vec![("Power".into(), "Reset".into()), ("New".into(), "Status".into())]);
+        assert_eq!(refs(&diff.commands_removed), vec![("Power".into(), "Off".into()), ("Old".into(), "Ping".into())]);
+        assert_eq!(diff.commands_changed.len(), 1);
+        assert_eq!(diff.commands_changed[0].before, json!({ "cmd": "on" }));
+        assert_eq!(diff.commands_changed[0].after, json!({ "cmd": "on!" }));
+        assert!(!diff.categories_reordered);
+    }
+
+    #[test]
+    fn diff_notices_reordered_categories() {
+        let from = palette(json!({ "A": {}, "B": {}, "C": {} }));
+        let to = palette(json!({ "B": {}, "A": {} }));
+        assert!(diff_palettes(1, &from, 2, &to).categories_reordered);
+        assert!(!diff_palettes(1, &from, 1, &from).categories_reordered);
+    }
+
+    #[test]
+    fn diff_against_an_empty_palette_adds_everything() {
+        let to = palette(json!({ "Power": { "On": { "cmd": "on" } } }));
+        let diff = diff_palettes(0, &palette(json!({})), 1, &to);
+        assert_eq!(diff.categories_added, vec!["Power"]);
+        assert_eq!(refs(&diff.commands_added), vec![("Power".into(), "On".into())]);
+        assert!(diff.commands_removed.is_empty() && diff.commands_changed.is_empty());
+    }
+
+    #[test]
+    fn revisions_are_numbered_and_listed_newest_first() {
+        let dir = temp_dir();
+        let name = PaletteName::parse("Test").unwrap();
+        assert_eq!(latest_revision_number(&dir, &name).unwrap(), None);
+        for message in ["first", "second", "third"] {
+            record_revision(&dir, &palette(json!({ message: {} })), &PaletteChange::with_message(message)).unwrap();
+        }
+        let revs: Vec<(u64, Option<String>)> = list_revisions(&dir, &name).unwrap().into_iter().map(|r| (r.rev, r.message)).collect();
+        assert_eq!(revs, vec![(3, Some("third".into())), (2, Some("second".into())), (1, Some("first".into()))]);
+        assert!(load_revision(&dir, &name, 2).unwrap().palette.commands.contains_key("second"));
+        assert!(load_revision(&dir, &name, 4).unwrap_err().is(crate::error::ErrorCode::NotFound));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+use std::io::{Cursor, Read};
+use flate2::read::GzDecoder;
+use serde_json::Value as JsonValue;
+
+use crate::types::{IssueSeverity, Palette, ValidationIssue};
+use crate::palette_name::PaletteName;
+use crate::palette_formats::PaletteFormat;
+use crate::palette_validation::{has_errors, validate_palette_json};
+
+// Largest archive entry we unpack; palettes are small, anything bigger is a mistake or an attack
+const MAX_ENTRY_SIZE: u64 = 10 * 1024 * 1024;
+// Most files and bytes unpacked from one archive, so many entries just under
+// MAX_ENTRY_SIZE (a zip bomb) cannot fill memory either
+const MAX_ARCHIVE_ENTRIES: usize = 1000;
+const MAX_ARCHIVE_SIZE: u64 = 50 * 1024 * 1024;
+
+// A palette file found in an uploaded archive
+pub struct ArchiveEntry {
+    pub file_name: String,
+    pub contents: Vec<u8>,
+}
+
+fn document_error(code: &str, message: String) -> Vec<ValidationIssue> {
+    vec![ValidationIssue { severity: IssueSeverity::Error, pointer: String::new(), code: code.to_string(), message }]
+}
+
+// The palette name implied by an uploaded file's name, e.g. "Device Tests.yaml" -> "Device Tests".
+// Files exported by Commander carry escaped names, which are decoded.
+fn name_from_file_name(file_name: &str) -> Option<String> {
+    let base = file_name.rsplit(['/', '\\']).next().unwrap_or(file_name);
+    let stem = match PaletteFormat::from_file_name(base) {
+        Some(_) => base.rsplit_once('.').map_or(base, |(stem, _)| stem),
+        None => base,
+    };
+    match PaletteName::from_file_stem(stem) {
+        Some(name) => Some(name.to_string()),
+        None if !stem.is_empty() => Some(stem.to_string()),
+        None => None,
+    }
+}
+
+// Palette files come in two shapes: the {"name": ..., "commands": {...}, "meta": {...}} document written
+// by Commander, and the bare {"Category": {"Command": {...}}} map described in the README.
+// A bare map is named after its file.
+fn as_palette_document(value: JsonValue, file_name: &str) -> JsonValue {
+    let is_wrapped = value.as_object().is_some_and(|object| {
+        object.get("commands").is_some_and(JsonValue::is_object)
+            && object.keys().all(|key| key == "name" || key == "commands" || key == "meta")
+    });
+    if is_wrapped {
+        let mut document = value;
+        if document.get("name").is_none() {
+            if let Some(name) = name_from_file_name(file_name) {
+                document["name"] = JsonValue::String(name);
+            }
+        }
+        return document;
+    }
+    let mut document = serde_json::json!({ "commands": value });
+    if let Some(name) = name_from_file_name(file_name) {
+        document["name"] = JsonValue::String(name);
+    }
+    document
+}
+
+// Parses and validates an uploaded palette file in any supported format (JSON unless the
+// extension says otherwise). Fails with the validation issues if it cannot be imported as it is.
+pub fn parse_palette_file(file_name: &str, contents: &[u8]) -> Result<(Palette, Vec<ValidationIssue>), Vec<ValidationIssue>> {
+    let format = PaletteFormat::from_file_name(file_name).unwrap_or_default();
+    let value = format.parse(contents)
+        .map_err(|e| document_error("unreadable_file", format!("Failed to read '{}': {}", file_name, e)))?;
+    let document = as_palette_document(value, file_name);
+    let issues = validate_palette_json(&document);
+    if has_errors(&issues) {
+        return Err(issues);
+    }
+    let palette = serde_json::from_value(document)
+        .map_err(|e| document_error("invalid_palette", format!("Failed to read palette from '{}': {}", file_name, e)))?;
+    Ok((palette, issues))
+}
+
+// Archive formats accepted for bulk imports, recognized by their leading bytes
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_kind(contents: &[u8]) -> Option<ArchiveKind> {
+    if contents.starts_with(b"PK\x03\x04") || contents.starts_with(b"PK\x05\x06") {
+        Some(ArchiveKind::Zip)
+    } else if contents.starts_with(&[0x1f, 0x8b]) {
+        Some(ArchiveKind::TarGz)
+    } else if contents.get(257..262) == Some(b"ustar".as_slice()) {
+        Some(ArchiveKind::Tar)
+    } else {
+        None
+    }
+}
+
+// Only files in a palette format are palettes; dotfiles and macOS resource forks are skipped
+fn is_palette_entry(path: &str) -> bool {
+    PaletteFormat::from_file_name(path).is_some()
+        && !path.split(['/', '\\']).any(|part| part.starts_with('.') || part == "__MACOSX")
+}
+
+// Reads every palette file from a zip, tar or tar.gz archive.
+// Returns None if `contents` is not an archive.
+pub fn read_archive(contents: &[u8]) -> Result<Option<Vec<ArchiveEntry>>, String> {
+    let entries = match archive_kind(contents) {
+        None => return Ok(None),
+        Some(ArchiveKind::Zip) => read_zip(contents)?,
+        Some(ArchiveKind::Tar) => read_tar(contents)?,
+        Some(ArchiveKind::TarGz) => read_tar(GzDecoder::new(contents))?,
+    };
+    Ok(Some(entries))
+}
+
+fn read_zip(contents: &[u8]) -> Result<Vec<ArchiveEntry>, String> {
+    let mut budget = UnpackBudget::default();
+    let mut archive = zip::ZipArchive::new(Cursor::new(contents)).map_err(|e| format!("Failed to read zip archive: {}", e))?;
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(|e| format!("Failed to read zip entry: {}", e))?;
+        if !file.is_file() || !is_palette_entry(file.name()) {
+            continue;
+        }
+        let file_name = file.name().to_string();
+        entries.push(ArchiveEntry { contents: budget.read_entry(file, &file_name)?, file_name });
+    }
+    Ok(entries)
+}
+
+fn read_tar(reader: impl Read) -> Result<Vec<ArchiveEntry>, String> {
+    let mut budget = UnpackBudget::default();
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))? {
+        let entry = entry.map_err(|e| format!("Failed to read tar entry: {}", e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let file_name = entry.path().map_err(|e| format!("Invalid tar entry path: {}", e))?.to_string_lossy().into_owned();
+        if !is_palette_entry(&file_name) {
+            continue;
+        }
+        entries.push(ArchiveEntry { contents: budget.read_entry(entry, &file_name)?, file_name });
+    }
+    Ok(entries)
+}
+
+// What one archive may still unpack: every entry read counts against the same budget
+pub struct UnpackBudget {
+    entries_left: usize,
+    bytes_left: u64,
+}
+
+impl Default for UnpackBudget {
+    fn default() -> Self {
+        Self { entries_left: MAX_ARCHIVE_ENTRIES, bytes_left: MAX_ARCHIVE_SIZE }
+    }
+}
+
+impl UnpackBudget {
+    // Reads one archive entry, refusing entries that unpack to more than MAX_ENTRY_SIZE
+    // and archives that go over their entry count or total size
+    pub fn read_entry(&mut self, reader: impl Read, file_name: &str) -> Result<Vec<u8>, String> {
+        if self.entries_left == 0 {
+            return Err(format!("The archive has more than {} files.", MAX_ARCHIVE_ENTRIES));
+        }
+        let limit = MAX_ENTRY_SIZE.min(self.bytes_left);
+        let mut contents = Vec::new();
+        reader.take(limit + 1).read_to_end(&mut contents)
+            .map_err(|e| format!("Failed to read '{}' from archive: {}", file_name, e))?;
+        let size = contents.len() as u64;
+        if size > MAX_ENTRY_SIZE {
+            return Err(format!("'{}' in the archive is too large to be a palette.", file_name));
+        }
+        if size > self.bytes_left {
+            return Err(format!("The archive unpacks to more than {} MiB.", MAX_ARCHIVE_SIZE / (1024 * 1024)));
+        }
+        self.entries_left -= 1;
+        self.bytes_left -= size;
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use zip::write::SimpleFileOptions;
+
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, contents) in files {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *contents).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn bare_maps_are_named_after_their_file() {
+        let (palette, issues) = parse_palette_file("dir/Device%20Tests.json", br#"{"Power": {"On": {"cmd": "on"}}}"#).unwrap();
+        assert_eq!(palette.name.as_str(), "Device Tests");
+        assert!(palette.commands["Power"].contains_key("On"));
+        assert!(issues.is_empty());
+
+        let (palette, _) = parse_palette_file("x.json", br#"{"name": "Named", "commands": {}}"#).unwrap();
+        assert_eq!(palette.name.as_str(), "Named");
+    }
+
+    #[test]
+    fn invalid_files_fail_with_their_issues() {
+        let issues = parse_palette_file("x.json", b"{not json").unwrap_err();
+        assert_eq!(issues[0].code, "unreadable_file");
+        let issues = parse_palette_file("x.json", br#"{"Get Version": {"cmd": "ver"}}"#).unwrap_err();
+        assert_eq!(issues[0].code, "missing_category");
+    }
+
+    #[test]
+    fn archives_yield_only_palette_files() {
+        let files: &[(&str, &[u8])] = &[
+            ("a.json", b"{}"),
+            ("nested/b.json", b"{}"),
+            ("readme.txt", b"hi"),
+            (".hidden.json", b"{}"),
+            ("__MACOSX/a.json", b"{}"),
+        ];
+        for archive in [zip_of(files), tar_of(files)] {
+            let names: Vec<String> = read_archive(&archive).unwrap().unwrap().into_iter().map(|e| e.file_name).collect();
+            assert_eq!(names, ["a.json", "nested/b.json"]);
+        }
+        assert!(read_archive(br#"{"name": "x"}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn entries_are_capped_one_by_one() {
+        let mut budget = UnpackBudget::default();
+        let error = budget.read_entry(io::repeat(b' ').take(MAX_ENTRY_SIZE + 1), "big.json").unwrap_err();
+        assert!(error.contains("too large"), "{}", error);
+        assert_eq!(budget.read_entry(io::repeat(b' ').take(MAX_ENTRY_SIZE), "ok.json").unwrap().len() as u64, MAX_ENTRY_SIZE);
+    }
+
+    #[test]
+    fn archives_are_capped_in_total_size() {
+        let mut budget = UnpackBudget::default();
+        let entry = MAX_ENTRY_SIZE - 1;
+        for i in 0..MAX_ARCHIVE_SIZE / entry {
+            budget.read_entry(io::repeat(b' ').take(entry), &format!("{}.json", i)).unwrap();
+        }
+        let error = budget.read_entry(io::repeat(b' ').take(entry), "last.json").unwrap_err();
+        assert!(error.contains("unpacks to more than"), "{}", error);
+    }
+
+    #[test]
+    fn archives_are_capped_in_entry_count() {
+        let names: Vec<String> = (0..=MAX_ARCHIVE_ENTRIES).map(|i| format!("{}.json", i)).collect();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), b"{}".as_slice())).collect();
+        let error = read_archive(&zip_of(&files)).err().unwrap();
+        assert!(error.contains("more than 1000 files"), "{}", error);
+        assert_eq!(read_archive(&zip_of(&files[1..])).unwrap().unwrap().len(), MAX_ARCHIVE_ENTRIES);
+    }
+}
@@ -1,38 +1,122 @@
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use serde_json;
-use directories::ProjectDirs;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
-use crate::types::{Palette};
+use crate::config::{app_config, PaletteRoot};
+use crate::types::{ConflictPolicy, ImportStatus, Palette, PaletteBackup, PaletteChange};
+use crate::palette_name::PaletteName;
+use crate::palette_history::{has_history, record_revision, history_dir_for};
+use crate::palette_edit;
+use crate::palette_validation::check_valid;
+use crate::error::{AppError, ErrorCode};
 
-const PALETTE_DIR_NAME: &str = "palettes";
+const BACKUP_DIR_NAME: &str = ".backups";
+const BACKUP_EXTENSION: &str = ".json.bak";
+// Number of previous versions kept per palette
+const MAX_BACKUPS: usize = 10;
 
-// Helper function to get the application's data directory for palettes
-fn get_palette_storage_dir() -> Result<PathBuf, String> {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "RustCommander", "RustCommander") {
-        let data_dir = proj_dirs.data_dir();
-        let palette_dir = data_dir.join(PALETTE_DIR_NAME);
-        if !palette_dir.exists() {
-            fs::create_dir_all(&palette_dir)
-                .map_err(|e| format!("Failed to create palette directory: {}", e))?;
-        }
-        Ok(palette_dir)
-    } else {
-        Err("Unable to find project directories".to_string())
+// Serializes every write to the palette directory so read-modify-write cycles
+// (command edits, renames, restores) never interleave with each other.
+static PALETTE_WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+// Proof that the palette write lock is held. Every function here that writes palettes
+// takes one, so the caller can keep the lock until it has also updated whatever
+// depends on the write, like the repository's cache.
+pub struct PaletteWriteGuard {
+    _lock: MutexGuard<'static, ()>,
+}
+
+pub fn lock_palette_writes() -> PaletteWriteGuard {
+    // The guarded data is (), so a poisoned lock carries no broken state
+    PaletteWriteGuard { _lock: PALETTE_WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner()) }
+}
+
+// The configured palette root a palette name refers to
+pub fn palette_root_for(name: &PaletteName) -> Result<&'static PaletteRoot, AppError> {
+    let config = app_config();
+    match name.root() {
+        None => Ok(config.default_root()),
+        Some(root) => config.palette_root(root).ok_or_else(|| AppError::not_found(format!("Palette root '{}' not found.", root))),
+    }
+}
+
+// Directory of a palette root. Writable roots are created on first use;
+// a missing read-only root simply has no palettes.
+pub fn palette_root_dir(root: &PaletteRoot) -> Result<PathBuf, AppError> {
+    if !root.read_only && !root.path.exists() {
+        fs::create_dir_all(&root.path)
+            .map_err(|e| AppError::internal(format!("Failed to create palette directory '{}': {}", root.path.display(), e)))?;
     }
+    Ok(root.path.clone())
 }
 
-// Lists all available palettes
-pub fn list_palettes() -> Result<Vec<String>, String> {
-    let dir = get_palette_storage_dir()?;
+// Directory the given palette is stored in
+pub fn palette_dir(name: &PaletteName) -> Result<PathBuf, AppError> {
+    palette_root_dir(palette_root_for(name)?)
+}
+
+// Directory the given palette is stored in, failing if its root is read-only
+fn writable_palette_dir(name: &PaletteName) -> Result<PathBuf, AppError> {
+    let root = palette_root_for(name)?;
+    if root.read_only {
+        return Err(AppError::new(ErrorCode::ReadOnly, format!("Palette root '{}' is read-only.", root.name)));
+    }
+    palette_root_dir(root)
+}
+
+// Finds the file a palette is currently stored in, falling back to the
+// unescaped file name used by older versions.
+fn existing_palette_path(dir: &Path, name: &PaletteName) -> Option<PathBuf> {
+    let file_path = dir.join(name.file_name());
+    if file_path.exists() {
+        return Some(file_path);
+    }
+    let legacy_path = dir.join(name.legacy_file_name());
+    if legacy_path.exists() {
+        return Some(legacy_path);
+    }
+    None
+}
+
+// Modification time of a palette's file, or None if the palette does not exist
+pub fn palette_modified_time(name: &PaletteName) -> Result<Option<SystemTime>, AppError> {
+    let dir = palette_dir(name)?;
+    match existing_palette_path(&dir, name) {
+        Some(path) => fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map(Some)
+            .map_err(|e| AppError::internal(format!("Failed to read palette file metadata: {}", e))),
+        None => Ok(None),
+    }
+}
+
+// Lists all available palettes, those of the default root first.
+// Palettes in other roots are qualified with the root's name.
+pub fn list_palettes() -> Result<Vec<PaletteName>, AppError> {
+    let config = app_config();
     let mut palettes = Vec::new();
-    for entry in fs::read_dir(dir).map_err(|e| format!("Failed to read palette directory: {}", e))? {
-        let entry = entry.map_err(|e| format!("Failed to read directory entry: {}", e))?;
-        let path = entry.path();
-        if path.is_file() && path.extension().map_or(false, |ext| ext == "json") {
-            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                palettes.push(stem.to_string());
+    for root in &config.palette_roots {
+        let dir = palette_root_dir(root)?;
+        if !dir.exists() {
+            println!("Palette root '{}' does not exist: {}", root.name, dir.display());
+            continue;
+        }
+        let qualifier = config.root_qualifier(root);
+        for entry in fs::read_dir(&dir).map_err(|e| AppError::internal(format!("Failed to read palette directory: {}", e)))? {
+            let entry = entry.map_err(|e| AppError::internal(format!("Failed to read directory entry: {}", e)))?;
+            let path = entry.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    match PaletteName::from_file_stem(stem).map(|name| name.in_root(qualifier)) {
+                        Some(name) if !palettes.contains(&name) => palettes.push(name),
+                        Some(_) => {}
+                        None => println!("Skipping palette file with an invalid name: {}", path.display()),
+                    }
+                }
             }
         }
     }
@@ -40,47 +124,563 @@ pub fn list_palettes() -> Result<Vec<String>, String> {
 }
 
 // Loads a specific palette by name
-pub fn load_palette(name: &str) -> Result<Palette, String> {
-    let dir = get_palette_storage_dir()?;
-    let file_path = dir.join(format!("{}.json", name));
-    if !file_path.exists() {
-        return Err(format!("Palette '{}' not found.", name));
-    }
-    let mut file = File::open(&file_path).map_err(|e| format!("Failed to open palette file: {}", e))?;
+pub fn load_palette(name: &PaletteName) -> Result<Palette, AppError> {
+    let dir = palette_dir(name)?;
+    let file_path = match existing_palette_path(&dir, name) {
+        Some(path) => path,
+        None => return Err(AppError::not_found(format!("Palette '{}' not found.", name))),
+    };
+    let mut file = File::open(&file_path).map_err(|e| AppError::internal(format!("Failed to open palette file: {}", e)))?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents).map_err(|e| format!("Failed to read palette file: {}", e))?;
-    serde_json::from_str(&contents).map_err(|e| format!("Failed to parse palette JSON: {}", e))
+    file.read_to_string(&mut contents).map_err(|e| AppError::internal(format!("Failed to read palette file: {}", e)))?;
+    let mut palette: Palette = serde_json::from_str(&contents).map_err(|e| AppError::internal(format!("Failed to parse palette JSON: {}", e)))?;
+    // Files only store the name within their root
+    palette.name = name.clone();
+    Ok(palette)
 }
 
 // Saves a palette
-pub fn save_palette(palette: &Palette) -> Result<(), String> {
-    let dir = get_palette_storage_dir()?;
-    let file_path = dir.join(format!("{}.json", palette.name));
-    let mut file = File::create(&file_path).map_err(|e| format!("Failed to create palette file: {}", e))?;
-    let contents = serde_json::to_string_pretty(palette).map_err(|e| format!("Failed to serialize palette: {}", e))?;
-    file.write_all(contents.as_bytes()).map_err(|e| format!("Failed to write palette file: {}", e))
+// The previous version is kept as a backup and the new contents are written atomically,
+// so a crash or full disk mid-write never leaves a truncated palette behind.
+// Every successful save is recorded as a new revision in the palette's history.
+pub fn save_palette(_guard: &PaletteWriteGuard, palette: &Palette, change: &PaletteChange) -> Result<(), AppError> {
+    write_palette(palette, change)
 }
 
-// Deletes a palette by name
-pub fn delete_palette(name: &str) -> Result<(), String> {
-    let dir = get_palette_storage_dir()?;
-    let file_path = dir.join(format!("{}.json", name));
-    if !file_path.exists() {
-        return Err(format!("Palette '{}' not found for deletion.", name));
+// Loads a palette, applies `edit` to it and saves the result, all under the write lock,
+// so concurrent edits of the same palette cannot overwrite each other.
+// If `if_match` is given, the edit only happens if the stored palette still has that ETag.
+pub fn modify_palette<T>(
+    _guard: &PaletteWriteGuard,
+    name: &PaletteName,
+    change: &PaletteChange,
+    if_match: Option<&str>,
+    edit: impl FnOnce(&mut Palette) -> Result<T, AppError>,
+) -> Result<(Palette, T), AppError> {
+    let mut palette = load_palette(name)?;
+    check_if_match(&palette, if_match)?;
+    let result = edit(&mut palette)?;
+    write_palette(&palette, change)?;
+    Ok((palette, result))
+}
+
+// Version tag of a palette's content, used as its HTTP ETag.
+// Derived from the content itself so hand edits on disk also change it.
+pub fn palette_etag(palette: &Palette) -> String {
+    let bytes = serde_json::to_vec(palette).unwrap_or_default();
+    let digest = Sha256::digest(&bytes);
+    let hex: String = digest.iter().take(16).map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+// Checks an If-Match header value ("*", or a list of strong ETags) against a palette.
+// A mismatch carries the current version so the client can merge and retry.
+fn check_if_match(palette: &Palette, if_match: Option<&str>) -> Result<(), AppError> {
+    let Some(if_match) = if_match else {
+        return Ok(());
+    };
+    let current = palette_etag(palette);
+    let matches = if_match.trim() == "*" || if_match.split(',').any(|tag| tag.trim() == current);
+    if matches {
+        Ok(())
+    } else {
+        let message = format!(
+            "Palette '{}' was modified by someone else (expected version {}, current version {}).",
+            palette.name, if_match.trim(), current
+        );
+        Err(AppError::new(ErrorCode::PreconditionFailed, message)
+            .with_details(serde_json::json!({ "etag": current, "palette": palette })))
     }
-    fs::remove_file(&file_path).map_err(|e| format!("Failed to delete palette file: {}", e))
 }
 
-// Imports a palette from a temporary file path (e.g., after an upload)
-// The new_palette_name is the name to save it under, extracted from the Palette struct itself
-pub fn import_palette(temp_file_path: &Path) -> Result<Palette, String> {
-    let mut file = File::open(temp_file_path).map_err(|e| format!("Failed to open temporary palette file: {}", e))?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).map_err(|e| format!("Failed to read temporary palette file: {}", e))?;
-    
-    let palette: Palette = serde_json::from_str(&contents).map_err(|e| format!("Failed to parse uploaded palette JSON: {}", e))?;
-    
-    // Now save it using the existing save_palette function
-    save_palette(&palette)?; // This will use palette.name
+// Saves a palette; callers must hold the palette write lock
+fn write_palette(palette: &Palette, change: &PaletteChange) -> Result<(), AppError> {
+    let dir = writable_palette_dir(&palette.name)?;
+    let file_path = dir.join(palette.name.file_name());
+    // Stored without the root prefix so a palette directory can be moved or shared as a whole
+    let stored = Palette { name: palette.name.in_root(None), ..palette.clone() };
+    let contents = serde_json::to_string_pretty(&stored).map_err(|e| AppError::internal(format!("Failed to serialize palette: {}", e)))?;
+
+    if let Some(existing_path) = existing_palette_path(&dir, &palette.name) {
+        // Palettes created before history was kept get their current contents as revision 1
+        if !has_history(&dir, &palette.name)? {
+            if let Ok(existing) = load_palette(&palette.name) {
+                let existing = Palette { name: stored.name.clone(), ..existing };
+                record_revision(&dir, &existing, &PaletteChange::with_message("Existing palette before history was recorded"))?;
+            }
+        }
+        backup_palette_file(&dir, &palette.name, &existing_path)?;
+    }
+    write_atomically(&file_path, contents.as_bytes())?;
+
+    // Palettes saved by older versions live under their unescaped name; drop that copy now
+    let legacy_path = dir.join(palette.name.legacy_file_name());
+    if legacy_path != file_path && legacy_path.exists() {
+        fs::remove_file(&legacy_path).map_err(|e| AppError::internal(format!("Failed to remove legacy palette file: {}", e)))?;
+    }
+    record_revision(&dir, &stored, change)?;
+    Ok(())
+}
+
+// Writes to a temporary file in the same directory, fsyncs it and renames it over the target.
+// The rename is atomic, so readers see either the old or the new file, never a partial one.
+fn write_atomically(file_path: &Path, contents: &[u8]) -> Result<(), AppError> {
+    let dir = file_path.parent().ok_or_else(|| AppError::internal("Palette file has no parent directory"))?;
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("palette");
+    let temp_path = dir.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+
+    let write_result = (|| {
+        let mut temp_file = File::create(&temp_path).map_err(|e| AppError::internal(format!("Failed to create temporary palette file: {}", e)))?;
+        temp_file.write_all(contents).map_err(|e| AppError::internal(format!("Failed to write temporary palette file: {}", e)))?;
+        temp_file.sync_all().map_err(|e| AppError::internal(format!("Failed to flush temporary palette file to disk: {}", e)))?;
+        fs::rename(&temp_path, file_path).map_err(|e| AppError::internal(format!("Failed to move palette file into place: {}", e)))
+    })();
+    if write_result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    write_result?;
+
+    // Persist the rename itself; directories can only be opened for syncing on Unix
+    #[cfg(unix)]
+    {
+        if let Ok(dir_handle) = File::open(dir) {
+            let _ = dir_handle.sync_all();
+        }
+    }
+    Ok(())
+}
+
+fn backup_dir_for(dir: &Path, name: &PaletteName) -> PathBuf {
+    dir.join(BACKUP_DIR_NAME).join(name.file_stem())
+}
+
+// Copies the current palette file into its backup directory and prunes old backups
+fn backup_palette_file(dir: &Path, name: &PaletteName, existing_path: &Path) -> Result<(), AppError> {
+    let backup_dir = backup_dir_for(dir, name);
+    fs::create_dir_all(&backup_dir).map_err(|e| AppError::internal(format!("Failed to create backup directory: {}", e)))?;
+
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    let mut backup_id = timestamp.clone();
+    let mut counter = 1;
+    while backup_dir.join(format!("{}{}", backup_id, BACKUP_EXTENSION)).exists() {
+        backup_id = format!("{}-{}", timestamp, counter);
+        counter += 1;
+    }
+    let contents = fs::read(existing_path).map_err(|e| AppError::internal(format!("Failed to read palette file for backup: {}", e)))?;
+    write_atomically(&backup_dir.join(format!("{}{}", backup_id, BACKUP_EXTENSION)), &contents)?;
+
+    let backups = list_backup_ids(&backup_dir)?;
+    if backups.len() > MAX_BACKUPS {
+        for old_id in &backups[..backups.len() - MAX_BACKUPS] {
+            let _ = fs::remove_file(backup_dir.join(format!("{}{}", old_id, BACKUP_EXTENSION)));
+        }
+    }
+    Ok(())
+}
+
+// Backup ids in ascending (oldest first) order
+fn list_backup_ids(backup_dir: &Path) -> Result<Vec<String>, AppError> {
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(backup_dir).map_err(|e| AppError::internal(format!("Failed to read backup directory: {}", e)))? {
+        let entry = entry.map_err(|e| AppError::internal(format!("Failed to read directory entry: {}", e)))?;
+        if let Some(id) = entry.file_name().to_str().and_then(|n| n.strip_suffix(BACKUP_EXTENSION)) {
+            ids.push(id.to_string());
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// Backup ids are generated by us; anything else could point outside the backup directory
+fn is_valid_backup_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.contains("..")
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+// Lists the backups of a palette, newest first
+pub fn list_backups(name: &PaletteName) -> Result<Vec<PaletteBackup>, AppError> {
+    let dir = palette_dir(name)?;
+    let backup_dir = backup_dir_for(&dir, name);
+    let mut backups = Vec::new();
+    for id in list_backup_ids(&backup_dir)?.into_iter().rev() {
+        let metadata = fs::metadata(backup_dir.join(format!("{}{}", id, BACKUP_EXTENSION)))
+            .map_err(|e| AppError::internal(format!("Failed to read backup metadata: {}", e)))?;
+        let created_at = metadata.modified().ok().map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339());
+        backups.push(PaletteBackup { id, created_at, size_bytes: metadata.len() });
+    }
+    Ok(backups)
+}
+
+// Restores a palette from one of its backups. The version being replaced is itself backed up.
+pub fn restore_backup(_guard: &PaletteWriteGuard, name: &PaletteName, backup_id: &str) -> Result<Palette, AppError> {
+    if !is_valid_backup_id(backup_id) {
+        return Err(AppError::invalid(format!("Invalid backup id '{}'.", backup_id)));
+    }
+    let dir = writable_palette_dir(name)?;
+    let backup_path = backup_dir_for(&dir, name).join(format!("{}{}", backup_id, BACKUP_EXTENSION));
+    if !backup_path.exists() {
+        return Err(AppError::not_found(format!("Backup '{}' of palette '{}' not found.", backup_id, name)));
+    }
+    let contents = fs::read_to_string(&backup_path).map_err(|e| AppError::internal(format!("Failed to read backup file: {}", e)))?;
+    let mut palette: Palette = serde_json::from_str(&contents).map_err(|e| AppError::internal(format!("Failed to parse backup JSON: {}", e)))?;
+    palette.name = name.clone();
+    // Backups are plain files and may predate the current validation rules
+    check_valid(&palette)?;
+    write_palette(&palette, &PaletteChange::with_message(format!("Restored backup {}", backup_id)))?;
+    Ok(palette)
+}
+
+// Renames a palette: its file, backups and history all move to the new name.
+// Fails if another palette already uses the new name.
+pub fn rename_palette(_guard: &PaletteWriteGuard, old_name: &PaletteName, new_name: &PaletteName, change: &PaletteChange) -> Result<Palette, AppError> {
+    if palette_root_for(old_name)?.name != palette_root_for(new_name)?.name {
+        return Err(AppError::invalid(format!(
+            "Invalid rename: '{}' and '{}' are in different palette roots. Duplicate the palette instead.",
+            old_name, new_name
+        )));
+    }
+    let dir = writable_palette_dir(old_name)?;
+    let old_path = match existing_palette_path(&dir, old_name) {
+        Some(path) => path,
+        None => return Err(AppError::not_found(format!("Palette '{}' not found.", old_name))),
+    };
+    if let Some(new_path) = existing_palette_path(&dir, new_name) {
+        // A case-only rename on a case-insensitive filesystem finds the palette itself
+        if !is_same_file(&old_path, &new_path) {
+            return Err(AppError::already_exists(format!("Palette '{}' already exists.", new_name)));
+        }
+    }
+
+    // A deleted palette keeps its backups and history, so they can be in the way of the new
+    // name. Check both before moving either, so a failed rename leaves everything in place.
+    let moves = [
+        ("backups", backup_dir_for(&dir, old_name), backup_dir_for(&dir, new_name)),
+        ("history", history_dir_for(&dir, old_name), history_dir_for(&dir, new_name)),
+    ];
+    for (what, from, to) in &moves {
+        if is_dir_taken(from, to) {
+            return Err(AppError::already_exists(format!(
+                "Palette '{}' cannot be renamed to '{}': the {} of a deleted palette named '{}' is still in '{}'.",
+                old_name, new_name, what, new_name, to.display()
+            )));
+        }
+    }
+
+    let mut palette = load_palette(old_name)?;
+    palette.name = new_name.clone();
+
+    // The history moves first, as the write records its revision under the new name
+    let new_path = dir.join(new_name.file_name());
+    let dirs: Vec<_> = moves.iter().map(|(_, from, to)| (from.clone(), to.clone())).collect();
+    if let Err(error) = with_dirs_moved(&dirs, || write_palette(&palette, change)) {
+        if new_path.exists() && !is_same_file(&old_path, &new_path) {
+            let _ = fs::remove_file(&new_path);
+        }
+        return Err(error);
+    }
+
+    if old_path.exists() && !is_same_file(&old_path, &new_path) {
+        fs::remove_file(&old_path).map_err(|e| AppError::internal(format!("Failed to remove old palette file: {}", e)))?;
+    }
+    Ok(palette)
+}
+
+// Copies a palette under a new name. The copy starts with a fresh history.
+pub fn duplicate_palette(_guard: &PaletteWriteGuard, source_name: &PaletteName, new_name: &PaletteName, change: &PaletteChange) -> Result<Palette, AppError> {
+    let dir = writable_palette_dir(new_name)?;
+    if existing_palette_path(&dir, new_name).is_some() {
+        return Err(AppError::already_exists(format!("Palette '{}' already exists.", new_name)));
+    }
+    // Backups or history left by a deleted palette of the new name would become the copy's
+    let source_dir = palette_dir(source_name)?;
+    let leftovers = [
+        ("backups", backup_dir_for(&source_dir, source_name), backup_dir_for(&dir, new_name)),
+        ("history", history_dir_for(&source_dir, source_name), history_dir_for(&dir, new_name)),
+    ];
+    for (what, source, to) in &leftovers {
+        if is_dir_taken(source, to) {
+            return Err(AppError::already_exists(format!(
+                "Palette '{}' cannot be duplicated as '{}': the {} of a deleted palette named '{}' is still in '{}'.",
+                source_name, new_name, what, new_name, to.display()
+            )));
+        }
+    }
+    let mut palette = load_palette(source_name)?;
+    palette.name = new_name.clone();
+    write_palette(&palette, change)?;
     Ok(palette)
-} 
\ No newline at end of file
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Whether `to` already holds another palette's directory; a case-only rename on a
+// case-insensitive filesystem finds the palette's own directory there
+fn is_dir_taken(from: &Path, to: &Path) -> bool {
+    to.exists() && !is_same_file(from, to)
+}
+
+// Renames a per-palette directory (backups, history). Never merges into or replaces
+// a directory that is already there.
+fn move_dir_if_present(from: &Path, to: &Path) -> Result<(), AppError> {
+    if is_dir_taken(from, to) {
+        return Err(AppError::already_exists(format!("Cannot move '{}': '{}' already exists.", from.display(), to.display())));
+    }
+    if from.exists() {
+        fs::rename(from, to).map_err(|e| AppError::internal(format!("Failed to move '{}': {}", from.display(), e)))?;
+    }
+    Ok(())
+}
+
+// Moves each directory, then runs `write`. If a move or the write fails, the directories
+// already moved are moved back, so a failed rename leaves the backups and history in place.
+fn with_dirs_moved<T>(dirs: &[(PathBuf, PathBuf)], write: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    let mut moved = Vec::new();
+    let result = dirs
+        .iter()
+        .try_for_each(|(from, to)| {
+            let present = from.exists();
+            move_dir_if_present(from, to)?;
+            if present {
+                moved.push((from, to));
+            }
+            Ok(())
+        })
+        .and_then(|()| write());
+    if result.is_err() {
+        for (from, to) in moved.into_iter().rev() {
+            let _ = fs::rename(to, from);
+        }
+    }
+    result
+}
+
+// Deletes a palette by name
+// Its backups are left in place so a deleted palette can still be restored.
+pub fn delete_palette(_guard: &PaletteWriteGuard, name: &PaletteName, if_match: Option<&str>) -> Result<(), AppError> {
+    let dir = writable_palette_dir(name)?;
+    let file_path = match existing_palette_path(&dir, name) {
+        Some(path) => path,
+        None => return Err(AppError::not_found(format!("Palette '{}' not found for deletion.", name))),
+    };
+    if if_match.is_some() {
+        check_if_match(&load_palette(name)?, if_match)?;
+    }
+    fs::remove_file(&file_path).map_err(|e| AppError::internal(format!("Failed to delete palette file: {}", e)))
+}
+
+// Saves an imported palette, resolving a clash with an existing palette of the same
+// name according to `policy`. Returns the palette as stored and what was done with it.
+pub fn import_palette(
+    _guard: &PaletteWriteGuard,
+    mut palette: Palette,
+    policy: ConflictPolicy,
+    change: &PaletteChange,
+) -> Result<(Palette, ImportStatus), AppError> {
+    let dir = writable_palette_dir(&palette.name)?;
+    if existing_palette_path(&dir, &palette.name).is_none() {
+        write_palette(&palette, change)?;
+        return Ok((palette, ImportStatus::Created));
+    }
+
+    let status = match policy {
+        ConflictPolicy::Reject => return Err(AppError::already_exists(format!("Palette '{}' already exists.", palette.name))),
+        ConflictPolicy::Overwrite => ImportStatus::Overwritten,
+        ConflictPolicy::Rename => {
+            palette.name = free_palette_name(&dir, &palette.name)?;
+            ImportStatus::Renamed
+        }
+        ConflictPolicy::Merge => {
+            let mut existing = load_palette(&palette.name)?;
+            for (category, commands) in palette.commands {
+                existing.commands.entry(category).or_default().extend(commands);
+            }
+            existing.meta.categories.extend(palette.meta.categories);
+            for (category, annotations) in palette.meta.commands {
+                existing.meta.commands.entry(category).or_default().extend(annotations);
+            }
+            // Validation only saw the incoming palette; the merge can introduce clashes of its own
+            palette_edit::prune_annotations(&mut existing);
+            check_valid(&existing)?;
+            palette = existing;
+            ImportStatus::Merged
+        }
+    };
+    write_palette(&palette, change)?;
+    Ok((palette, status))
+}
+
+// First of "Name (2)", "Name (3)", ... that no palette uses yet
+fn free_palette_name(dir: &Path, name: &PaletteName) -> Result<PaletteName, AppError> {
+    for n in 2.. {
+        let candidate = PaletteName::parse(&format!("{} ({})", name, n)).map_err(AppError::invalid)?;
+        if existing_palette_path(dir, &candidate).is_none() {
+            return Ok(candidate);
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{palette, temp_dir};
+
+    #[test]
+    fn write_atomically_replaces_the_file_and_leaves_no_temp_files() {
+        let dir = temp_dir();
+        let path = dir.join("p.json");
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backups_are_pruned_to_the_newest() {
+        let dir = temp_dir();
+        let name = PaletteName::parse("My Palette").unwrap();
+        let path = dir.join(name.file_name());
+        for i in 0..MAX_BACKUPS + 3 {
+            fs::write(&path, i.to_string()).unwrap();
+            backup_palette_file(&dir, &name, &path).unwrap();
+        }
+        let backup_dir = backup_dir_for(&dir, &name);
+        let ids = list_backup_ids(&backup_dir).unwrap();
+        assert_eq!(ids.len(), MAX_BACKUPS);
+        let newest = fs::read_to_string(backup_dir.join(format!("{}{}", ids[MAX_BACKUPS - 1], BACKUP_EXTENSION))).unwrap();
+        assert_eq!(newest, (MAX_BACKUPS + 2).to_string());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn moving_a_dir_never_merges_into_an_existing_one() {
+        let dir = temp_dir();
+        let (from, to) = (dir.join("old"), dir.join("new"));
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("000001.json"), "mine").unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("000001.json"), "deleted palette").unwrap();
+
+        assert!(is_dir_taken(&from, &to));
+        assert!(move_dir_if_present(&from, &to).unwrap_err().is(ErrorCode::AlreadyExists));
+        assert_eq!(fs::read_to_string(from.join("000001.json")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(to.join("000001.json")).unwrap(), "deleted palette");
+
+        fs::remove_dir_all(&to).unwrap();
+        move_dir_if_present(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(to.join("000001.json")).unwrap(), "mine");
+        // Nothing to move is fine
+        move_dir_if_present(&from, &dir.join("other")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_move_the_dirs_back() {
+        let dir = temp_dir();
+        let dirs = [(dir.join("old-backups"), dir.join("new-backups")), (dir.join("old-history"), dir.join("new-history"))];
+        for (from, _) in &dirs {
+            fs::create_dir_all(from).unwrap();
+        }
+
+        let failed: Result<(), AppError> = with_dirs_moved(&dirs, || Err(AppError::internal("disk full")));
+        assert!(failed.unwrap_err().is(ErrorCode::Internal));
+        for (from, to) in &dirs {
+            assert!(from.exists() && !to.exists());
+        }
+
+        // A move that fails part way undoes the ones before it
+        fs::create_dir_all(&dirs[1].1).unwrap();
+        let blocked = with_dirs_moved(&dirs, || Ok(()));
+        assert!(blocked.unwrap_err().is(ErrorCode::AlreadyExists));
+        assert!(dirs[0].0.exists() && !dirs[0].1.exists());
+        fs::remove_dir_all(&dirs[1].1).unwrap();
+
+        with_dirs_moved(&dirs, || Ok(())).unwrap();
+        for (from, to) in &dirs {
+            assert!(!from.exists() && to.exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicates_do_not_take_over_a_deleted_palettes_history() {
+        let guard = lock_palette_writes();
+        let source = PaletteName::parse(&format!("Dup Source {}", Uuid::new_v4())).unwrap();
+        let gone = PaletteName::parse(&format!("Dup Deleted {}", Uuid::new_v4())).unwrap();
+        let change = PaletteChange::with_message("test");
+        for name in [&source, &gone] {
+            let mut p = palette(serde_json::json!({ "A": { "cmd": { "op": 1 } } }));
+            p.name = name.clone();
+            save_palette(&guard, &p, &change).unwrap();
+        }
+        delete_palette(&guard, &gone, None).unwrap();
+
+        let error = duplicate_palette(&guard, &source, &gone, &change).unwrap_err();
+        assert!(error.is(ErrorCode::AlreadyExists), "{}", error.message);
+        assert!(load_palette(&gone).is_err());
+        delete_palette(&guard, &source, None).unwrap();
+    }
+
+    #[test]
+    fn invalid_backups_are_not_restored() {
+        let guard = lock_palette_writes();
+        let mut p = palette(serde_json::json!({ "A": { "cmd": { "op": 1 } } }));
+        p.name = PaletteName::parse(&format!("Restore Test {}", Uuid::new_v4())).unwrap();
+        save_palette(&guard, &p, &PaletteChange::with_message("test")).unwrap();
+        let backup_dir = backup_dir_for(&palette_dir(&p.name).unwrap(), &p.name);
+        fs::create_dir_all(&backup_dir).unwrap();
+        let clashing = serde_json::json!({ "name": "x", "commands": { "Power": {}, "power ": {} } });
+        fs::write(backup_dir.join(format!("20261019T101010.123Z{}", BACKUP_EXTENSION)), clashing.to_string()).unwrap();
+
+        let error = restore_backup(&guard, &p.name, "20261019T101010.123Z").unwrap_err();
+        assert!(error.is(ErrorCode::InvalidPalette));
+        assert_eq!(error.details.unwrap()["issues"][0]["code"], "duplicate_name");
+        assert_eq!(load_palette(&p.name).unwrap().commands, p.commands);
+        delete_palette(&guard, &p.name, None).unwrap();
+    }
+
+    #[test]
+    fn etags_are_strong_and_follow_the_content() {
+        let a = palette(serde_json::json!({ "A": { "x": 1 } }));
+        let b = palette(serde_json::json!({ "A": { "x": 2 } }));
+        let etag = palette_etag(&a);
+        assert!(etag.starts_with('"') && etag.ends_with('"') && !etag.starts_with("W/"));
+        assert_eq!(etag, palette_etag(&a.clone()));
+        assert_ne!(etag, palette_etag(&b));
+    }
+
+    #[test]
+    fn if_match_accepts_the_current_etag_or_a_wildcard() {
+        let p = palette(serde_json::json!({ "A": {} }));
+        let current = palette_etag(&p);
+        check_if_match(&p, None).unwrap();
+        check_if_match(&p, Some("*")).unwrap();
+        check_if_match(&p, Some(&current)).unwrap();
+        check_if_match(&p, Some(&format!("\"stale\", {}", current))).unwrap();
+
+        let error = check_if_match(&p, Some("\"stale\"")).unwrap_err();
+        assert!(error.is(ErrorCode::PreconditionFailed));
+        let details = error.details.unwrap();
+        assert_eq!(details["etag"], current.as_str());
+        assert_eq!(details["palette"]["commands"], serde_json::json!({ "A": {} }));
+    }
+
+    #[test]
+    fn backup_ids_cannot_leave_the_backup_directory() {
+        assert!(is_valid_backup_id("20261019T101010.123Z-2"));
+        for id in ["", "..", "../x", "a/b", "a\\b", "a b"] {
+            assert!(!is_valid_backup_id(id), "{:?} should be rejected", id);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{app_config, AppConfig};
+
+// Longest escaped file stem we are willing to write; keeps us clear of the
+// 255 byte filename limit on common filesystems once ".json" is appended.
+const MAX_FILE_STEM_LEN: usize = 200;
+
+// Device names Windows refuses to use as file names, with or without an extension
+const RESERVED_WINDOWS_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Separates the palette root from the palette's own name, e.g. "shared:Deploy"
+const ROOT_SEPARATOR: char = ':';
+
+// A palette name that is safe to turn into a file inside a palette directory.
+// Every palette name coming from a URL, a request body or an uploaded file goes
+// through `PaletteName::parse` before it gets anywhere near the filesystem.
+// Palettes outside the default root are qualified with their root's name ("shared:Deploy").
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PaletteName {
+    name: String,
+    // Where the name within the root starts; 0 for palettes in the default root
+    local_start: usize,
+}
+
+impl PaletteName {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        Self::parse_in(raw, app_config())
+    }
+
+    // A name is only qualified when the part before ':' is a configured root, so
+    // "shared:Deploy" is in the "shared" root while "v2:Deploy" is a palette of that
+    // name in the default root. The default root's own prefix is dropped, so
+    // "local:Deploy" and "Deploy" are the same palette.
+    fn parse_in(raw: &str, config: &AppConfig) -> Result<Self, String> {
+        if let Some((root, local)) = raw.split_once(ROOT_SEPARATOR) {
+            if let Some(root) = config.palette_root(root) {
+                check_local_name(raw, local)?;
+                return Ok(PaletteName::unqualified(local).in_root(config.root_qualifier(root)));
+            }
+        }
+        check_local_name(raw, raw)?;
+        Ok(PaletteName::unqualified(raw))
+    }
+
+    fn unqualified(local: &str) -> Self {
+        PaletteName { name: local.to_string(), local_start: 0 }
+    }
+
+    // Recovers a palette name from a file stem found in the palette directory.
+    // Stems written by `file_name` are percent-decoded; stems from palettes saved
+    // before names were escaped are taken literally.
+    // The result has no root, even if it contains ':'; use `in_root` to qualify it.
+    pub fn from_file_stem(stem: &str) -> Option<Self> {
+        let local = percent_decode(stem).unwrap_or_else(|| stem.to_string());
+        check_local_name(&local, &local).ok()?;
+        Some(PaletteName::unqualified(&local))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    // The palette root this name is qualified with, or None for the default root
+    pub fn root(&self) -> Option<&str> {
+        self.local_start.checked_sub(ROOT_SEPARATOR.len_utf8()).map(|end| &self.name[..end])
+    }
+
+    // The name without its root prefix; this is what the palette's file is named after
+    pub fn local_name(&self) -> &str {
+        &self.name[self.local_start..]
+    }
+
+    // The same palette name qualified with another root (None for the default root)
+    pub fn in_root(&self, root: Option<&str>) -> PaletteName {
+        match root {
+            Some(root) => PaletteName {
+                name: format!("{}{}{}", root, ROOT_SEPARATOR, self.local_name()),
+                local_start: root.len() + ROOT_SEPARATOR.len_utf8(),
+            },
+            None => PaletteName::unqualified(self.local_name()),
+        }
+    }
+
+    // The file name this palette is stored under, e.g. "My Palette" -> "My%20Palette.json"
+    pub fn file_name(&self) -> String {
+        format!("{}.json", self.file_stem())
+    }
+
+    // The file name used by versions that did not escape palette names.
+    // Only used to find and migrate palettes saved by those versions.
+    pub fn legacy_file_name(&self) -> String {
+        format!("{}.json", self.local_name())
+    }
+
+    pub fn file_stem(&self) -> String {
+        escape_file_stem(self.local_name())
+    }
+}
+
+// Checks the part of a palette name that becomes its file name; `raw` is the whole name for messages
+fn check_local_name(raw: &str, local: &str) -> Result<(), String> {
+    if local.is_empty() {
+        return Err("Invalid palette name: name cannot be empty.".to_string());
+    }
+    if local.trim() != local {
+        return Err(format!("Invalid palette name '{}': leading or trailing whitespace is not allowed.", raw));
+    }
+    if local.contains('/') || local.contains('\\') {
+        return Err(format!("Invalid palette name '{}': path separators are not allowed.", raw));
+    }
+    if local.contains("..") {
+        return Err(format!("Invalid palette name '{}': '..' is not allowed.", raw));
+    }
+    if local.chars().any(|c| c.is_control()) {
+        return Err(format!("Invalid palette name '{}': control characters are not allowed.", raw.escape_debug()));
+    }
+    let base = local.split('.').next().unwrap_or(local).to_ascii_uppercase();
+    if RESERVED_WINDOWS_NAMES.contains(&base.as_str()) {
+        return Err(format!("Invalid palette name '{}': '{}' is a reserved device name.", raw, base));
+    }
+    if escape_file_stem(local).len() > MAX_FILE_STEM_LEN {
+        return Err(format!("Invalid palette name '{}': name is too long.", raw));
+    }
+    Ok(())
+}
+
+// Percent-encodes everything outside [A-Za-z0-9._-] so spaces, Unicode and
+// characters that are illegal on some filesystems (including ':') map to a portable
+// file name. A leading '.' is escaped too so palettes never become hidden files.
+fn escape_file_stem(local: &str) -> String {
+    let mut stem = String::with_capacity(local.len());
+    for (i, byte) in local.bytes().enumerate() {
+        let keep = byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || (byte == b'.' && i > 0);
+        if keep {
+            stem.push(byte as char);
+        } else {
+            stem.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    stem
+}
+
+impl fmt::Display for PaletteName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl TryFrom<String> for PaletteName {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        PaletteName::parse(&value)
+    }
+}
+
+impl From<PaletteName> for String {
+    fn from(name: PaletteName) -> Self {
+        name.name
+    }
+}
+
+// Decodes %XX sequences. Returns None if the stem contains no escapes or is not
+// valid escaped UTF-8, in which case the caller treats it as a legacy stem.
+fn percent_decode(stem: &str) -> Option<String> {
+    if !stem.contains('%') {
+        return None;
+    }
+    let bytes = stem.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = stem.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_names_that_escape_the_palette_directory() {
+        for raw in ["", " padded", "padded ", "a/b", "a\\b", "..", "a..b", "tab\there", "CON", "com1.json", "lpt9"] {
+            assert!(PaletteName::parse(raw).is_err(), "{:?} should be rejected", raw);
+        }
+        assert!(PaletteName::parse(&"x".repeat(MAX_FILE_STEM_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn accepts_ordinary_names() {
+        for raw in ["Deploy", "My Palette", "Grüße", "v1.2", "Console"] {
+            assert_eq!(PaletteName::parse(raw).unwrap().as_str(), raw);
+        }
+    }
+
+    #[test]
+    fn escapes_file_stems() {
+        let name = PaletteName::parse("My Palette").unwrap();
+        assert_eq!(name.file_name(), "My%20Palette.json");
+        assert_eq!(name.legacy_file_name(), "My Palette.json");
+        assert_eq!(PaletteName::parse(".hidden").unwrap().file_stem(), "%2Ehidden");
+        assert_eq!(PaletteName::parse("a*b?").unwrap().file_stem(), "a%2Ab%3F");
+    }
+
+    #[test]
+    fn file_stems_round_trip() {
+        for raw in ["My Palette", "Grüße", ".hidden", "50% off", "plain"] {
+            let name = PaletteName::parse(raw).unwrap();
+            assert_eq!(PaletteName::from_file_stem(&name.file_stem()), Some(name));
+        }
+    }
+
+    fn config() -> AppConfig {
+        let root = |name: &str, read_only| crate::config::PaletteRoot { name: name.into(), path: name.into(), read_only };
+        AppConfig { palette_roots: vec![root("local", false), root("shared", true)] }
+    }
+
+    #[test]
+    fn only_configured_roots_qualify_a_name() {
+        let config = config();
+        let shared = PaletteName::parse_in("shared:Deploy", &config).unwrap();
+        assert_eq!((shared.root(), shared.local_name()), (Some("shared"), "Deploy"));
+        assert_eq!(shared.file_name(), "Deploy.json");
+
+        // Not a root, so the whole string is the palette's name in the default root
+        let plain = PaletteName::parse_in("v2:Deploy", &config).unwrap();
+        assert_eq!((plain.root(), plain.local_name()), (None, "v2:Deploy"));
+        assert_eq!(plain.file_name(), "v2%3ADeploy.json");
+        let nested = PaletteName::parse_in("shared:a:b", &config).unwrap();
+        assert_eq!((nested.root(), nested.local_name()), (Some("shared"), "a:b"));
+
+        assert!(PaletteName::parse_in("shared:", &config).is_err());
+        assert!(PaletteName::parse_in("shared:../x", &config).is_err());
+    }
+
+    #[test]
+    fn the_default_root_prefix_is_dropped() {
+        let config = config();
+        let qualified = PaletteName::parse_in("local:Deploy", &config).unwrap();
+        assert_eq!(qualified, PaletteName::parse_in("Deploy", &config).unwrap());
+        assert_eq!(qualified.as_str(), "Deploy");
+        assert_eq!(qualified.root(), None);
+    }
+
+    #[test]
+    fn names_with_a_colon_survive_their_file_stem() {
+        let name = PaletteName::parse_in("Rack 3: PSU", &config()).unwrap();
+        assert_eq!(PaletteName::from_file_stem(&name.file_stem()), Some(name.clone()));
+        // Even a stem that looks qualified belongs to the directory it was found in
+        let stem = PaletteName::from_file_stem("shared%3ADeploy").unwrap();
+        assert_eq!((stem.root(), stem.local_name()), (None, "shared:Deploy"));
+        assert_eq!(stem.in_root(Some("shared")).as_str(), "shared:shared:Deploy");
+    }
+
+    #[test]
+    fn legacy_stems_are_taken_literally() {
+        assert_eq!(PaletteName::from_file_stem("Old Palette").unwrap().as_str(), "Old Palette");
+        // Not valid escaped UTF-8, so it is a legacy stem rather than an escaped one
+        assert_eq!(PaletteName::from_file_stem("100%").unwrap().as_str(), "100%");
+        assert_eq!(PaletteName::from_file_stem("a%2Fb"), None);
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::broadcast::Sender;
+
+use crate::config::{app_config, PaletteRoot};
+use crate::types::{ConflictPolicy, ImportStatus, Palette, PaletteChange};
+use crate::palette_name::PaletteName;
+use crate::palette_manager;
+use crate::palette_validation::check_valid;
+use crate::error::AppError;
+
+// Prefix of the /ws message sent when a palette file changes on disk
+pub const PALETTE_CHANGED_PREFIX: &str = "PALETTE_CHANGED:";
+
+// Several filesystem events arrive for a single save; only one notification is sent per window
+const CHANGE_NOTIFY_DEBOUNCE: Duration = Duration::from_millis(250);
+
+struct CachedPalette {
+    // None once Commander deleted the palette itself, so the watcher can tell that
+    // removal apart from one made outside of Commander
+    palette: Option<Palette>,
+    // Modification time of the file the palette was read from or written to
+    modified: Option<SystemTime>,
+    // Whether the entry comes from Commander's own write or delete rather than a read
+    written: bool,
+}
+
+// Single access point for palettes. Wraps the on-disk storage in `palette_manager`
+// with an in-memory cache that is updated on every write through the repository,
+// evicted on deletes and revalidated against the file's modification time on reads,
+// so palettes edited by hand on disk are never served stale.
+// Writes update the cache before the palette write lock is released, so the cache
+// always ends up with the palette that was written last.
+// Clones share the same cache.
+#[derive(Clone)]
+pub struct PaletteRepository {
+    cache: Arc<Mutex<HashMap<PaletteName, CachedPalette>>>,
+}
+
+impl PaletteRepository {
+    pub fn new() -> Self {
+        Self { cache: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn list(&self) -> Result<Vec<PaletteName>, AppError> {
+        palette_manager::list_palettes()
+    }
+
+    pub fn get(&self, name: &PaletteName) -> Result<Palette, AppError> {
+        let modified = match palette_manager::palette_modified_time(name)? {
+            Some(modified) => modified,
+            None => return Err(AppError::not_found(format!("Palette '{}' not found.", name))),
+        };
+        if let Some(CachedPalette { palette: Some(palette), modified: cached, .. }) = self.cache.lock().unwrap().get(name) {
+            if *cached == Some(modified) {
+                return Ok(palette.clone());
+            }
+        }
+        let palette = palette_manager::load_palette(name)?;
+        self.store(&palette, false);
+        Ok(palette)
+    }
+
+    pub fn save(&self, palette: &Palette, change: &PaletteChange) -> Result<(), AppError> {
+        check_valid(palette)?;
+        let guard = palette_manager::lock_palette_writes();
+        palette_manager::save_palette(&guard, palette, change)?;
+        self.store(palette, true);
+        Ok(())
+    }
+
+    pub fn modify<T>(
+        &self,
+        name: &PaletteName,
+        change: &PaletteChange,
+        if_match: Option<&str>,
+        edit: impl FnOnce(&mut Palette) -> Result<T, AppError>,
+    ) -> Result<(Palette, T), AppError> {
+        let guard = palette_manager::lock_palette_writes();
+        let (palette, result) = palette_manager::modify_palette(&guard, name, change, if_match, |palette| {
+            let result = edit(palette)?;
+            check_valid(palette)?;
+            Ok(result)
+        })?;
+        self.store(&palette, true);
+        Ok((palette, result))
+    }
+
+    pub fn delete(&self, name: &PaletteName, if_match: Option<&str>) -> Result<(), AppError> {
+        let guard = palette_manager::lock_palette_writes();
+        let result = palette_manager::delete_palette(&guard, name, if_match);
+        self.store_removed(name);
+        result
+    }
+
+    pub fn import(&self, palette: Palette, policy: ConflictPolicy, change: &PaletteChange) -> Result<(Palette, ImportStatus), AppError> {
+        check_valid(&palette)?;
+        let guard = palette_manager::lock_palette_writes();
+        let (palette, status) = palette_manager::import_palette(&guard, palette, policy, change)?;
+        self.store(&palette, true);
+        Ok((palette, status))
+    }
+
+    pub fn rename(&self, old_name: &PaletteName, new_name: &PaletteName, change: &PaletteChange) -> Result<Palette, AppError> {
+        let guard = palette_manager::lock_palette_writes();
+        let palette = palette_manager::rename_palette(&guard, old_name, new_name, change)?;
+        self.store_removed(old_name);
+        self.store(&palette, true);
+        Ok(palette)
+    }
+
+    pub fn duplicate(&self, source_name: &PaletteName, new_name: &PaletteName, change: &PaletteChange) -> Result<Palette, AppError> {
+        let guard = palette_manager::lock_palette_writes();
+        let palette = palette_manager::duplicate_palette(&guard, source_name, new_name, change)?;
+        self.store(&palette, true);
+        Ok(palette)
+    }
+
+    pub fn restore_backup(&self, name: &PaletteName, backup_id: &str) -> Result<Palette, AppError> {
+        let guard = palette_manager::lock_palette_writes();
+        let palette = palette_manager::restore_backup(&guard, name, backup_id)?;
+        self.store(&palette, true);
+        Ok(palette)
+    }
+
+    // Drops a cached palette so the next read goes to disk
+    pub fn invalidate(&self, name: &PaletteName) {
+        self.cache.lock().unwrap().remove(name);
+    }
+
+    // Whether the file is in the state Commander's own last write or delete left it in,
+    // judged by its modification time (None once deleted). Callers must hold the palette
+    // write lock, so a write in progress has reached the cache.
+    fn is_own_write(&self, name: &PaletteName, modified: Option<SystemTime>) -> bool {
+        self.cache.lock().unwrap().get(name).is_some_and(|cached| cached.written && cached.modified == modified)
+    }
+
+    fn store(&self, palette: &Palette, written: bool) {
+        match palette_manager::palette_modified_time(&palette.name) {
+            Ok(modified) => {
+                let cached = CachedPalette { palette: Some(palette.clone()), modified, written };
+                self.cache.lock().unwrap().insert(palette.name.clone(), cached);
+            }
+            Err(_) => self.invalidate(&palette.name),
+        }
+    }
+
+    // Records a palette Commander removed, or tried to: the cache follows the disk
+    fn store_removed(&self, name: &PaletteName) {
+        match palette_manager::palette_modified_time(name) {
+            Ok(None) => {
+                self.cache.lock().unwrap().insert(name.clone(), CachedPalette { palette: None, modified: None, written: true });
+            }
+            _ => self.invalidate(name),
+        }
+    }
+}
+
+// Watches every palette root for changes made outside of Commander (hand edits,
+// files copied in, git pulls), invalidates the affected cache entries and tells
+// every /ws client which palette changed. Commander's own writes are recognized by
+// the cache already holding the file's new modification time and are not reported. The watcher stops when the returned value is dropped.
+pub fn watch_palette_dirs(
+    repository: PaletteRepository,
+    ws_tx: Sender<String>,
+) -> Result<RecommendedWatcher, AppError> {
+    let config = app_config();
+    let mut last_notified: HashMap<PaletteName, Instant> = HashMap::new();
+    // Events may name a file through another path to the same directory (symlinks,
+    // /private on macOS, relative roots), so roots and event paths are compared canonicalized.
+    // Roots that do not exist cannot be canonicalized and are not watched.
+    let mut root_dirs: Vec<(&PaletteRoot, PathBuf)> = Vec::new();
+    for root in &config.palette_roots {
+        if let Ok(dir) = fs::canonicalize(palette_manager::palette_root_dir(root)?) {
+            root_dirs.push((root, dir));
+        }
+    }
+    let watched_roots = root_dirs.clone();
+
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+        let event = match result {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Palette directory watch error: {}", e);
+                return;
+            }
+        };
+        // Reading a palette (including our own reads below) is not a change
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        for path in &event.paths {
+            // Temporary files, backups and history live elsewhere or use other extensions
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(parent) = path.parent().and_then(|parent| fs::canonicalize(parent).ok()) else {
+                continue;
+            };
+            let Some(root) = watched_roots.iter().find(|(_, dir)| *dir == parent).map(|(root, _)| *root) else {
+                continue;
+            };
+            let Some(name) = path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(PaletteName::from_file_stem)
+                .map(|name| name.in_root(config.root_qualifier(root)))
+            else {
+                continue;
+            };
+            {
+                // Taking the write lock waits for a write in progress to reach the cache
+                let _guard = palette_manager::lock_palette_writes();
+                let modified = palette_manager::palette_modified_time(&name).ok().flatten();
+                if repository.is_own_write(&name, modified) {
+                    continue;
+                }
+                repository.invalidate(&name);
+            }
+
+            let now = Instant::now();
+            if last_notified.get(&name).is_some_and(|last| now.duration_since(*last) < CHANGE_NOTIFY_DEBOUNCE) {
+                continue;
+            }
+            last_notified.insert(name.clone(), now);
+
+            let notification = match palette_manager::palette_modified_time(&name) {
+                Ok(Some(_)) => serde_json::json!({
+                    "name": name,
+                    "change": "changed",
+                    // A file caught mid-write by an editor may not parse yet; it is reported without an ETag
+                    "etag": repository.get(&name).ok().map(|palette| palette_manager::palette_etag(&palette)),
+                }),
+                _ => serde_json::json!({ "name": name, "change": "removed" }),
+            };
+            println!("Palette '{}' changed on disk.", name);
+            // No /ws clients connected is not an error
+            let _ = ws_tx.send(format!("{}{}", PALETTE_CHANGED_PREFIX, notification));
+        }
+    }).map_err(|e| AppError::internal(format!("Failed to create palette directory watcher: {}", e)))?;
+
+    for (_, dir) in &root_dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| AppError::internal(format!("Failed to watch palette directory '{}': {}", dir.display(), e)))?;
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value as JsonValue};
+    use uuid::Uuid;
+
+    fn palette(name: &PaletteName, command: &str) -> Palette {
+        serde_json::from_value(json!({ "name": name, "commands": { "A": { "cmd": { "op": command } } } })).unwrap()
+    }
+
+    fn unique_name() -> PaletteName {
+        PaletteName::parse(&format!("Repo Test {}", Uuid::new_v4())).unwrap()
+    }
+
+    fn file_path(name: &PaletteName) -> PathBuf {
+        palette_manager::palette_dir(name).unwrap().join(name.file_name())
+    }
+
+    // Replaces the palette file behind Commander's back, the way editors save: write a
+    // copy, then move it over the original. The copy gets a later modification time than
+    // the original so the change shows even on filesystems with coarse times.
+    fn edit_on_disk(name: &PaletteName, command: &str) {
+        let path = file_path(name);
+        let temp_path = path.with_extension("swp");
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).unwrap_or(SystemTime::now());
+        fs::write(&temp_path, serde_json::to_vec(&palette(&name.in_root(None), command)).unwrap()).unwrap();
+        fs::File::options().write(true).open(&temp_path).unwrap().set_modified(modified + Duration::from_secs(2)).unwrap();
+        fs::rename(&temp_path, &path).unwrap();
+    }
+
+    fn command(palette: &Palette) -> &JsonValue {
+        &palette.commands["A"]["cmd"]["op"]
+    }
+
+    // PALETTE_CHANGED notifications for `name` arriving within `wait`
+    fn notifications(rx: &mut tokio::sync::broadcast::Receiver<String>, name: &PaletteName, wait: Duration) -> Vec<JsonValue> {
+        let deadline = Instant::now() + wait;
+        let mut found = Vec::new();
+        while Instant::now() < deadline {
+            match rx.try_recv() {
+                Ok(message) => {
+                    let Some(event) = message.strip_prefix(PALETTE_CHANGED_PREFIX) else { continue };
+                    let event: JsonValue = serde_json::from_str(event).unwrap();
+                    if event["name"] == name.as_str() {
+                        found.push(event);
+                    }
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(20)),
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn writes_and_deletes_keep_the_cache_in_step() {
+        let repository = PaletteRepository::new();
+        let name = unique_name();
+        repository.save(&palette(&name, "one"), &PaletteChange::default()).unwrap();
+        assert_eq!(command(&repository.get(&name).unwrap()), "one");
+
+        let (_, status) = repository.import(palette(&name, "two"), ConflictPolicy::Overwrite, &PaletteChange::default()).unwrap();
+        assert_eq!(status, ImportStatus::Overwritten);
+        assert_eq!(command(&repository.get(&name).unwrap()), "two");
+
+        repository.delete(&name, None).unwrap();
+        assert!(repository.get(&name).unwrap_err().is(crate::error::ErrorCode::NotFound));
+        // A clone shares the cache, and a palette created again under the name is served fresh
+        let clone = repository.clone();
+        clone.import(palette(&name, "three"), ConflictPolicy::Reject, &PaletteChange::default()).unwrap();
+        assert_eq!(command(&repository.get(&name).unwrap()), "three");
+        repository.delete(&name, None).unwrap();
+    }
+
+    #[test]
+    fn external_edits_are_reloaded() {
+        let repository = PaletteRepository::new();
+        let name = unique_name();
+        repository.save(&palette(&name, "one"), &PaletteChange::default()).unwrap();
+        assert_eq!(command(&repository.get(&name).unwrap()), "one");
+
+        edit_on_disk(&name, "edited");
+        assert_eq!(command(&repository.get(&name).unwrap()), "edited");
+        // And the edit is cached like any other read
+        assert_eq!(command(&repository.get(&name).unwrap()), "edited");
+
+        fs::remove_file(file_path(&name)).unwrap();
+        assert!(repository.get(&name).is_err());
+    }
+
+    #[test]
+    fn the_watcher_reports_external_changes_only() {
+        let repository = PaletteRepository::new();
+        let (tx, mut rx) = tokio::sync::broadcast::channel(256);
+        let _watcher = watch_palette_dirs(repository.clone(), tx).unwrap();
+        let name = unique_name();
+        let quiet = Duration::from_millis(500);
+
+        repository.save(&palette(&name, "one"), &PaletteChange::default()).unwrap();
+        repository.modify(&name, &PaletteChange::default(), None, |palette| {
+            palette.commands.get_mut("A").unwrap().insert("cmd".to_string(), json!({ "op": "two" }));
+            Ok(())
+        }).unwrap();
+        assert_eq!(notifications(&mut rx, &name, quiet), Vec::<JsonValue>::new());
+
+        edit_on_disk(&name, "edited");
+        let events = notifications(&mut rx, &name, quiet);
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0]["change"], "changed");
+        assert_eq!(events[0]["etag"], palette_manager::palette_etag(&palette(&name, "edited")));
+        assert_eq!(command(&repository.get(&name).unwrap()), "edited");
+
+        fs::remove_file(file_path(&name)).unwrap();
+        let events = notifications(&mut rx, &name, quiet);
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0]["change"], "removed");
+
+        repository.save(&palette(&name, "again"), &PaletteChange::default()).unwrap();
+        repository.delete(&name, None).unwrap();
+        assert_eq!(notifications(&mut rx, &name, quiet), Vec::<JsonValue>::new());
+    }
+}
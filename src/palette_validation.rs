@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use serde_json::Value as JsonValue;
+
+use crate::types::{Palette, IssueSeverity, ValidationIssue};
+use crate::palette_name::PaletteName;
+use crate::error::{AppError, ErrorCode};
+
+// Checks the shape of palettes before they are saved, so mistakes like a command
+// placed directly at the top level (missing its category) are reported up front
+// instead of breaking the UI later. Every issue points at the offending value
+// with a JSON pointer relative to the palette document.
+
+fn error(pointer: String, code: &str, message: String) -> ValidationIssue {
+    ValidationIssue { severity: IssueSeverity::Error, pointer, code: code.to_string(), message }
+}
+
+fn warning(pointer: String, code: &str, message: String) -> ValidationIssue {
+    ValidationIssue { severity: IssueSeverity::Warning, pointer, code: code.to_string(), message }
+}
+
+// Appends a reference token to a JSON pointer, escaping it as RFC 6901 requires
+fn pointer_join(base: &str, token: &str) -> String {
+    format!("{}/{}", base, token.replace('~', "~0").replace('/', "~1"))
+}
+
+// Names that only differ in case or surrounding/repeated whitespace look identical in the UI
+fn normalize_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+pub fn has_errors(issues: &[ValidationIssue]) -> bool {
+    issues.iter().any(|issue| issue.severity == IssueSeverity::Error)
+}
+
+// Refuses to store a palette with validation errors; warnings are allowed
+pub fn check_valid(palette: &Palette) -> Result<(), AppError> {
+    let errors: Vec<_> = validate_palette(palette).into_iter().filter(|issue| issue.severity == IssueSeverity::Error).collect();
+    match errors.first() {
+        Some(issue) => Err(AppError::new(ErrorCode::InvalidPalette, format!("Invalid palette: {} (at {})", issue.message, issue.pointer))
+            .with_details(serde_json::json!({ "issues": errors }))),
+        None => Ok(()),
+    }
+}
+
+// Validates a palette document as uploaded or sent for a dry run: {"name": ..., "commands": {...}}
+pub fn validate_palette_json(value: &JsonValue) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let Some(document) = value.as_object() else {
+        issues.push(error(String::new(), "not_an_object", "A palette must be a JSON object with \"name\" and \"commands\".".to_string()));
+        return issues;
+    };
+    match document.get("name") {
+        Some(JsonValue::String(name)) => {
+            if let Err(e) = PaletteName::parse(name) {
+                issues.push(error("/name".to_string(), "invalid_palette_name", e));
+            }
+        }
+        Some(_) => issues.push(error("/name".to_string(), "invalid_palette_name", "The palette name must be a string.".to_string())),
+        None => issues.push(error("/name".to_string(), "missing_field", "The palette has no \"name\".".to_string())),
+    }
+    match document.get("commands") {
+        Some(commands) => validate_commands(commands, "/commands", &mut issues),
+        None => issues.push(error("/commands".to_string(), "missing_field", "The palette has no \"commands\".".to_string())),
+    }
+    issues
+}
+
+// Validates a palette that already deserialized, e.g. from a create or update request
+pub fn validate_palette(palette: &Palette) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    match serde_json::to_value(&palette.commands) {
+        Ok(commands) => validate_commands(&commands, "/commands", &mut issues),
+        Err(e) => issues.push(error("/commands".to_string(), "invalid_json", format!("Failed to serialize commands: {}", e))),
+    }
+    issues
+}
+
+fn validate_commands(commands: &JsonValue, pointer: &str, issues: &mut Vec<ValidationIssue>) {
+    let Some(categories) = commands.as_object() else {
+        issues.push(error(pointer.to_string(), "not_an_object", "Commands must be an object mapping category names to categories.".to_string()));
+        return;
+    };
+    check_names(categories.keys(), "category", pointer, issues);
+
+    for (category, entries) in categories {
+        let category_pointer = pointer_join(pointer, category);
+        let Some(entries) = entries.as_object() else {
+            issues.push(error(
+                category_pointer,
+                "category_not_an_object",
+                format!("Category '{}' must be an object mapping command names to commands.", category),
+            ));
+            continue;
+        };
+
+        // {"Get Version": {"cmd": "ver"}} is a command with no category around it
+        if !entries.is_empty() && entries.values().all(|entry| !entry.is_object()) {
+            issues.push(error(
+                category_pointer,
+                "missing_category",
+                format!("'{}' looks like a command placed directly at the top level; commands must be nested under a category.", category),
+            ));
+            continue;
+        }
+
+        check_names(entries.keys(), "command", &category_pointer, issues);
+        for (command, body) in entries {
+            let command_pointer = pointer_join(&category_pointer, command);
+            if !body.is_object() {
+                issues.push(error(
+                    command_pointer,
+                    "command_not_an_object",
+                    format!("Command '{}' in category '{}' must be a JSON object.", command, category),
+                ));
+                continue;
+            }
+            check_placeholders(body, &command_pointer, issues);
+        }
+    }
+}
+
+// Reports empty names and names that collide once normalized
+fn check_names<'a>(names: impl Iterator<Item = &'a String>, kind: &str, pointer: &str, issues: &mut Vec<ValidationIssue>) {
+    let mut seen: HashMap<String, &String> = HashMap::new();
+    for name in names {
+        let name_pointer = pointer_join(pointer, name);
+        if name.trim().is_empty() {
+            issues.push(error(name_pointer, "empty_name", format!("A {} name cannot be empty.", kind)));
+            continue;
+        }
+        if let Some(first) = seen.get(&normalize_name(name)) {
+            issues.push(error(
+                name_pointer,
+                "duplicate_name",
+                format!("The {} name '{}' only differs from '{}' in case or whitespace.", kind, name, first),
+            ));
+        } else {
+            seen.insert(normalize_name(name), name);
+        }
+    }
+}
+
+// A placeholder is a whole string value like "%01" or "%name". The UI replaces the
+// entire value of any string containing '%', so anything else around it is lost,
+// and a '%' in a key is never offered as a variable at all.
+fn check_placeholders(value: &JsonValue, pointer: &str, issues: &mut Vec<ValidationIssue>) {
+    match value {
+        JsonValue::Object(map) => {
+            for (key, child) in map {
+                let child_pointer = pointer_join(pointer, key);
+                if key.contains('%') {
+                    issues.push(warning(
+                        child_pointer.clone(),
+                        "placeholder_in_key",
+                        format!("'{}' is a key; placeholders are only recognized in string values.", key),
+                    ));
+                }
+                check_placeholders(child, &child_pointer, issues);
+            }
+        }
+        JsonValue::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_placeholders(item, &pointer_join(pointer, &i.to_string()), issues);
+            }
+        }
+        JsonValue::String(text) if text.contains('%') && !is_placeholder(text) => {
+            issues.push(warning(
+                pointer.to_string(),
+                "unparseable_placeholder",
+                format!("'{}' is not a placeholder like \"%01\"; the whole value will be replaced by the entered text.", text),
+            ));
+        }
+        _ => {}
+    }
+}
+
+fn is_placeholder(text: &str) -> bool {
+    text.strip_prefix('%')
+        .is_some_and(|name| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn codes(issues: &[ValidationIssue]) -> Vec<(IssueSeverity, &str, &str)> {
+        issues.iter().map(|i| (i.severity, i.code.as_str(), i.pointer.as_str())).collect()
+    }
+
+    #[test]
+    fn a_well_formed_palette_has_no_issues() {
+        let issues = validate_palette_json(&json!({
+            "name": "Test",
+            "commands": { "Power": { "On": { "cmd": "on", "ch": "%01" } }, "Empty": {} },
+        }));
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn document_level_problems_are_errors() {
+        assert_eq!(codes(&validate_palette_json(&json!([]))), [(IssueSeverity::Error, "not_an_object", "")]);
+        assert_eq!(
+            codes(&validate_palette_json(&json!({ "name": "../x" }))),
+            [(IssueSeverity::Error, "invalid_palette_name", "/name"), (IssueSeverity::Error, "missing_field", "/commands")]
+        );
+        assert_eq!(
+            codes(&validate_palette_json(&json!({ "commands": [] }))),
+            [(IssueSeverity::Error, "missing_field", "/name"), (IssueSeverity::Error, "not_an_object", "/commands")]
+        );
+    }
+
+    #[test]
+    fn commands_without_a_category_are_reported() {
+        let issues = validate_palette_json(&json!({ "name": "T", "commands": { "Get Version": { "cmd": "ver" } } }));
+        assert_eq!(codes(&issues), [(IssueSeverity::Error, "missing_category", "/commands/Get Version")]);
+        assert!(has_errors(&issues));
+    }
+
+    #[test]
+    fn categories_and_commands_must_be_objects() {
+        let issues = validate_palette_json(&json!({ "name": "T", "commands": { "A": 1, "B": { "x": {}, "y": 2 } } }));
+        assert_eq!(codes(&issues), [
+            (IssueSeverity::Error, "category_not_an_object", "/commands/A"),
+            (IssueSeverity::Error, "command_not_an_object", "/commands/B/y"),
+        ]);
+    }
+
+    #[test]
+    fn empty_and_colliding_names_are_errors() {
+        let issues = validate_palette_json(&json!({ "name": "T", "commands": {
+            " ": {},
+            "Power": { "On": {}, "on ": {} },
+            "power": {},
+        } }));
+        assert_eq!(codes(&issues), [
+            (IssueSeverity::Error, "empty_name", "/commands/ "),
+            (IssueSeverity::Error, "duplicate_name", "/commands/power"),
+            (IssueSeverity::Error, "duplicate_name", "/commands/Power/on "),
+        ]);
+    }
+
+    #[test]
+    fn questionable_placeholders_are_only_warnings() {
+        let issues = validate_palette_json(&json!({ "name": "T", "commands": { "A": { "x": {
+            "line": "ver %01",
+            "%key": "value",
+            "list": ["%01", "50%"],
+            "a/b": { "c~d": "%" },
+        } } } }));
+        assert_eq!(codes(&issues), [
+            (IssueSeverity::Warning, "unparseable_placeholder", "/commands/A/x/line"),
+            (IssueSeverity::Warning, "placeholder_in_key", "/commands/A/x/%key"),
+            (IssueSeverity::Warning, "unparseable_placeholder", "/commands/A/x/list/1"),
+            (IssueSeverity::Warning, "unparseable_placeholder", "/commands/A/x/a~1b/c~0d"),
+        ]);
+        assert!(!has_errors(&issues));
+    }
+
+    #[test]
+    fn deserialized_palettes_get_the_same_checks() {
+        let palette: Palette = serde_json::from_value(json!({ "name": "T", "commands": { "A": { "x": { "v": "v%1" } } } })).unwrap();
+        assert_eq!(codes(&validate_palette(&palette)), [(IssueSeverity::Warning, "unparseable_placeholder", "/commands/A/x/v")]);
+    }
+
+    #[test]
+    fn check_valid_rejects_errors_but_not_warnings() {
+        let clashing: Palette = serde_json::from_value(json!({ "name": "T", "commands": { "Power": {}, "power ": {} } })).unwrap();
+        let error = check_valid(&clashing).unwrap_err();
+        assert!(error.is(ErrorCode::InvalidPalette));
+        assert_eq!(error.details.unwrap()["issues"][0]["code"], "duplicate_name");
+
+        let warned: Palette = serde_json::from_value(json!({ "name": "T", "commands": { "A": { "x": { "v": "v%1" } } } })).unwrap();
+        check_valid(&warned).unwrap();
+    }
+}
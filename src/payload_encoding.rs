@@ -0,0 +1,175 @@
+use std::io::{Cursor, ErrorKind};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+// How commands are put on the wire for a connection. Palettes and commands are always
+// authored as JSON; binary encodings convert them on the way out and back on the way in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Json,
+    #[serde(alias = "messagepack")]
+    Msgpack,
+    Cbor,
+}
+
+// The outcome of reading one value from the front of a byte buffer
+pub enum Decoded {
+    // A value and the number of bytes it took up
+    Value(JsonValue, usize),
+    // The buffer holds the start of a value; wait for more bytes
+    Incomplete,
+    Invalid(String),
+}
+
+impl PayloadEncoding {
+    pub fn label(self) -> &'static str {
+        match self {
+            PayloadEncoding::Json => "JSON",
+            PayloadEncoding::Msgpack => "MessagePack",
+            PayloadEncoding::Cbor => "CBOR",
+        }
+    }
+
+    pub fn encode(self, value: &JsonValue) -> Result<Vec<u8>, String> {
+        let serialize_error = |e: &dyn std::fmt::Display| format!("Failed to serialize command as {}: {}", self.label(), e);
+        match self {
+            PayloadEncoding::Json => serde_json::to_vec(value).map_err(|e| serialize_error(&e)),
+            // Named encoding keeps objects as maps rather than positional arrays
+            PayloadEncoding::Msgpack => rmp_serde::to_vec_named(value).map_err(|e| serialize_error(&e)),
+            PayloadEncoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| serialize_error(&e))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    // Decodes a whole frame, which must hold exactly one value
+    pub fn decode(self, bytes: &[u8]) -> Result<JsonValue, String> {
+        match self.decode_next(bytes) {
+            Decoded::Value(value, len) if len == bytes.len() => Ok(value),
+            Decoded::Value(_, len) => Err(format!("{} bytes left over after the {} value.", bytes.len() - len, self.label())),
+            Decoded::Incomplete => Err(format!("Frame ends in the middle of a {} value.", self.label())),
+            Decoded::Invalid(e) => Err(e),
+        }
+    }
+
+    // Reads the first value in `bytes`, for streams without framing
+    pub fn decode_next(self, bytes: &[u8]) -> Decoded {
+        let mut cursor = Cursor::new(bytes);
+        let result = match self {
+            PayloadEncoding::Json => {
+                let mut values = serde_json::Deserializer::from_slice(bytes).into_iter::<JsonValue>();
+                return match values.next() {
+                    Some(Ok(value)) => Decoded::Value(value, values.byte_offset()),
+                    Some(Err(e)) if e.is_eof() => Decoded::Incomplete,
+                    Some(Err(e)) => Decoded::Invalid(format!("Invalid JSON: {}", e)),
+                    None => Decoded::Incomplete,
+                };
+            }
+            PayloadEncoding::Msgpack => {
+                let mut deserializer = rmp_serde::Deserializer::new(&mut cursor);
+                JsonValue::deserialize(&mut deserializer).map_err(|e| match e {
+                    rmp_serde::decode::Error::InvalidMarkerRead(ref io) | rmp_serde::decode::Error::InvalidDataRead(ref io)
+                        if io.kind() == ErrorKind::UnexpectedEof => None,
+                    e => Some(e.to_string()),
+                })
+            }
+            PayloadEncoding::Cbor => ciborium::from_reader::<JsonValue, _>(&mut cursor).map_err(|e| match e {
+                ciborium::de::Error::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => None,
+                e => Some(format!("{:?}", e)),
+            }),
+        };
+        match result {
+            Ok(value) => Decoded::Value(value, cursor.position() as usize),
+            Err(None) => Decoded::Incomplete,
+            Err(Some(e)) => Decoded::Invalid(format!("Invalid {}: {}", self.label(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ENCODINGS: [PayloadEncoding; 3] = [PayloadEncoding::Json, PayloadEncoding::Msgpack, PayloadEncoding::Cbor];
+
+    fn sample() -> JsonValue {
+        json!({"cmd": "set", "id": 7, "args": [1.5, -2, true, null, "é"], "nested": {"empty": {}}})
+    }
+
+    fn value(decoded: Decoded) -> (JsonValue, usize) {
+        match decoded {
+            Decoded::Value(value, len) => (value, len),
+            Decoded::Incomplete => panic!("expected a value, got Incomplete"),
+            Decoded::Invalid(e) => panic!("expected a value, got Invalid({})", e),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(&sample()).unwrap();
+            assert_eq!(encoding.decode(&bytes).unwrap(), sample(), "{}", encoding.label());
+            assert_eq!(value(encoding.decode_next(&bytes)), (sample(), bytes.len()));
+        }
+    }
+
+    #[test]
+    fn msgpack_keeps_objects_as_maps() {
+        let bytes = PayloadEncoding::Msgpack.encode(&json!({"a": 1})).unwrap();
+        assert_eq!(bytes, [0x81, 0xa1, b'a', 0x01]);
+    }
+
+    #[test]
+    fn back_to_back_values() {
+        for encoding in ENCODINGS {
+            let first = encoding.encode(&json!({"n": 1})).unwrap();
+            let mut stream = first.clone();
+            stream.extend(encoding.encode(&json!([2])).unwrap());
+            assert_eq!(value(encoding.decode_next(&stream)), (json!({"n": 1}), first.len()));
+            assert_eq!(value(encoding.decode_next(&stream[first.len()..])).0, json!([2]));
+        }
+    }
+
+    #[test]
+    fn truncated_values_are_incomplete() {
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(&sample()).unwrap();
+            for len in [0, 1, bytes.len() / 2, bytes.len() - 1] {
+                assert!(
+                    matches!(encoding.decode_next(&bytes[..len]), Decoded::Incomplete),
+                    "{} cut at {} of {}", encoding.label(), len, bytes.len()
+                );
+            }
+            assert_eq!(
+                encoding.decode(&bytes[..bytes.len() - 1]).unwrap_err(),
+                format!("Frame ends in the middle of a {} value.", encoding.label())
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        // 0xc1 is never used in MessagePack, and 0x1c is a reserved CBOR header
+        let cases = [(PayloadEncoding::Json, &b"}"[..]), (PayloadEncoding::Msgpack, &[0xc1][..]), (PayloadEncoding::Cbor, &[0x1c][..])];
+        for (encoding, bytes) in cases {
+            match encoding.decode_next(bytes) {
+                Decoded::Invalid(e) => assert!(e.starts_with(&format!("Invalid {}:", encoding.label())), "{}", e),
+                _ => panic!("{} accepted {:02x?}", encoding.label(), bytes),
+            }
+        }
+    }
+
+    #[test]
+    fn frames_hold_exactly_one_value() {
+        for encoding in ENCODINGS {
+            let mut bytes = encoding.encode(&json!([1])).unwrap();
+            bytes.extend(encoding.encode(&json!([2])).unwrap());
+            assert!(encoding.decode(&bytes).unwrap_err().contains("bytes left over after the"));
+        }
+    }
+}
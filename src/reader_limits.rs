@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Sender;
+
+use crate::byte_send::to_hex;
+
+// Sent on /ws when buffered bytes are given up on, so the device output is not silently lost
+pub const TCP_FLUSHED_PREFIX: &str = "TCP_FLUSHED:";
+// How much of the flushed data is shown in the event
+const FLUSH_PREVIEW_LEN: usize = 64;
+
+fn default_max_frame_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_partial_timeout_ms() -> u64 {
+    5_000
+}
+
+// Bounds on what the reader keeps while waiting for a message to complete
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct ReaderLimits {
+    // A partial message that grows past this is flushed
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    // A partial message with no new bytes for this long is flushed; 0 waits forever
+    #[serde(default = "default_partial_timeout_ms")]
+    pub partial_timeout_ms: u64,
+}
+
+impl Default for ReaderLimits {
+    fn default() -> Self {
+        Self { max_frame_bytes: default_max_frame_bytes(), partial_timeout_ms: default_partial_timeout_ms() }
+    }
+}
+
+impl ReaderLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_frame_bytes == 0 {
+            return Err("Invalid reader limits: max_frame_bytes must be at least 1.".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushReason {
+    // The partial message grew past max_frame_bytes
+    Oversized,
+    // No new bytes arrived within partial_timeout_ms
+    Idle,
+    // The connection closed with a partial message buffered
+    Closed,
+}
+
+// Counters for the current connection, reset on every /connect
+#[derive(Default)]
+pub struct ReaderStats {
+    bytes_received: AtomicU64,
+    dropped_bytes: AtomicU64,
+    oversized_flushes: AtomicU64,
+    idle_flushes: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReaderStatsSnapshot {
+    pub bytes_received: u64,
+    // Bytes that never made it into a message: flushed partials, skipped garbage and bad frames
+    pub dropped_bytes: u64,
+    pub oversized_flushes: u64,
+    pub idle_flushes: u64,
+}
+
+impl ReaderStats {
+    pub fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, len: usize) {
+        self.dropped_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ReaderStatsSnapshot {
+        ReaderStatsSnapshot {
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            dropped_bytes: self.dropped_bytes.load(Ordering::Relaxed),
+            oversized_flushes: self.oversized_flushes.load(Ordering::Relaxed),
+            idle_flushes: self.idle_flushes.load(Ordering::Relaxed),
+        }
+    }
+
+    // Counts the flushed bytes and reports them on /ws as
+    // TCP_FLUSHED:{"reason", "bytes", "hex", "text", "truncated"}
+    pub fn flush(&self, tx: &Sender<String>, reason: FlushReason, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.dropped(bytes.len());
+        match reason {
+            FlushReason::Oversized => self.oversized_flushes.fetch_add(1, Ordering::Relaxed),
+            FlushReason::Idle => self.idle_flushes.fetch_add(1, Ordering::Relaxed),
+            FlushReason::Closed => 0,
+        };
+        let preview = &bytes[..bytes.len().min(FLUSH_PREVIEW_LEN)];
+        let event = serde_json::json!({
+            "reason": reason,
+            "bytes": bytes.len(),
+            "hex": to_hex(preview),
+            "text": String::from_utf8_lossy(preview),
+            "truncated": bytes.len() > FLUSH_PREVIEW_LEN,
+        });
+        println!("Flushed {} buffered bytes ({:?}).", bytes.len(), reason);
+        let _ = tx.send(format!("{}{}", TCP_FLUSHED_PREFIX, event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value as JsonValue;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn defaults_fill_missing_fields() {
+        let limits: ReaderLimits = serde_json::from_str("{}").unwrap();
+        assert_eq!(limits.max_frame_bytes, 64 * 1024);
+        assert_eq!(limits.partial_timeout_ms, 5_000);
+        let limits: ReaderLimits = serde_json::from_str(r#"{"partial_timeout_ms": 0}"#).unwrap();
+        assert_eq!(limits.partial_timeout_ms, 0);
+        assert!(limits.validate().is_ok());
+    }
+
+    #[test]
+    fn frames_must_allow_a_byte() {
+        let limits = ReaderLimits { max_frame_bytes: 0, ..Default::default() };
+        assert_eq!(limits.validate().unwrap_err(), "Invalid reader limits: max_frame_bytes must be at least 1.");
+        assert!(ReaderLimits { max_frame_bytes: 1, ..Default::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn counters_add_up() {
+        let stats = ReaderStats::default();
+        let (tx, _rx) = broadcast::channel(8);
+        stats.received(10);
+        stats.received(5);
+        stats.dropped(2);
+        stats.flush(&tx, FlushReason::Oversized, b"abc");
+        stats.flush(&tx, FlushReason::Idle, b"d");
+        stats.flush(&tx, FlushReason::Closed, b"ef");
+        // Nothing buffered, nothing to count
+        stats.flush(&tx, FlushReason::Idle, b"");
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_received, 15);
+        assert_eq!(snapshot.dropped_bytes, 8);
+        assert_eq!(snapshot.oversized_flushes, 1);
+        assert_eq!(snapshot.idle_flushes, 1);
+    }
+
+    #[test]
+    fn flushes_are_reported_with_a_preview() {
+        let stats = ReaderStats::default();
+        let (tx, mut rx) = broadcast::channel(8);
+        stats.flush(&tx, FlushReason::Idle, b"\x02hi");
+        let event: JsonValue = serde_json::from_str(rx.try_recv().unwrap().strip_prefix(TCP_FLUSHED_PREFIX).unwrap()).unwrap();
+        assert_eq!(event, serde_json::json!({"reason": "idle", "bytes": 3, "hex": "02 68 69", "text": "\u{2}hi", "truncated": false}));
+
+        stats.flush(&tx, FlushReason::Oversized, &[b'x'; FLUSH_PREVIEW_LEN + 1]);
+        let event: JsonValue = serde_json::from_str(rx.try_recv().unwrap().strip_prefix(TCP_FLUSHED_PREFIX).unwrap()).unwrap();
+        assert_eq!(event["bytes"], FLUSH_PREVIEW_LEN + 1);
+        assert_eq!(event["text"].as_str().unwrap().len(), FLUSH_PREVIEW_LEN);
+        assert_eq!(event["truncated"], true);
+
+        stats.flush(&tx, FlushReason::Closed, b"");
+        assert!(rx.try_recv().is_err());
+    }
+}
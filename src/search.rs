@@ -0,0 +1,231 @@
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::palette_name::PaletteName;
+use crate::palette_repository::PaletteRepository;
+use crate::error::AppError;
+
+// Weight of a term found in each kind of field. Exact matches score higher than
+// prefix matches, which score higher than matches anywhere in the text.
+const COMMAND_NAME_WEIGHT: u32 = 40;
+const CATEGORY_NAME_WEIGHT: u32 = 20;
+const PALETTE_NAME_WEIGHT: u32 = 10;
+const JSON_KEY_WEIGHT: u32 = 6;
+const JSON_VALUE_WEIGHT: u32 = 5;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchField {
+    PaletteName,
+    CategoryName,
+    CommandName,
+    Key,
+    Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchMatch {
+    pub field: MatchField,
+    // JSON pointer into the command for key and value matches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<String>,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HitKind {
+    Palette,
+    Category,
+    Command,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub kind: HitKind,
+    pub palette: PaletteName,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub score: u32,
+    pub matches: Vec<SearchMatch>,
+}
+
+// Scores one term against one piece of text, or None if it does not occur
+fn term_score(text_lower: &str, term: &str, weight: u32) -> Option<u32> {
+    if text_lower == term {
+        Some(weight * 3)
+    } else if text_lower.starts_with(term) {
+        Some(weight * 2)
+    } else if text_lower.contains(term) {
+        Some(weight)
+    } else {
+        None
+    }
+}
+
+// A field that can be searched, with what to report if it matches
+struct Field {
+    field: MatchField,
+    pointer: Option<String>,
+    text: String,
+    weight: u32,
+}
+
+fn name_field(field: MatchField, text: &str, weight: u32) -> Field {
+    Field { field, pointer: None, text: text.to_string(), weight }
+}
+
+// Collects every key and scalar value of a command as searchable fields
+fn json_fields(value: &JsonValue, pointer: &str, fields: &mut Vec<Field>) {
+    match value {
+        JsonValue::Object(map) => {
+            for (key, child) in map {
+                let child_pointer = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+                fields.push(Field { field: MatchField::Key, pointer: Some(child_pointer.clone()), text: key.clone(), weight: JSON_KEY_WEIGHT });
+                json_fields(child, &child_pointer, fields);
+            }
+        }
+        JsonValue::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                json_fields(item, &format!("{}/{}", pointer, i), fields);
+            }
+        }
+        JsonValue::String(text) => {
+            fields.push(Field { field: MatchField::Value, pointer: Some(pointer.to_string()), text: text.clone(), weight: JSON_VALUE_WEIGHT });
+        }
+        JsonValue::Number(_) | JsonValue::Bool(_) => {
+            fields.push(Field { field: MatchField::Value, pointer: Some(pointer.to_string()), text: value.to_string(), weight: JSON_VALUE_WEIGHT });
+        }
+        JsonValue::Null => {}
+    }
+}
+
+// Every term has to match at least one field; the score adds up the best match of each term
+fn score_fields(fields: Vec<Field>, terms: &[String]) -> Option<(u32, Vec<SearchMatch>)> {
+    let lowered: Vec<String> = fields.iter().map(|f| f.text.to_lowercase()).collect();
+    let mut total = 0;
+    let mut matched = vec![false; fields.len()];
+    for term in terms {
+        let mut best = None;
+        for (i, field) in fields.iter().enumerate() {
+            if let Some(score) = term_score(&lowered[i], term, field.weight) {
+                matched[i] = true;
+                best = best.max(Some(score));
+            }
+        }
+        total += best?;
+    }
+    let matches = fields.into_iter()
+        .zip(matched)
+        .filter(|(_, matched)| *matched)
+        .map(|(field, _)| SearchMatch { field: field.field, pointer: field.pointer, text: field.text })
+        .collect();
+    Some((total, matches))
+}
+
+// Searches palette, category and command names and command contents across all palettes.
+// Results are ordered by score, best first, then by their position in the palettes.
+pub fn search_palettes(palettes: &PaletteRepository, query: &str, limit: usize) -> Result<Vec<SearchHit>, AppError> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let mut hits = Vec::new();
+    if terms.is_empty() {
+        return Ok(hits);
+    }
+
+    for name in palettes.list()? {
+        // A palette that cannot be read right now should not break the search
+        let Ok(palette) = palettes.get(&name) else {
+            continue;
+        };
+        let palette_field = || name_field(MatchField::PaletteName, name.as_str(), PALETTE_NAME_WEIGHT);
+
+        if let Some((score, matches)) = score_fields(vec![palette_field()], &terms) {
+            hits.push(SearchHit { kind: HitKind::Palette, palette: name.clone(), category: None, command: None, score, matches });
+        }
+        for (category, commands) in &palette.commands {
+            let category_field = || name_field(MatchField::CategoryName, category, CATEGORY_NAME_WEIGHT);
+            if let Some((score, matches)) = score_fields(vec![palette_field(), category_field()], &terms) {
+                // Only report categories whose own name matched, not every category of a matching palette
+                if matches.iter().any(|m| m.field == MatchField::CategoryName) {
+                    hits.push(SearchHit { kind: HitKind::Category, palette: name.clone(), category: Some(category.clone()), command: None, score, matches });
+                }
+            }
+            for (command, body) in commands {
+                let mut fields = vec![palette_field(), category_field(), name_field(MatchField::CommandName, command, COMMAND_NAME_WEIGHT)];
+                json_fields(body, "", &mut fields);
+                if let Some((score, matches)) = score_fields(fields, &terms) {
+                    // Likewise, a command needs a match of its own
+                    if matches.iter().any(|m| matches!(m.field, MatchField::CommandName | MatchField::Key | MatchField::Value)) {
+                        hits.push(SearchHit {
+                            kind: HitKind::Command,
+                            palette: name.clone(),
+                            category: Some(category.clone()),
+                            command: Some(command.clone()),
+                            score,
+                            matches,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Stable sort keeps palette order among equal scores
+    hits.sort_by_key(|hit| std::cmp::Reverse(hit.score));
+    hits.truncate(limit);
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn terms(query: &str) -> Vec<String> {
+        query.split_whitespace().map(str::to_lowercase).collect()
+    }
+
+    #[test]
+    fn exact_beats_prefix_beats_substring() {
+        assert_eq!(term_score("reset", "reset", 10), Some(30));
+        assert_eq!(term_score("reset all", "reset", 10), Some(20));
+        assert_eq!(term_score("soft reset", "reset", 10), Some(10));
+        assert_eq!(term_score("restart", "reset", 10), None);
+    }
+
+    #[test]
+    fn command_contents_are_searchable_with_pointers() {
+        let mut fields = Vec::new();
+        json_fields(&json!({ "cmd": "set", "a/b": [true, 5, null], "nested": { "x~y": "deep" } }), "", &mut fields);
+        let found: Vec<(MatchField, Option<&str>, &str)> = fields.iter().map(|f| (f.field, f.pointer.as_deref(), f.text.as_str())).collect();
+        assert_eq!(found, [
+            (MatchField::Key, Some("/cmd"), "cmd"),
+            (MatchField::Value, Some("/cmd"), "set"),
+            (MatchField::Key, Some("/a~1b"), "a/b"),
+            (MatchField::Value, Some("/a~1b/0"), "true"),
+            (MatchField::Value, Some("/a~1b/1"), "5"),
+            (MatchField::Key, Some("/nested"), "nested"),
+            (MatchField::Key, Some("/nested/x~0y"), "x~y"),
+            (MatchField::Value, Some("/nested/x~0y"), "deep"),
+        ]);
+    }
+
+    #[test]
+    fn every_term_must_match_and_scores_add_up() {
+        let fields = || vec![
+            name_field(MatchField::CategoryName, "Power", CATEGORY_NAME_WEIGHT),
+            name_field(MatchField::CommandName, "Reset Device", COMMAND_NAME_WEIGHT),
+        ];
+        let (score, matches) = score_fields(fields(), &terms("POWER reset")).unwrap();
+        assert_eq!(score, CATEGORY_NAME_WEIGHT * 3 + COMMAND_NAME_WEIGHT * 2);
+        assert_eq!(matches.len(), 2);
+        assert!(score_fields(fields(), &terms("power missing")).is_none());
+
+        // Only the best field counts for a term, but every matching field is reported
+        let (score, matches) = score_fields(fields(), &terms("e")).unwrap();
+        assert_eq!(score, COMMAND_NAME_WEIGHT);
+        assert_eq!(matches.len(), 2);
+    }
+}
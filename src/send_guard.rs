@@ -0,0 +1,186 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::palette_name::PaletteName;
+
+// How long a confirmation stays valid; long enough to click "OK", short enough
+// that an old token cannot fire a dangerous command by accident later on
+pub const CONFIRMATION_TTL: Duration = Duration::from_secs(60);
+const SEND_HISTORY_LEN: usize = 200;
+
+// A command in a palette, as addressed by the palette send path
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandRef {
+    pub palette: PaletteName,
+    pub category: String,
+    pub command: String,
+}
+
+struct PendingConfirmation {
+    target: CommandRef,
+    // The palette version the user saw when confirming
+    etag: String,
+    // Digest of the command body that was confirmed, see `command_digest`
+    command: String,
+    issued: Instant,
+    confirmed_at: String,
+}
+
+// Single-use tokens issued by the confirm call and required to send dangerous commands
+pub struct Confirmations {
+    pending: Mutex<HashMap<String, PendingConfirmation>>,
+}
+
+impl Confirmations {
+    pub fn new() -> Self {
+        Self { pending: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn issue(&self, target: CommandRef, etag: String, command: String) -> String {
+        let token = Uuid::new_v4().to_string();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, confirmation| confirmation.issued.elapsed() < CONFIRMATION_TTL);
+        pending.insert(token.clone(), PendingConfirmation {
+            target,
+            etag,
+            command,
+            issued: Instant::now(),
+            confirmed_at: chrono::Utc::now().to_rfc3339(),
+        });
+        token
+    }
+
+    // Uses up a token and returns when the send was confirmed. Fails if the token is
+    // unknown, expired, was issued for another command or command body, or the palette changed since.
+    pub fn redeem(&self, token: &str, target: &CommandRef, etag: &str, command: &str) -> Result<String, String> {
+        let confirmation = self.pending.lock().unwrap().remove(token)
+            .ok_or_else(|| "Invalid confirmation token: unknown or already used.".to_string())?;
+        if confirmation.issued.elapsed() >= CONFIRMATION_TTL {
+            return Err("Invalid confirmation token: it has expired.".to_string());
+        }
+        if confirmation.target != *target {
+            return Err(format!(
+                "Invalid confirmation token: it was issued for '{}' in '{}' of palette '{}'.",
+                confirmation.target.command, confirmation.target.category, confirmation.target.palette
+            ));
+        }
+        if confirmation.etag != etag {
+            return Err("Invalid confirmation token: the palette was modified after the command was confirmed.".to_string());
+        }
+        if confirmation.command != command {
+            return Err("Invalid confirmation token: it was issued for a different command body. Confirm the command as it is sent.".to_string());
+        }
+        Ok(confirmation.confirmed_at)
+    }
+}
+
+// Identifies the exact command body a confirmation is for, so a token cannot be
+// used to send something other than what the user was asked about
+pub fn command_digest(command: &JsonValue) -> String {
+    let bytes = serde_json::to_vec(command).unwrap_or_default();
+    Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SendStatus {
+    Sent,
+    Failed,
+}
+
+// One palette command sent (or attempted) over the connection
+#[derive(Serialize, Debug, Clone)]
+pub struct SendRecord {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub target: CommandRef,
+    pub bytes: usize,
+    pub danger: bool,
+    // Set when a dangerous command was sent with a confirmation token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmed_at: Option<String>,
+    pub status: SendStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// The most recent palette sends, kept in memory only
+pub struct SendHistory {
+    records: Mutex<VecDeque<SendRecord>>,
+}
+
+impl SendHistory {
+    pub fn new() -> Self {
+        Self { records: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn record(&self, record: SendRecord) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == SEND_HISTORY_LEN {
+            records.pop_back();
+        }
+        records.push_front(record);
+    }
+
+    // Newest first
+    pub fn list(&self) -> Vec<SendRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(command: &str) -> CommandRef {
+        CommandRef { palette: PaletteName::parse("Test").unwrap(), category: "Power".into(), command: command.into() }
+    }
+
+    #[test]
+    fn tokens_work_once_for_their_command_and_version() {
+        let confirmations = Confirmations::new();
+        let off = command_digest(&serde_json::json!({ "op": "off" }));
+        let token = confirmations.issue(target("Off"), "\"v1\"".into(), off.clone());
+        confirmations.redeem(&token, &target("Off"), "\"v1\"", &off).unwrap();
+        assert!(confirmations.redeem(&token, &target("Off"), "\"v1\"", &off).unwrap_err().contains("already used"));
+
+        let token = confirmations.issue(target("Off"), "\"v1\"".into(), off.clone());
+        assert!(confirmations.redeem(&token, &target("On"), "\"v1\"", &off).unwrap_err().contains("issued for 'Off'"));
+        // A rejected token is used up as well
+        assert!(confirmations.redeem(&token, &target("Off"), "\"v1\"", &off).is_err());
+
+        let token = confirmations.issue(target("Off"), "\"v1\"".into(), off.clone());
+        assert!(confirmations.redeem(&token, &target("Off"), "\"v2\"", &off).unwrap_err().contains("modified"));
+        assert!(confirmations.redeem("made-up", &target("Off"), "\"v1\"", &off).is_err());
+
+        // An edited body needs its own confirmation
+        let token = confirmations.issue(target("Off"), "\"v1\"".into(), off.clone());
+        let edited = command_digest(&serde_json::json!({ "op": "format-disk" }));
+        assert!(confirmations.redeem(&token, &target("Off"), "\"v1\"", &edited).unwrap_err().contains("different command body"));
+    }
+
+    #[test]
+    fn send_history_keeps_the_newest_records() {
+        let history = SendHistory::new();
+        for bytes in 0..SEND_HISTORY_LEN + 5 {
+            history.record(SendRecord {
+                timestamp: String::new(),
+                target: target("On"),
+                bytes,
+                danger: false,
+                confirmed_at: None,
+                status: SendStatus::Sent,
+                error: None,
+            });
+        }
+        let records = history.list();
+        assert_eq!(records.len(), SEND_HISTORY_LEN);
+        assert_eq!(records[0].bytes, SEND_HISTORY_LEN + 4);
+        assert_eq!(records[SEND_HISTORY_LEN - 1].bytes, 5);
+    }
+}
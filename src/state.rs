@@ -1,26 +1,44 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast::Sender;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::task::JoinHandle;
-use crate::types::{Palette};
-use std::collections::HashMap;
+use crate::palette_repository::PaletteRepository;
+use crate::send_guard::{Confirmations, SendHistory};
+use crate::reader_limits::ReaderStats;
+use crate::connection::Connection;
 
 // Application State
 pub struct AppState {
-    pub tcp_stream: Mutex<Option<OwnedWriteHalf>>,
-    pub tcp_reader_handle: Mutex<Option<JoinHandle<()>>>,
+    // The open TCP connection. Only ever locked to swap or clone it, never across an await.
+    pub connection: Mutex<Option<Arc<Connection>>>,
     pub tcp_message_tx: Sender<String>,
-    pub palettes: Mutex<HashMap<String, Palette>>,
+    // Reader counters for the current (or last) connection
+    pub reader_stats: Mutex<Arc<ReaderStats>>,
+    pub palettes: PaletteRepository,
+    pub confirmations: Confirmations,
+    pub send_history: SendHistory,
 }
 
 impl AppState {
     pub fn new() -> Self {
         let (tx, _rx) = tokio::sync::broadcast::channel(100);
         Self {
-            tcp_stream: Mutex::new(None),
-            tcp_reader_handle: Mutex::new(None),
+            connection: Mutex::new(None),
             tcp_message_tx: tx,
-            palettes: Mutex::new(HashMap::new()),
+            reader_stats: Mutex::new(Arc::new(ReaderStats::default())),
+            palettes: PaletteRepository::new(),
+            confirmations: Confirmations::new(),
+            send_history: SendHistory::new(),
         }
     }
-} 
\ No newline at end of file
+
+    // The open connection, if any. One whose writer has stopped is dropped here, so
+    // callers see "not connected" instead of writing into a dead socket.
+    pub fn current_connection(&self) -> Option<Arc<Connection>> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.as_ref().is_some_and(|connection| connection.is_closed()) {
+            if let Some(closed) = connection.take() {
+                closed.close();
+            }
+        }
+        connection.clone()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,28 @@
+// Helpers shared by the unit tests of several modules
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde_json::{json, Value as JsonValue};
+use uuid::Uuid;
+
+use crate::types::Palette;
+
+// Stands in for the user's config and data directories for the whole test run, so
+// tests never see or change real palettes and settings
+pub fn test_home() -> &'static Path {
+    static HOME: OnceLock<PathBuf> = OnceLock::new();
+    HOME.get_or_init(|| std::env::temp_dir().join(format!("rust-commander-test-{}", Uuid::new_v4())))
+}
+
+// A fresh directory under the system temp dir; removed again by the caller
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-commander-test-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A palette named "Test" with the given categories and commands
+pub fn palette(commands: JsonValue) -> Palette {
+    serde_json::from_value(json!({ "name": "Test", "commands": commands })).unwrap()
+}
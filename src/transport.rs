@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::error::AppError;
+
+// How the JSON text of a command is turned into bytes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextEncoding {
+    #[default]
+    #[serde(alias = "utf-8")]
+    Utf8,
+    // Non-ASCII characters are escaped as \uXXXX, for devices that only handle 7-bit text
+    Ascii,
+    // One byte per character; characters above U+00FF cannot be sent
+    #[serde(alias = "iso-8859-1")]
+    Latin1,
+}
+
+// How palette commands are sent. A palette sets defaults in `meta.transport`, and
+// categories and commands can override single fields in their annotations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TransportSettings {
+    // Appended after the command, with escapes like "\r" or "\0"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<TextEncoding>,
+    // Address ("host:port") the command is meant for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl TransportSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == TransportSettings::default()
+    }
+
+    // Takes every field not set here from `defaults`
+    pub fn or(self, defaults: &TransportSettings) -> TransportSettings {
+        TransportSettings {
+            delimiter: self.delimiter.or_else(|| defaults.delimiter.clone()),
+            encoding: self.encoding.or(defaults.encoding),
+            target: self.target.or_else(|| defaults.target.clone()),
+        }
+    }
+}
+
+pub fn encode_command(command: &JsonValue, encoding: TextEncoding) -> Result<Vec<u8>, AppError> {
+    let text = serde_json::to_string(command).map_err(|e| AppError::internal(format!("Failed to serialize command: {}", e)))?;
+    match encoding {
+        TextEncoding::Utf8 => Ok(text.into_bytes()),
+        // Outside of strings JSON is plain ASCII, so escaping every other character keeps it valid
+        TextEncoding::Ascii => {
+            let mut escaped = String::with_capacity(text.len());
+            for c in text.chars() {
+                if c.is_ascii() {
+                    escaped.push(c);
+                } else {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        escaped.push_str(&format!("\\u{:04x}", unit));
+                    }
+                }
+            }
+            Ok(escaped.into_bytes())
+        }
+        TextEncoding::Latin1 => text.chars()
+            .map(|c| u8::try_from(u32::from(c)).map_err(|_| AppError::invalid(format!("Invalid command: '{}' cannot be encoded as Latin-1.", c))))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn or_fills_only_missing_fields() {
+        let defaults = TransportSettings {
+            delimiter: Some("\\n".to_string()),
+            encoding: Some(TextEncoding::Ascii),
+            target: Some("127.0.0.1:9000".to_string()),
+        };
+        let own = TransportSettings { delimiter: Some("\\r".to_string()), ..Default::default() };
+        assert_eq!(own.or(&defaults), TransportSettings { delimiter: Some("\\r".to_string()), ..defaults.clone() });
+        assert_eq!(TransportSettings::default().or(&defaults), defaults);
+        assert!(TransportSettings::default().or(&TransportSettings::default()).is_empty());
+    }
+
+    #[test]
+    fn encodes_utf8_as_is() {
+        assert_eq!(encode_command(&json!({"t": "é"}), TextEncoding::Utf8).unwrap(), "{\"t\":\"é\"}".as_bytes());
+    }
+
+    #[test]
+    fn escapes_non_ascii() {
+        assert_eq!(encode_command(&json!("é😀"), TextEncoding::Ascii).unwrap(), b"\"\\u00e9\\ud83d\\ude00\"");
+        let escaped = encode_command(&json!({"t": "é😀"}), TextEncoding::Ascii).unwrap();
+        assert_eq!(serde_json::from_slice::<JsonValue>(&escaped).unwrap(), json!({"t": "é😀"}));
+    }
+
+    #[test]
+    fn encodes_latin1_one_byte_per_character() {
+        assert_eq!(encode_command(&json!("é"), TextEncoding::Latin1).unwrap(), vec![b'"', 0xE9, b'"']);
+        let err = encode_command(&json!("€"), TextEncoding::Latin1).unwrap_err();
+        assert!(err.is(crate::error::ErrorCode::InvalidRequest));
+        assert!(err.message.contains("Latin-1"));
+    }
+}
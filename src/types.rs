@@ -3,6 +3,15 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use indexmap::IndexMap;
 
+use crate::palette_name::PaletteName;
+use crate::palette_formats::PaletteFormat;
+use crate::transport::{TextEncoding, TransportSettings};
+use crate::framing::FramingConfig;
+use crate::payload_encoding::PayloadEncoding;
+use crate::jsonrpc::JsonRpcConfig;
+use crate::reader_limits::ReaderLimits;
+use crate::connection::ConnectionTimeouts;
+
 // Message type for WebSocket actor to send text to its client
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -18,15 +27,62 @@ pub struct CommandPayload {
 #[derive(Deserialize, Serialize)]
 pub struct ConnectPayload {
     pub socket_path: String,
+    // Frames every command sent on this connection and checks inbound frames
+    #[serde(default)]
+    pub framing: Option<FramingConfig>,
+    // "json" (default), "msgpack" or "cbor" for commands and device messages
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    // Sends commands as JSON-RPC 2.0 requests and matches responses to them
+    #[serde(default)]
+    pub jsonrpc: Option<JsonRpcConfig>,
+    // How much the reader buffers for a message that has not completed yet
+    #[serde(default)]
+    pub limits: ReaderLimits,
+    // Connect, write and idle timeouts in milliseconds
+    #[serde(default)]
+    pub timeouts: ConnectionTimeouts,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct TextCommandPayload {
     pub text_command: String,
     pub delimiter: Option<String>,
+    // Reads escapes like "\x02" in the text; otherwise it is sent exactly as written
+    #[serde(default)]
+    pub unescape: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteFormat {
+    // Pairs of hex digits; spaces, ':' and '-' between bytes and "0x" prefixes are ignored
+    #[default]
+    Hex,
+    Base64,
+}
+
+// Payload for sending exact bytes; prefix and suffix use the same format as data
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BytesPayload {
+    pub data: String,
+    #[serde(default)]
+    pub format: ByteFormat,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub suffix: Option<String>,
+}
+
+// Requests a WebSocket client can send, e.g. {"op": "send_bytes", "data": "02 41 03"}
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientOp {
+    SendBytes(BytesPayload),
 }
 
 // New structs for palettes and commands
+#[allow(dead_code)] // not used by the server itself yet
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Command {
     pub name: String,
@@ -38,20 +94,351 @@ pub type CommandsMap = IndexMap<String, IndexMap<String, JsonValue>>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Palette {
-    pub name: String,
+    pub name: PaletteName,
     pub commands: CommandsMap,
+    #[serde(default, skip_serializing_if = "PaletteMeta::is_empty")]
+    pub meta: PaletteMeta,
+}
+
+// Notes attached to a category or command. They are stored next to the commands
+// and never become part of what is sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Annotation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Shown before sending, e.g. "only send after arming"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub danger: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<AnnotationLink>,
+    // Overrides the palette's transport defaults
+    #[serde(default, skip_serializing_if = "TransportSettings::is_empty")]
+    pub transport: TransportSettings,
+}
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        *self == Annotation::default()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnnotationLink {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+// Annotations of a palette, keyed like `commands`: category -> annotation and
+// category -> command -> annotation. Entries without an annotation are left out.
+// `transport` holds the defaults for sending the palette's commands.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PaletteMeta {
+    #[serde(default, skip_serializing_if = "TransportSettings::is_empty")]
+    pub transport: TransportSettings,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub categories: IndexMap<String, Annotation>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub commands: IndexMap<String, IndexMap<String, Annotation>>,
+}
+
+impl PaletteMeta {
+    pub fn is_empty(&self) -> bool {
+        self.transport.is_empty() && self.categories.is_empty() && self.commands.is_empty()
+    }
+
+    pub fn category(&self, category: &str) -> Option<&Annotation> {
+        self.categories.get(category)
+    }
+
+    pub fn command(&self, category: &str, command: &str) -> Option<&Annotation> {
+        self.commands.get(category)?.get(command)
+    }
+
+    // A command is dangerous if it or its whole category is marked so
+    pub fn is_dangerous(&self, category: &str, command: &str) -> bool {
+        [self.category(category), self.command(category, command)].into_iter().flatten().any(|a| a.danger)
+    }
+
+    // Transport settings for a command: its own, then its category's, then the palette's
+    pub fn transport_for(&self, category: &str, command: &str) -> TransportSettings {
+        let own = self.command(category, command).map(|a| a.transport.clone()).unwrap_or_default();
+        let category = self.category(category).map(|a| a.transport.clone()).unwrap_or_default();
+        own.or(&category.or(&self.transport))
+    }
+
+    // The command's own warning, or else its category's
+    pub fn warning(&self, category: &str, command: &str) -> Option<&str> {
+        self.command(category, command).and_then(|a| a.warning.as_deref())
+            .or_else(|| self.category(category).and_then(|a| a.warning.as_deref()))
+    }
 }
 
-// Payload for creating/updating a palette via API
+// Payload for creating/updating a palette via API.
+// An update without `meta` keeps the palette's current annotations.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PalettePayload {
-    pub name: String,
+    pub name: PaletteName,
     pub commands: CommandsMap,
+    #[serde(default)]
+    pub meta: Option<PaletteMeta>,
+}
+
+// Narrows a palette down to the commands carrying a tag and/or the danger flag.
+// A command matches if it or its category is annotated that way.
+#[derive(Deserialize, Debug, Default)]
+pub struct PaletteFilterQuery {
+    pub tag: Option<String>,
+    pub danger: Option<bool>,
+}
+
+impl PaletteFilterQuery {
+    pub fn is_empty(&self) -> bool {
+        self.tag.is_none() && self.danger.is_none()
+    }
+}
+
+// Optional payload for confirming a palette command: the same `values` or edited `command`
+// that will be sent, as the token only allows sending that exact body
+#[derive(Deserialize, Debug, Default)]
+pub struct PaletteConfirmPayload {
+    #[serde(default)]
+    pub values: IndexMap<String, JsonValue>,
+    pub command: Option<JsonValue>,
+}
+
+// Payload for sending a palette command. `values` fills its placeholders, keyed by the
+// JSON pointer of each placeholder inside the command (e.g. "/params/id").
+// `command` sends an edited body instead of the stored one; the palette's transport
+// defaults and danger checks still apply.
+#[derive(Deserialize, Debug)]
+pub struct PaletteSendPayload {
+    #[serde(default)]
+    pub values: IndexMap<String, JsonValue>,
+    pub command: Option<JsonValue>,
+    // These override the transport settings of the palette and command; "" sends
+    // without a delimiter or skips the target check
+    pub delimiter: Option<String>,
+    pub encoding: Option<TextEncoding>,
+    pub target: Option<String>,
+    // Required for dangerous commands; issued by the confirm call
+    pub confirm_token: Option<String>,
+}
+
+// Payload for renaming or duplicating a palette
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewPaletteNamePayload {
+    pub new_name: PaletteName,
 }
 
 // Payload for adding a command to an existing palette
+// The command goes into the "Saved Commands" category unless another category is given.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AddCommandPayload {
     pub command_name: String,
     pub command_data: JsonValue,
-} 
\ No newline at end of file
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+// Payload for creating a category, optionally at a given position
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CreateCategoryPayload {
+    pub name: String,
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RenameCategoryPayload {
+    pub new_name: String,
+}
+
+// Payload listing every category of a palette in its new order
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CategoryOrderPayload {
+    pub order: Vec<String>,
+}
+
+// Payload for moving a command; omitted fields keep the command's current category and name
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MoveCommandPayload {
+    #[serde(default)]
+    pub to_category: Option<String>,
+    #[serde(default)]
+    pub new_name: Option<String>,
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteCommandQuery {
+    #[serde(default)]
+    pub remove_empty_category: bool,
+}
+
+// A previous version of a palette kept by the palette manager
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaletteBackup {
+    pub id: String,
+    pub created_at: Option<String>,
+    pub size_bytes: u64,
+}
+
+// Who made a change to a palette and why, recorded in the palette's history
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PaletteChange {
+    pub author: Option<String>,
+    pub message: Option<String>,
+}
+
+impl PaletteChange {
+    pub fn with_message(message: impl Into<String>) -> Self {
+        Self { author: None, message: Some(message.into()) }
+    }
+}
+
+// Metadata of one saved revision of a palette
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevisionInfo {
+    pub rev: u64,
+    pub author: Option<String>,
+    pub timestamp: String,
+    pub message: Option<String>,
+}
+
+// A revision as stored in the history directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaletteRevision {
+    #[serde(flatten)]
+    pub info: RevisionInfo,
+    pub palette: Palette,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandRef {
+    pub category: String,
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandChange {
+    pub category: String,
+    pub command: String,
+    pub before: JsonValue,
+    pub after: JsonValue,
+}
+
+// Structural difference between two revisions of a palette
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaletteDiff {
+    pub from: u64,
+    pub to: u64,
+    pub categories_added: Vec<String>,
+    pub categories_removed: Vec<String>,
+    pub categories_reordered: bool,
+    pub commands_added: Vec<CommandRef>,
+    pub commands_removed: Vec<CommandRef>,
+    pub commands_changed: Vec<CommandChange>,
+}
+
+// Query parameters for diffing two revisions; missing values default to the latest two.
+// `from=0` is the empty palette before revision 1.
+#[derive(Deserialize, Debug)]
+pub struct DiffQuery {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueSeverity {
+    Error,
+    Warning,
+}
+
+// A problem found while validating a palette; `pointer` is a JSON pointer into the palette document
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationIssue {
+    pub severity: IssueSeverity,
+    pub pointer: String,
+    pub code: String,
+    pub message: String,
+}
+
+// What to do when an imported palette has the same name as an existing one
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    // Leave the existing palette alone and report a conflict
+    #[default]
+    Reject,
+    // Replace the existing palette (it is still backed up)
+    Overwrite,
+    // Import under a free name such as "Name (2)"
+    Rename,
+    // Add the imported categories and commands to the existing palette
+    Merge,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: PaletteFormat,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Created,
+    Overwritten,
+    Renamed,
+    Merged,
+    Conflict,
+    Invalid,
+    Failed,
+}
+
+// Outcome of importing one file, e.g. one entry of an uploaded archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportResult {
+    pub file: String,
+    pub name: Option<PaletteName>,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub issues: Vec<ValidationIssue>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RestoreQuery {
+    // Only report what would change
+    #[serde(default)]
+    pub dry_run: bool,
+    // Also restore config.json from the backup
+    #[serde(default)]
+    pub settings: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    // Maximum number of hits returned, best first
+    pub limit: Option<usize>,
+}
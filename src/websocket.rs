@@ -3,7 +3,8 @@ use actix_web_actors::ws;
 use actix::{Actor, StreamHandler, Handler, ActorContext, AsyncContext};
 use tokio::sync::broadcast;
 
-use crate::types::ClientTextMessage;
+use crate::types::{ClientOp, ClientTextMessage};
+use crate::byte_send::{decode_payload, send_bytes};
 use crate::state::AppState;
 
 // WebSocket Actor
@@ -57,6 +58,21 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
             }
             Ok(ws::Message::Text(text)) => {
                 println!("Received WS message from client: {}", text);
+                // Other text is only logged, as before
+                if let Ok(ClientOp::SendBytes(payload)) = serde_json::from_str::<ClientOp>(&text) {
+                    let app_state = self.app_state.clone();
+                    let ws_actor_addr = ctx.address();
+                    actix::spawn(async move {
+                        // Success is announced to every client by send_bytes; errors only go to this one
+                        let result = match decode_payload(&payload) {
+                            Ok(bytes) => send_bytes(&app_state, &bytes).await.map_err(|e| e.to_string()),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
+                            ws_actor_addr.do_send(ClientTextMessage(format!("TCP_SEND_ERROR:{}", e)));
+                        }
+                    });
+                }
             }
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
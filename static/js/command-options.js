@@ -1,4 +1,6 @@
 // Command Options management module
+import { errorMessage } from './connection.js';
+
 export class CommandOptionsManager {
   constructor(commandManager, uiManager, saveManager) {
     this.commandManager = commandManager;
@@ -438,43 +440,26 @@ export class CommandOptionsManager {
     }
   }
 
+  // URL of a single command in the palette API
+  commandUrl(commandInfo) {
+    return `/api/palettes/${encodeURIComponent(commandInfo.paletteName)}/commands/` +
+      `${encodeURIComponent(commandInfo.categoryName)}/${encodeURIComponent(commandInfo.commandName)}`;
+  }
+
   async deleteCommandFromPalette(commandInfo) {
     try {
       if (!commandInfo.paletteName) {
         throw new Error('No palette name provided in command info');
       }
       
-      // Fetch the current palette
-      const response = await fetch(`/api/palettes/${encodeURIComponent(commandInfo.paletteName)}`);
-      if (!response.ok) {
-        throw new Error(`Failed to fetch palette "${commandInfo.paletteName}": ${response.status}`);
-      }
-      
-      const paletteData = await response.json();
-      
-      // Remove the command from the palette structure
-      if (paletteData.commands[commandInfo.categoryName]) {
-        delete paletteData.commands[commandInfo.categoryName][commandInfo.commandName];
-        
-        // If the category is now empty, optionally remove it (be careful with this)
-        if (Object.keys(paletteData.commands[commandInfo.categoryName]).length === 0) {
-          delete paletteData.commands[commandInfo.categoryName];
-        }
-      }
-
-      // Update the palette
-      const updateResponse = await fetch(`/api/palettes/${encodeURIComponent(commandInfo.paletteName)}`, {
-        method: 'PUT',
-        headers: { 'Content-Type': 'application/json' },
-        body: JSON.stringify({
-          name: commandInfo.paletteName,
-          commands: paletteData.commands
-        }),
+      // Delete just this command; the server also removes the category if it ends up empty
+      const updateResponse = await fetch(`${this.commandUrl(commandInfo)}?remove_empty_category=true`, {
+        method: 'DELETE',
       });
 
       if (!updateResponse.ok) {
         const errorData = await updateResponse.text();
-        throw new Error(`Failed to update palette: ${updateResponse.status}, ${errorData}`);
+        throw new Error(`Failed to delete command: ${updateResponse.status}, ${errorMessage(errorData)}`);
       }
 
       this.uiManager.showResponse(`Command "${commandInfo.commandName}" deleted successfully from palette "${commandInfo.paletteName}".`, true, "success");
@@ -509,33 +494,16 @@ export class CommandOptionsManager {
         throw new Error('No palette name provided in command info');
       }
       
-      // Fetch the current palette
-      const response = await fetch(`/api/palettes/${encodeURIComponent(commandInfo.paletteName)}`);
-      if (!response.ok) {
-        throw new Error(`Failed to fetch palette "${commandInfo.paletteName}": ${response.status}`);
-      }
-      
-      const paletteData = await response.json();
-      
-      // Update the command in the palette structure
-      if (!paletteData.commands[commandInfo.categoryName]) {
-        paletteData.commands[commandInfo.categoryName] = {};
-      }
-      paletteData.commands[commandInfo.categoryName][commandInfo.commandName] = newCommandData;
-
-      // Update the palette
-      const updateResponse = await fetch(`/api/palettes/${encodeURIComponent(commandInfo.paletteName)}`, {
+      // Replace just this command (the category is created if it no longer exists)
+      const updateResponse = await fetch(this.commandUrl(commandInfo), {
         method: 'PUT',
         headers: { 'Content-Type': 'application/json' },
-        body: JSON.stringify({
-          name: commandInfo.paletteName,
-          commands: paletteData.commands
-        }),
+        body: JSON.stringify(newCommandData),
       });
 
       if (!updateResponse.ok) {
         const errorData = await updateResponse.text();
-        throw new Error(`Failed to update palette: ${updateResponse.status}, ${errorData}`);
+        throw new Error(`Failed to update command: ${updateResponse.status}, ${errorMessage(errorData)}`);
       }
 
       // Don't show a toast here because handleEditCommand will show one.
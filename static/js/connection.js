@@ -1,9 +1,22 @@
 // Connection management module
+
+// API errors come back as {"error": {"code", "message", "details"}}; read errors on /ws
+// as {"kind", "message"}. Either way, the readable part is "message".
+export function errorMessage(text) {
+  try {
+    const body = JSON.parse(text);
+    return body.error?.message || body.message || text;
+  } catch (e) {
+    return text;
+  }
+}
+
 export class ConnectionManager {
-  constructor(onMessage, onStatusChange) {
+  constructor(onMessage, onStatusChange, onPaletteChanged = null) {
     this.persistentSocket = null;
     this.onMessage = onMessage;
     this.onStatusChange = onStatusChange;
+    this.onPaletteChanged = onPaletteChanged;
   }
 
   // Connect to TCP socket via backend
@@ -25,7 +38,7 @@ export class ConnectionManager {
         return { success: true, message: text };
       } else {
         // Send system message about failed TCP connection
-        this.onMessage(`TCP Connection failed: ${text}`, "system_error");
+        this.onMessage(`TCP Connection failed: ${errorMessage(text)}`, "system_error");
         return { success: false, message: text };
       }
     } catch (error) {
@@ -54,7 +67,7 @@ export class ConnectionManager {
         return { success: true, message: text };
       } else {
         // Send system message about failed disconnection
-        this.onMessage(`TCP Disconnect failed: ${text}`, "system_error");
+        this.onMessage(`TCP Disconnect failed: ${errorMessage(text)}`, "system_error");
         return { success: false, message: text };
       }
     } catch (error) {
@@ -97,8 +110,79 @@ export class ConnectionManager {
         if (this.persistentSocket && this.persistentSocket.readyState === WebSocket.OPEN) {
           this.persistentSocket.close(1000, "TCP connection closed by peer");
         }
+      } else if (event.data.startsWith("PALETTE_CHANGED:")) {
+        // A palette file changed on disk; not a device message
+        try {
+          const change = JSON.parse(event.data.substring("PALETTE_CHANGED:".length));
+          if (this.onPaletteChanged) {
+            this.onPaletteChanged(change);
+          }
+        } catch (e) {
+          console.error("Invalid palette change notification:", e);
+        }
+        return;
+      } else if (event.data.startsWith("TCP_SENT:")) {
+        // Raw bytes written by /send-bytes, shown as hex
+        try {
+          const sent = JSON.parse(event.data.substring("TCP_SENT:".length));
+          messageContent = `[${sent.bytes} bytes] ${sent.hex}`;
+        } catch (e) {
+          messageContent = event.data.substring("TCP_SENT:".length);
+        }
+        messageType = "sent";
+      } else if (event.data.startsWith("TCP_FRAME_ERROR:")) {
+        // An inbound frame failed its length or checksum check
+        try {
+          const frameError = JSON.parse(event.data.substring("TCP_FRAME_ERROR:".length));
+          messageContent = `Frame error: ${frameError.error} [${frameError.hex}]`;
+        } catch (e) {
+          messageContent = event.data.substring("TCP_FRAME_ERROR:".length);
+        }
+        messageType = "system_error";
+      } else if (event.data.startsWith("TCP_FLUSHED:")) {
+        // A partial message was given up on for being too big, going idle or the connection closing
+        try {
+          const flushed = JSON.parse(event.data.substring("TCP_FLUSHED:".length));
+          messageContent = `Dropped ${flushed.bytes} incomplete bytes (${flushed.reason}): ${flushed.text}${flushed.truncated ? "…" : ""}`;
+        } catch (e) {
+          messageContent = event.data.substring("TCP_FLUSHED:".length);
+        }
+        messageType = "system_warn";
+      } else if (event.data.startsWith("JSONRPC_RESPONSE:")) {
+        // A JSON-RPC response, matched to the request it answers
+        try {
+          const response = JSON.parse(event.data.substring("JSONRPC_RESPONSE:".length));
+          const call = response.method ? `${response.method} #${response.id}, ${response.elapsed_ms} ms` : `unknown request #${response.id}`;
+          if (response.error) {
+            messageContent = `RPC error (${call}): ${JSON.stringify(response.error)}`;
+            messageType = "system_error";
+          } else {
+            messageContent = `RPC result (${call}): ${JSON.stringify(response.result)}`;
+          }
+        } catch (e) {
+          messageContent = event.data.substring("JSONRPC_RESPONSE:".length);
+        }
+      } else if (event.data.startsWith("JSONRPC_NOTIFICATION:")) {
+        try {
+          const notification = JSON.parse(event.data.substring("JSONRPC_NOTIFICATION:".length));
+          messageContent = `RPC notification ${notification.method}: ${JSON.stringify(notification.params ?? null)}`;
+        } catch (e) {
+          messageContent = event.data.substring("JSONRPC_NOTIFICATION:".length);
+        }
+        messageType = "system_info";
+      } else if (event.data.startsWith("JSONRPC_TIMEOUT:")) {
+        try {
+          const timeout = JSON.parse(event.data.substring("JSONRPC_TIMEOUT:".length));
+          messageContent = `RPC timeout: no response to ${timeout.method} #${timeout.id} within ${timeout.timeout_ms} ms`;
+        } catch (e) {
+          messageContent = event.data.substring("JSONRPC_TIMEOUT:".length);
+        }
+        messageType = "system_warn";
+      } else if (event.data.startsWith("TCP_SEND_ERROR:")) {
+        messageContent = `Byte send error: ${event.data.substring("TCP_SEND_ERROR:".length)}`;
+        messageType = "system_error";
       } else if (event.data.startsWith("TCP_READ_ERROR:")) {
-        messageContent = `--- TCP Read Error: ${event.data.substring("TCP_READ_ERROR:".length)} ---`;
+        messageContent = `--- TCP Read Error: ${errorMessage(event.data.substring("TCP_READ_ERROR:".length))} ---`;
         messageType = "system_error";
         if (this.persistentSocket && this.persistentSocket.readyState === WebSocket.OPEN) {
           this.persistentSocket.close(1000, "TCP read error");
@@ -160,7 +244,7 @@ export class ConnectionManager {
       const text = await response.text();
       if (!response.ok) {
         // Send system message about command sending error
-        this.onMessage(`Command send error: ${text}`, "system_error");
+        this.onMessage(`Command send error: ${errorMessage(text)}`, "system_error");
       }
       return {
         success: response.ok,
@@ -177,6 +261,46 @@ export class ConnectionManager {
     }
   }
 
+  // Send a command stored in a palette. `body` is either { values } for the server to fill in
+  // (JSON pointer -> value) or { command } with an edited command to send instead.
+  // Dangerous commands answer 428 until they are sent with a token from confirmPaletteCommand.
+  async sendPaletteCommand(commandInfo, body, delimiter, confirmToken = null) {
+    try {
+      const response = await fetch(this.paletteCommandUrl(commandInfo, "send"), {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ ...body, delimiter, confirm_token: confirmToken }),
+      });
+      const text = await response.text();
+      if (!response.ok && response.status !== 428) {
+        this.onMessage(`Command send error: ${errorMessage(text)}`, "system_error");
+      }
+      return { success: response.ok, status: response.status, message: text };
+    } catch (error) {
+      console.error("Error sending palette command:", error);
+      this.onMessage(`Command send error: ${error.message}`, "system_error");
+      return { success: false, status: 0, message: error.message };
+    }
+  }
+
+  // `body` must be the same { values } or { command } as the send; the token only allows that body
+  async confirmPaletteCommand(commandInfo, body) {
+    const response = await fetch(this.paletteCommandUrl(commandInfo, "confirm"), {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    if (!response.ok) {
+      throw new Error(errorMessage(await response.text()));
+    }
+    return (await response.json()).confirm_token;
+  }
+
+  paletteCommandUrl(commandInfo, action) {
+    const parts = [commandInfo.paletteName, commandInfo.categoryName, commandInfo.commandName].map(encodeURIComponent);
+    return `/api/palettes/${parts[0]}/commands/${parts[1]}/${parts[2]}/${action}`;
+  }
+
   // Check if WebSocket is connected
   isWebSocketConnected() {
     return this.persistentSocket && this.persistentSocket.readyState === WebSocket.OPEN;
@@ -1,6 +1,6 @@
 // Main application module
 import { CommandManager } from './commands.js';
-import { ConnectionManager } from './connection.js';
+import { ConnectionManager, errorMessage } from './connection.js';
 import { UIManager } from './ui.js';
 import { SaveManager } from './save.js';
 import { CommandOptionsManager } from './command-options.js';
@@ -14,7 +14,8 @@ class App {
     this.uiManager.setCommandManager(this.commandManager);
     this.connectionManager = new ConnectionManager(
       (message, type) => this.messagesManager.addMessage(message, type),
-      (isConnected) => this.uiManager.updateConnectionStatus(isConnected)
+      (isConnected) => this.uiManager.updateConnectionStatus(isConnected),
+      (change) => this.handlePaletteChanged(change)
     );
     this.saveManager = new SaveManager(this.commandManager, this.uiManager);
     this.commandOptionsManager = new CommandOptionsManager(this.commandManager, this.uiManager, this.saveManager);
@@ -58,6 +59,18 @@ class App {
     }
   }
 
+  // Called when a palette file is edited, added or removed outside of this page
+  handlePaletteChanged(change) {
+    if (change.name !== this.uiManager.getCurrentPaletteName()) {
+      return;
+    }
+    if (change.change === "removed") {
+      this.uiManager.showResponse(`Palette '${change.name}' was removed on disk.`, true, "warn");
+    } else if (change.etag && change.etag !== this.loadedPaletteEtag) {
+      this.uiManager.showResponse(`Palette '${change.name}' was changed on disk. Reload it to see the latest version.`, true, "warn");
+    }
+  }
+
   async loadPalette(paletteName, skipAutoActivation = false) {
     if (!paletteName) {
         this.uiManager.showResponse("No palette specified to load.", true, "warn");
@@ -70,11 +83,13 @@ class App {
         throw new Error(`HTTP error! status: ${response.status}`);
       }
       const paletteData = await response.json();
+      // Remember the version we loaded so saves can detect concurrent edits
+      this.loadedPaletteEtag = response.headers.get('ETag');
       const loadResult = this.commandManager.loadCommandsFromJson(paletteData);
       
-      // Pass only the commands map to populateCommandPalette
+      // Pass the commands map and its annotations to populateCommandPalette
       if (loadResult.success && loadResult.data) {
-        this.uiManager.populateCommandPalette(loadResult.data, skipAutoActivation);
+        this.uiManager.populateCommandPalette(loadResult.data, skipAutoActivation, paletteData.meta || {});
       } else {
         // Handle cases where commandsData might not be in the expected format or empty
         console.error("Loaded palette data is not in the expected format or failed to load:", paletteData);
@@ -109,16 +124,23 @@ class App {
     try {
       const response = await fetch(`/api/palettes/${paletteName}`, {
         method: 'PUT',
-        headers: {
-          'Content-Type': 'application/json',
-        },
+        headers: this.paletteUpdateHeaders(),
         body: JSON.stringify({ name: paletteName, commands: commands }),
       });
 
       if (response.ok) {
+        this.loadedPaletteEtag = response.headers.get('ETag');
         this.uiManager.showResponse(`Palette '${paletteName}' saved successfully.`, "success");
+        // Saved, but validation found placeholders that may not work as intended
+        const { warnings = [] } = await response.json().catch(() => ({}));
+        if (warnings.length > 0) {
+          const problems = warnings.map(issue => `${issue.pointer || "/"}: ${issue.message}`);
+          this.uiManager.showResponse(`Palette '${paletteName}' has warnings - ${problems.join("; ")}`, true, "warn");
+        }
+      } else if (response.status === 412) {
+        this.uiManager.showResponse(`Palette '${paletteName}' was changed by someone else. Reload it before saving.`, "error");
       } else {
-        const errorText = await response.text();
+        const errorText = await this.paletteErrorText(response);
         this.uiManager.showResponse(`Error saving palette: ${errorText}`, "error");
       }
     } catch (error) {
@@ -127,6 +149,31 @@ class App {
     }
   }
   
+  // Error text for a failed palette request; validation failures list each problem and where it is
+  async paletteErrorText(response) {
+    if (response.status === 422) {
+      try {
+        const body = await response.json();
+        const problems = body.error.details.issues
+          .filter(issue => issue.severity === "error")
+          .map(issue => `${issue.pointer || "/"}: ${issue.message}`);
+        return `invalid palette - ${problems.join("; ")}`;
+      } catch (e) {
+        // Fall through to the generic message
+      }
+    }
+    return `HTTP error! status: ${response.status}, message: ${errorMessage(await response.text())}`;
+  }
+
+  // Headers for replacing the loaded palette; If-Match makes the server reject stale saves
+  paletteUpdateHeaders() {
+    const headers = { 'Content-Type': 'application/json' };
+    if (this.loadedPaletteEtag) {
+      headers['If-Match'] = this.loadedPaletteEtag;
+    }
+    return headers;
+  }
+
   async createNewPalette() {
     const paletteName = prompt("Enter name for the new palette:");
     if (!paletteName || paletteName.trim() === "") {
@@ -146,7 +193,7 @@ class App {
         });
         if (!response.ok) {
             const errorData = await response.text();
-            throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+            throw new Error(`HTTP error! status: ${response.status}, message: ${errorMessage(errorData)}`);
         }
         this.uiManager.showResponse(`Palette '${paletteName}' created successfully.`, true, "success");
         
@@ -177,7 +224,7 @@ class App {
       const response = await fetch(`/api/palettes/${paletteName}`, { method: 'DELETE' });
       if (!response.ok) {
         const errorData = await response.text();
-        throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+        throw new Error(`HTTP error! status: ${response.status}, message: ${errorMessage(errorData)}`);
       }
       this.uiManager.showResponse(`Palette '${paletteName}' deleted successfully.`, true, "success");
       await this.fetchPalettes(); // Refresh the list
@@ -222,15 +269,15 @@ class App {
 
       const response = await fetch(`/api/palettes/${encodeURIComponent(paletteName)}`, {
         method: 'PUT',
-        headers: {
-          'Content-Type': 'application/json',
-        },
+        headers: this.paletteUpdateHeaders(),
         body: JSON.stringify(payload), // Send the full PalettePayload structure
       });
 
+      if (response.status === 412) {
+        throw new Error("the palette was changed by someone else since it was loaded. Reload it and edit again.");
+      }
       if (!response.ok) {
-        const errorData = await response.text();
-        throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+        throw new Error(await this.paletteErrorText(response));
       }
       this.uiManager.showResponse(`Palette '${paletteName}' updated successfully.`, true, "success");
       await this.fetchPalettes(); // Refresh palette list
@@ -329,7 +376,7 @@ class App {
       const result = await this.connectionManager.connectTCP(socketPath);
       // Apply appropriate styling based on success/failure
       const messageType = result.success ? "success" : "error";
-      this.uiManager.showResponse(result.message, false, messageType);
+      this.uiManager.showResponse(errorMessage(result.message), false, messageType);
     });
 
     document.getElementById("disconnectButton_header").addEventListener("click", async () => {
@@ -337,7 +384,7 @@ class App {
       const result = await this.connectionManager.disconnectTCP();
       // Apply appropriate styling based on success/failure
       const messageType = result.success ? "info" : "error";
-      this.uiManager.showResponse(result.message, false, messageType);
+      this.uiManager.showResponse(errorMessage(result.message), false, messageType);
     });
 
     document.getElementById("uploadCommandFileButton").addEventListener("click", () => {
@@ -367,8 +414,7 @@ class App {
                         body: JSON.stringify({ name: paletteName, commands: commandsData }),
                     });
                     if (!response.ok) {
-                        const errorData = await response.text();
-                        throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+                        throw new Error(await this.paletteErrorText(response));
                     }
                     this.uiManager.showResponse(`Palette '${paletteName}' created from file '${file.name}' and loaded.`, true, "success");
                     
@@ -585,14 +631,55 @@ class App {
     const delimiterInput = document.getElementById("commandDelimiterInput");
     const delimiter = delimiterInput ? delimiterInput.value : null; // Get delimiter, or null if input not found
 
-    const result = await this.connectionManager.sendCommand(command, delimiter);
+    const commandInfo = this.uiManager.getCurrentCommandInfo();
+    // An empty delimiter box lets the palette's transport defaults apply
+    const result = commandInfo && commandInfo.paletteName
+      ? await this.sendPaletteCommand(commandInfo, delimiter || null)
+      : await this.connectionManager.sendCommand(command, delimiter);
+    if (!result) return;
 
     if (result.success) {
       this.uiManager.addMessage(JSON.stringify(command) + (delimiter ? ` (delim: '${delimiter}')` : ''), "sent");
     } else {
       // Apply error styling to the response div
-      this.uiManager.showResponse(`Send error: ${result.message}`, false, "error");
+      this.uiManager.showResponse(`Send error: ${errorMessage(result.message)}`, false, "error");
+    }
+  }
+
+  // Sends the selected palette command by reference, so the server can guard dangerous ones.
+  // Only the placeholder values go along, unless the command was edited beyond them; then
+  // the edited body is sent as it is. Returns null if the user declined to confirm.
+  async sendPaletteCommand(commandInfo, delimiter) {
+    const filled = this.commandManager.getCurrentFilledCommand();
+    const expected = structuredClone(this.commandManager.getCurrentTemplateCommand());
+    const values = {};
+    for (const path of this.commandManager.activeVariablePaths) {
+      const pointer = "/" + path.map(part => String(part).replace(/~/g, "~0").replace(/\//g, "~1")).join("/");
+      const value = path.reduce((value, part) => (value == null ? value : value[part]), filled);
+      values[pointer] = value;
+      const parent = path.slice(0, -1).reduce((object, part) => object[part], expected);
+      parent[path[path.length - 1]] = value;
+    }
+    const edited = JSON.stringify(expected) !== JSON.stringify(filled);
+    const body = edited ? { command: filled } : { values };
+
+    let result = await this.connectionManager.sendPaletteCommand(commandInfo, body, delimiter);
+    if (result.status === 428) {
+      let warning = null;
+      try { warning = JSON.parse(result.message).error.details.warning; } catch (e) { /* no warning given */ }
+      const question = `"${commandInfo.commandName}" is marked as dangerous.` + (warning ? `\n\n${warning}` : "") + "\n\nSend it anyway?";
+      if (!confirm(question)) {
+        this.uiManager.showResponse("Send cancelled.", false, "info");
+        return null;
+      }
+      try {
+        const token = await this.connectionManager.confirmPaletteCommand(commandInfo, body);
+        result = await this.connectionManager.sendPaletteCommand(commandInfo, body, delimiter, token);
+      } catch (error) {
+        result = { success: false, message: error.message };
+      }
     }
+    return result;
   }
 
   // Handle command selection from tabs
@@ -1,3 +1,5 @@
+import { errorMessage } from './connection.js';
+
 export class SaveManager {
   constructor(commandManager, uiManager) {
     this.commandManager = commandManager;
@@ -152,13 +154,14 @@ export class SaveManager {
       });
 
       if (!response.ok) {
-        const errorData = await response.json().catch(() => ({ error: 'Unknown error' }));
+        const errorData = await response.json().catch(() => ({}));
+        const message = errorData.error?.message;
         if (response.status === 409) {
-          this.uiManager.showResponse(errorData.message || 'Command already exists in palette.', "warn");
-          return { success: false, error: errorData.message || 'Command already exists' };
+          this.uiManager.showResponse(message || 'Command already exists in palette.', "warn");
+          return { success: false, error: message || 'Command already exists' };
         } else {
-          this.uiManager.showResponse(`Save error: ${errorData.error || 'Unknown error'}`, "error");
-          return { success: false, error: errorData.error || 'Unknown error' };
+          this.uiManager.showResponse(`Save error: ${message || 'Unknown error'}`, "error");
+          return { success: false, error: message || 'Unknown error' };
         }
       }
 
@@ -224,21 +227,26 @@ export class SaveManager {
       clearTimeout(timeoutId);
 
       if (response.status === 409) {
-        // Command already exists - ask for confirmation
-        const overwrite = confirm(`Command "${this.escapeHtml(commandName)}" already exists in palette "${this.escapeHtml(paletteName)}". Overwrite?`);
+        // Command already exists - load it, so the overwrite only goes through if it is
+        // still what the user is shown here
+        const existingResponse = await fetch(this.savedCommandUrl(paletteName, commandName));
+        const etag = existingResponse.ok ? existingResponse.headers.get('ETag') : null;
+        const existing = existingResponse.ok ? await existingResponse.json() : null;
+        const current = existing === null ? '' : `\n\nCurrent command:\n${JSON.stringify(existing, null, 2)}`;
+        const overwrite = confirm(`Command "${this.escapeHtml(commandName)}" already exists in palette "${this.escapeHtml(paletteName)}". Overwrite?${current}`);
         if (!overwrite) {
           this.uiManager.showResponse("Save cancelled. Command name already exists.", "info");
           return;
         }
         
-        // User confirmed overwrite - we need to update the palette directly
-        await this.overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose);
+        // User confirmed overwrite - replace just that command
+        await this.overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose, etag);
         return;
       }
 
       if (!response.ok) {
         const errorData = await response.text();
-        throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+        throw new Error(`HTTP error! status: ${response.status}, message: ${errorMessage(errorData)}`);
       }
 
       const result = await response.json();
@@ -278,7 +286,13 @@ export class SaveManager {
     }
   }
 
-  async overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose) {
+  savedCommandUrl(paletteName, commandName) {
+    return `/api/palettes/${encodeURIComponent(paletteName)}/commands/${encodeURIComponent("Saved Commands")}/${encodeURIComponent(commandName)}`;
+  }
+
+  // `etag` is the palette version the user saw the existing command in; with it the
+  // server refuses the overwrite (412) if the palette has changed since
+  async overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose, etag = null) {
     try {
       // Add timeout for fetch requests
       const controller = new AbortController();
@@ -286,42 +300,39 @@ export class SaveManager {
       
       console.log(`Attempting to overwrite command "${commandName}" in palette "${paletteName}"`);
       
-      // First, fetch the current palette
-      const getResponse = await fetch(`/api/palettes/${encodeURIComponent(paletteName)}`, {
-        signal: controller.signal
-      });
-      if (!getResponse.ok) {
-        throw new Error('Failed to fetch palette for update');
-      }
-      
-      const palette = await getResponse.json();
-      
-      // Ensure "Saved Commands" category exists
-      if (!palette.commands["Saved Commands"]) {
-        palette.commands["Saved Commands"] = {};
+      // Only the one command is replaced, so edits elsewhere in the palette are kept
+      const headers = { 'Content-Type': 'application/json' };
+      if (etag) {
+        headers['If-Match'] = etag;
       }
-      
-      // Update the command
-      palette.commands["Saved Commands"][commandName] = currentCommand;
-      
-      // Save the updated palette
-      const updateResponse = await fetch(`/api/palettes/${encodeURIComponent(paletteName)}`, {
+      const updateResponse = await fetch(this.savedCommandUrl(paletteName, commandName), {
         method: 'PUT',
-        headers: {
-          'Content-Type': 'application/json',
-        },
-        body: JSON.stringify({
-          name: paletteName,
-          commands: palette.commands
-        }),
+        headers,
+        body: JSON.stringify(currentCommand),
         signal: controller.signal
       });
 
       clearTimeout(timeoutId);
 
+      if (updateResponse.status === 412) {
+        // Someone else changed the palette in the meantime; show them what is there now
+        const { error } = await updateResponse.json().catch(() => ({}));
+        const nowStored = error?.details?.palette?.commands?.["Saved Commands"]?.[commandName];
+        const now = nowStored === undefined
+          ? 'It has been removed since.'
+          : `It is now:\n${JSON.stringify(nowStored, null, 2)}`;
+        const overwriteAnyway = confirm(`Palette "${this.escapeHtml(paletteName)}" was changed by someone else while you were saving "${this.escapeHtml(commandName)}". ${now}\n\nOverwrite it anyway?`);
+        if (!overwriteAnyway) {
+          this.uiManager.showResponse(`Save cancelled. Command '${this.escapeHtml(commandName)}' was changed by someone else.`, "info");
+          return;
+        }
+        await this.overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose, error?.details?.etag ?? null);
+        return;
+      }
+
       if (!updateResponse.ok) {
         const errorData = await updateResponse.text();
-        throw new Error(`HTTP error! status: ${updateResponse.status}, message: ${errorData}`);
+        throw new Error(`HTTP error! status: ${updateResponse.status}, message: ${errorMessage(errorData)}`);
       }
 
       console.log('Overwrite successful');
@@ -354,7 +365,7 @@ export class SaveManager {
       const retry = confirm(`Failed to update command: ${errorMessage}\n\nWould you like to try again?`);
       if (retry) {
         setTimeout(() => {
-          this.overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose);
+          this.overwriteCommandInPalette(paletteName, commandName, currentCommand, modalToClose, etag);
         }, 1000);
       }
     }
@@ -393,7 +404,7 @@ export class SaveManager {
 
       if (!response.ok) {
         const errorData = await response.text();
-        throw new Error(`HTTP error! status: ${response.status}, message: ${errorData}`);
+        throw new Error(`HTTP error! status: ${response.status}, message: ${errorMessage(errorData)}`);
       }
 
       console.log('New palette created successfully');
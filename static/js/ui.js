@@ -31,7 +31,17 @@ export class UIManager {
   }
 
   // Command palette functionality - create tabs and command lists
-  populateCommandPalette(commandsData, skipAutoActivation = false) {
+  // Text shown when hovering a category or command with notes
+  annotationTooltip(annotation) {
+    if (!annotation) return "";
+    const lines = [];
+    if (annotation.warning) lines.push(`⚠️ ${annotation.warning}`);
+    if (annotation.description) lines.push(annotation.description);
+    if (annotation.tags && annotation.tags.length) lines.push(`Tags: ${annotation.tags.join(", ")}`);
+    return lines.join("\n");
+  }
+
+  populateCommandPalette(commandsData, skipAutoActivation = false, meta = {}) {
     const tabContainer = document.getElementById("tabContainer");
     const tabContentContainer = document.getElementById("tabContentContainer");
 
@@ -74,6 +84,8 @@ export class UIManager {
       const tabButton = document.createElement("div");
       tabButton.classList.add("tab");
       tabButton.textContent = key;
+      const categoryAnnotation = (meta.categories || {})[key];
+      tabButton.title = this.annotationTooltip(categoryAnnotation);
       tabContainer.appendChild(tabButton);
 
       const tabContent = document.createElement("div");
@@ -103,7 +115,10 @@ export class UIManager {
           if (nestedCommands.hasOwnProperty(commandName)) {
             const commandData = nestedCommands[commandName];
             const listItem = document.createElement("li");
-            listItem.textContent = commandName;
+            const annotation = ((meta.commands || {})[key] || {})[commandName];
+            const danger = (annotation && annotation.danger) || (categoryAnnotation && categoryAnnotation.danger);
+            listItem.textContent = danger ? `⚠️ ${commandName}` : commandName;
+            listItem.title = this.annotationTooltip(annotation);
             // Store the actual command object or its JSON string. Storing object directly is fine if not too large.
             // For consistency with previous patterns and explicit data handling:
             try {